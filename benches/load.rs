@@ -0,0 +1,65 @@
+//! Times `Model::new` from each kind of weight source.
+//!
+//! Run with `cargo bench --bench load`, optionally followed by `-- dir`,
+//! `-- bundle` or `-- mmap` to time just one of them. The bundle is packed
+//! into the target directory on first use.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use craftgpt::{Bundle, Model, ModelPaths, bundle};
+
+const RUNS: u32 = 5;
+
+fn time<F: FnMut() -> Model>(name: &str, mut load: F) {
+    let mut total = Duration::ZERO;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let model = load();
+        total += start.elapsed();
+        drop(model);
+    }
+    println!(
+        "{:<8} {:>8.1} ms",
+        name,
+        total.as_secs_f64() * 1000.0 / RUNS as f64
+    );
+}
+
+fn peak_rss() -> Option<String> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    Some(line["VmHWM:".len()..].trim().to_string())
+}
+
+fn main() {
+    let only = env::args().skip(1).find(|a| !a.starts_with('-'));
+    let wanted = |name: &str| only.as_deref().is_none_or(|o| o == name);
+
+    let paths = ModelPaths::from_env();
+    let bundle_path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("bench.cgpt");
+    if !bundle_path.exists() {
+        bundle::pack(&paths.weights_dir, &bundle_path).expect("couldn't pack weights");
+    }
+
+    if wanted("dir") {
+        time("dir", || Model::new(&paths).unwrap());
+    }
+    if wanted("bundle") {
+        time("bundle", || {
+            Model::new(&Bundle::open(&bundle_path).unwrap()).unwrap()
+        });
+    }
+    if wanted("mmap") {
+        // SAFETY: the bundle was written above and nothing touches it again.
+        time("mmap", || {
+            Model::new(&unsafe { Bundle::map(&bundle_path) }.unwrap()).unwrap()
+        });
+    }
+
+    if let Some(rss) = peak_rss() {
+        println!("peak RSS {}", rss);
+    }
+}
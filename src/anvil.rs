@@ -0,0 +1,369 @@
+//! Reading blocks out of a world save's Anvil region files (`region/*.mca`),
+//! in the chunk format used since Minecraft 1.18.
+//!
+//! [`WorldWriter`] writes the same format, so small synthetic worlds can be
+//! built without running the game.
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::Compression;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::ZlibEncoder;
+
+use crate::layout::Pos;
+use crate::nbt::{self, Tag};
+
+const SECTOR: u64 = 4096;
+const AIR: &str = "minecraft:air";
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The blocks of one 16x16x16 section, packed the way the game stores them.
+struct Section {
+    palette: Vec<String>,
+    bits: usize,
+    data: Vec<i64>,
+}
+
+impl Section {
+    fn block(&self, index: usize) -> &str {
+        if self.data.is_empty() {
+            return &self.palette[0];
+        }
+        let per_long = 64 / self.bits;
+        let long = self.data[index / per_long] as u64;
+        let id = (long >> ((index % per_long) * self.bits)) & ((1 << self.bits) - 1);
+        self.palette.get(id as usize).map_or(AIR, String::as_str)
+    }
+}
+
+/// A chunk's sections by their y index.
+type Chunk = HashMap<i32, Section>;
+
+/// A world save, with chunks decoded as they are first needed.
+pub struct World {
+    region_dir: PathBuf,
+    /// Chunk offsets for each region file read so far; `None` if the region
+    /// doesn't exist.
+    headers: HashMap<(i32, i32), Option<Vec<u32>>>,
+    chunks: HashMap<(i32, i32), Option<Chunk>>,
+}
+
+impl World {
+    /// Opens the world save in `dir`, which must contain a `region` folder.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let region_dir = dir.as_ref().join("region");
+        if !region_dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no region folder", dir.as_ref().display()),
+            ));
+        }
+        Ok(World {
+            region_dir,
+            headers: HashMap::new(),
+            chunks: HashMap::new(),
+        })
+    }
+
+    /// The name of the block at `pos`, e.g. `minecraft:glass`. Blocks in
+    /// chunks that were never generated read as air.
+    pub fn block(&mut self, (x, y, z): Pos) -> io::Result<&str> {
+        let key = (x.div_euclid(16), z.div_euclid(16));
+        if !self.chunks.contains_key(&key) {
+            let chunk = self.load_chunk(key)?;
+            self.chunks.insert(key, chunk);
+        }
+
+        let Some(chunk) = &self.chunks[&key] else {
+            return Ok(AIR);
+        };
+        let Some(section) = chunk.get(&y.div_euclid(16)) else {
+            return Ok(AIR);
+        };
+        let index = (y.rem_euclid(16) * 256 + z.rem_euclid(16) * 16 + x.rem_euclid(16)) as usize;
+        Ok(section.block(index))
+    }
+
+    fn region_path(&self, (rx, rz): (i32, i32)) -> PathBuf {
+        self.region_dir.join(format!("r.{}.{}.mca", rx, rz))
+    }
+
+    fn load_chunk(&mut self, (cx, cz): (i32, i32)) -> io::Result<Option<Chunk>> {
+        let region = (cx.div_euclid(32), cz.div_euclid(32));
+        let path = self.region_path(region);
+
+        let header = match self.headers.entry(region) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(read_header(&path)?),
+        };
+        let Some(header) = header else {
+            return Ok(None);
+        };
+
+        let location = header[(cx.rem_euclid(32) + 32 * cz.rem_euclid(32)) as usize];
+        let offset = (location >> 8) as u64 * SECTOR;
+        if offset == 0 {
+            return Ok(None);
+        }
+
+        let mut file = File::open(&path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut prefix = [0u8; 5];
+        file.read_exact(&mut prefix)?;
+        let len = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+        // The length prefix is followed by `len` bytes, all within the
+        // sectors the location gives the chunk.
+        let sectors = (location & 0xff) as usize;
+        if len + 4 > sectors * SECTOR as usize {
+            return Err(invalid(format!(
+                "chunk {} {} in {} claims {} bytes, but its sectors hold {}",
+                cx,
+                cz,
+                path.display(),
+                len + 4,
+                sectors * SECTOR as usize
+            )));
+        }
+        let mut compressed = vec![0u8; len.saturating_sub(1)];
+        file.read_exact(&mut compressed)?;
+
+        let mut data = Vec::new();
+        match prefix[4] {
+            1 => GzDecoder::new(compressed.as_slice()).read_to_end(&mut data)?,
+            2 => ZlibDecoder::new(compressed.as_slice()).read_to_end(&mut data)?,
+            3 => {
+                data = compressed;
+                data.len()
+            }
+            c => {
+                return Err(invalid(format!(
+                    "chunk {} {} in {} uses unsupported compression {}",
+                    cx,
+                    cz,
+                    path.display(),
+                    c
+                )));
+            }
+        };
+
+        let (_, root) = nbt::read(&mut data.as_slice())?;
+        parse_chunk(&root)
+            .map(Some)
+            .map_err(|e| invalid(format!("chunk {} {} in {}: {}", cx, cz, path.display(), e)))
+    }
+}
+
+/// Reads a region file's chunk locations, or `None` if it doesn't exist.
+fn read_header(path: &Path) -> io::Result<Option<Vec<u32>>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut buf = [0u8; SECTOR as usize];
+    file.read_exact(&mut buf)?;
+    Ok(Some(
+        buf.chunks_exact(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+    ))
+}
+
+fn parse_chunk(root: &Tag) -> Result<Chunk, String> {
+    let sections = root
+        .get("sections")
+        .and_then(Tag::as_list)
+        .ok_or("no sections; worlds older than 1.18 aren't supported")?;
+
+    let mut chunk = HashMap::new();
+    for section in sections {
+        let y = section
+            .get("Y")
+            .and_then(Tag::as_i64)
+            .ok_or("section has no Y")? as i32;
+        let Some(states) = section.get("block_states") else {
+            continue;
+        };
+
+        let palette: Vec<String> = states
+            .get("palette")
+            .and_then(Tag::as_list)
+            .ok_or("section has no palette")?
+            .iter()
+            .map(|entry| {
+                entry
+                    .get("Name")
+                    .and_then(Tag::as_str)
+                    .map(str::to_string)
+                    .ok_or("palette entry has no name")
+            })
+            .collect::<Result<_, _>>()?;
+        if palette.is_empty() {
+            return Err("section has an empty palette".to_string());
+        }
+
+        let data = match states.get("data") {
+            Some(Tag::LongArray(data)) => data.clone(),
+            Some(_) => return Err("section data is not a long array".to_string()),
+            None => Vec::new(),
+        };
+        let bits = bits_for(palette.len());
+        if !data.is_empty() && data.len() < 4096usize.div_ceil(64 / bits) {
+            return Err("section data is too short".to_string());
+        }
+
+        chunk.insert(
+            y,
+            Section {
+                palette,
+                bits,
+                data,
+            },
+        );
+    }
+    Ok(chunk)
+}
+
+/// Bits per block index for a palette of `len` entries.
+fn bits_for(len: usize) -> usize {
+    let needed = usize::BITS - (len.max(1) - 1).leading_zeros();
+    (needed as usize).max(4)
+}
+
+/// Collects blocks and writes them out as region files.
+#[derive(Default)]
+pub struct WorldWriter {
+    /// Block names by section, as (chunk x, section y, chunk z).
+    sections: BTreeMap<(i32, i32, i32), Vec<Option<String>>>,
+}
+
+impl WorldWriter {
+    pub fn new() -> Self {
+        WorldWriter::default()
+    }
+
+    pub fn set_block(&mut self, (x, y, z): Pos, name: &str) {
+        let key = (x.div_euclid(16), y.div_euclid(16), z.div_euclid(16));
+        let section = self.sections.entry(key).or_insert_with(|| vec![None; 4096]);
+        let index = (y.rem_euclid(16) * 256 + z.rem_euclid(16) * 16 + x.rem_euclid(16)) as usize;
+        section[index] = Some(name.to_string());
+    }
+
+    /// Writes `dir/region/r.X.Z.mca` for every region with a block in it.
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let region_dir = dir.as_ref().join("region");
+        fs::create_dir_all(&region_dir)?;
+
+        let mut chunks: BTreeMap<(i32, i32), Vec<Tag>> = BTreeMap::new();
+        for (&(cx, y, cz), blocks) in &self.sections {
+            chunks
+                .entry((cx, cz))
+                .or_default()
+                .push(section_nbt(y, blocks));
+        }
+
+        let mut regions: BTreeMap<(i32, i32), Vec<CompressedChunk>> = BTreeMap::new();
+        for ((cx, cz), sections) in chunks {
+            let root = Tag::Compound(vec![
+                ("DataVersion".to_string(), Tag::Int(3700)),
+                ("xPos".to_string(), Tag::Int(cx)),
+                ("zPos".to_string(), Tag::Int(cz)),
+                (
+                    "Status".to_string(),
+                    Tag::String("minecraft:full".to_string()),
+                ),
+                ("sections".to_string(), Tag::List(sections)),
+            ]);
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            nbt::write(&mut encoder, "", &root)?;
+            regions
+                .entry((cx.div_euclid(32), cz.div_euclid(32)))
+                .or_default()
+                .push((cx, cz, encoder.finish()?));
+        }
+
+        for ((rx, rz), chunks) in regions {
+            let mut header = vec![0u8; 2 * SECTOR as usize];
+            let mut body = Vec::new();
+            for (cx, cz, data) in chunks {
+                let sector = 2 + body.len() as u64 / SECTOR;
+                let mut record = Vec::new();
+                record.extend_from_slice(&(data.len() as u32 + 1).to_be_bytes());
+                record.push(2);
+                record.extend_from_slice(&data);
+                record.resize(record.len().div_ceil(SECTOR as usize) * SECTOR as usize, 0);
+                let sectors = record.len() as u64 / SECTOR;
+                if sectors > 255 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("chunk {} {} is too large for a region file", cx, cz),
+                    ));
+                }
+
+                let i = 4 * (cx.rem_euclid(32) + 32 * cz.rem_euclid(32)) as usize;
+                let location = ((sector as u32) << 8) | sectors as u32;
+                header[i..i + 4].copy_from_slice(&location.to_be_bytes());
+                body.extend_from_slice(&record);
+            }
+
+            let mut file = File::create(region_dir.join(format!("r.{}.{}.mca", rx, rz)))?;
+            file.write_all(&header)?;
+            file.write_all(&body)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A chunk's x and z and its zlib-compressed NBT.
+type CompressedChunk = (i32, i32, Vec<u8>);
+
+fn section_nbt(y: i32, blocks: &[Option<String>]) -> Tag {
+    let mut palette = vec![AIR.to_string()];
+    let mut ids = Vec::with_capacity(4096);
+    for block in blocks {
+        let name = block.as_deref().unwrap_or(AIR);
+        let id = match palette.iter().position(|p| p == name) {
+            Some(id) => id,
+            None => {
+                palette.push(name.to_string());
+                palette.len() - 1
+            }
+        };
+        ids.push(id as u64);
+    }
+
+    let bits = bits_for(palette.len());
+    let per_long = 64 / bits;
+    let data = ids
+        .chunks(per_long)
+        .map(|group| {
+            group
+                .iter()
+                .enumerate()
+                .fold(0u64, |long, (i, &id)| long | (id << (i * bits))) as i64
+        })
+        .collect();
+
+    let palette = palette
+        .into_iter()
+        .map(|name| Tag::Compound(vec![("Name".to_string(), Tag::String(name))]))
+        .collect();
+    Tag::Compound(vec![
+        ("Y".to_string(), Tag::Byte(y as i8)),
+        (
+            "block_states".to_string(),
+            Tag::Compound(vec![
+                ("palette".to_string(), Tag::List(palette)),
+                ("data".to_string(), Tag::LongArray(data)),
+            ]),
+        ),
+    ])
+}
@@ -1,103 +1,142 @@
-use std::{fs::File, io::Read};
+use crate::error::LoadError;
+use crate::trace::{Scoped, Tracer};
+use crate::weights::{MATRIX_ROM_SIZE, WeightSource, read_softmax_table};
+use crate::{
+    EMBED_SIZE, FIXED_POINT_MASK, FIXED_POINT_SIZE, Fixed24, matmul::MatMul, matmul::set_row,
+};
 
-use crate::{matmul::MatMul, Fixed24, EMBED_SIZE, FIXED_POINT_MASK, FIXED_POINT_SIZE};
-
-const HEADS: usize = 5;
-const HEAD_SIZE: usize = EMBED_SIZE / HEADS;
+pub(crate) const HEADS: usize = 5;
+pub(crate) const HEAD_SIZE: usize = EMBED_SIZE / HEADS;
 
 const ATT_CONST: u64 = 4331858; // int((1 << 26) / sqrt(EMBED_SIZE))
 
+/// Where a token's key and value go in the cache.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Slot {
+    /// Overwrite this entry, or append if the cache isn't that long yet.
+    Ring(usize),
+    /// Append, then drop the oldest entries past this many.
+    Window(usize),
+}
+
+/// Every head's keys and values for the tokens seen so far, as the
+/// pseudo-floats the machine stores them in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Cache {
+    pub(crate) keys: [Vec<[u16; HEAD_SIZE]>; HEADS],
+    pub(crate) values: [Vec<[u16; HEAD_SIZE]>; HEADS],
+}
+
+impl Cache {
+    pub(crate) fn new() -> Self {
+        Cache {
+            keys: std::array::from_fn(|_| Vec::new()),
+            values: std::array::from_fn(|_| Vec::new()),
+        }
+    }
+
+    /// Number of tokens cached, the same for every head.
+    pub(crate) fn len(&self) -> usize {
+        self.keys[0].len()
+    }
+
+    /// Drops all but the first `len` tokens.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        for head in 0..HEADS {
+            self.keys[head].truncate(len);
+            self.values[head].truncate(len);
+        }
+    }
+
+    /// Caches one head's key and value for a new token.
+    pub(crate) fn store(
+        &mut self,
+        head: usize,
+        slot: Slot,
+        key: [u16; HEAD_SIZE],
+        value: [u16; HEAD_SIZE],
+    ) {
+        let (keys, values) = (&mut self.keys[head], &mut self.values[head]);
+        match slot {
+            Slot::Ring(i) if i < keys.len() => {
+                keys[i] = key;
+                values[i] = value;
+            }
+            Slot::Ring(_) => {
+                keys.push(key);
+                values.push(value);
+            }
+            Slot::Window(size) => {
+                keys.push(key);
+                values.push(value);
+                let excess = keys.len().saturating_sub(size);
+                keys.drain(..excess);
+                values.drain(..excess);
+            }
+        }
+    }
+}
+
 pub struct Attention {
     matmul_key: [MatMul<EMBED_SIZE, HEAD_SIZE>; HEADS],
     matmul_value: [MatMul<EMBED_SIZE, HEAD_SIZE>; HEADS],
     matmul_query: [MatMul<EMBED_SIZE, HEAD_SIZE>; HEADS],
     matmul_proj: MatMul<EMBED_SIZE, EMBED_SIZE>,
     softmax_exp: [u32; 1024],
-    k_cache: [Vec<[u16; HEAD_SIZE]>; HEADS],
-    v_cache: [Vec<[u16; HEAD_SIZE]>; HEADS],
+    pub(crate) cache: Cache,
 }
 
 impl Attention {
-    pub fn new(block_num: usize) -> Self {
-        let mut key: [[[u8; EMBED_SIZE]; HEAD_SIZE]; HEADS] = [[[0; EMBED_SIZE]; HEAD_SIZE]; HEADS];
-        let mut value: [[[u8; EMBED_SIZE]; HEAD_SIZE]; HEADS] =
-            [[[0; EMBED_SIZE]; HEAD_SIZE]; HEADS];
-        let mut query: [[[u8; EMBED_SIZE]; HEAD_SIZE]; HEADS] =
-            [[[0; EMBED_SIZE]; HEAD_SIZE]; HEADS];
-        let mut proj: [[u8; EMBED_SIZE]; EMBED_SIZE] = [[0; EMBED_SIZE]; EMBED_SIZE];
+    pub fn new(source: &dyn WeightSource, block_num: usize) -> Result<Self, LoadError> {
+        let mut key: [Vec<u8>; HEADS] = std::array::from_fn(|_| vec![0; HEAD_SIZE * EMBED_SIZE]);
+        let mut value: [Vec<u8>; HEADS] = std::array::from_fn(|_| vec![0; HEAD_SIZE * EMBED_SIZE]);
+        let mut query: [Vec<u8>; HEADS] = std::array::from_fn(|_| vec![0; HEAD_SIZE * EMBED_SIZE]);
+        let mut proj = vec![0u8; EMBED_SIZE * EMBED_SIZE];
 
         for i in 0..24 {
-            let path = format!(
-                "weights/weight_files/attention/att_{}.bin",
-                1 + 24 * block_num + i
-            );
-            let mut file = File::open(path).expect("could't read file");
-            let mut cur_weights: [u8; 9600] = [0; 9600];
-            file.read_exact(&mut cur_weights)
-                .expect("couldn't read into buffer");
+            let name = format!("attention/att_{}", 1 + 24 * block_num + i);
+            let cur_weights = source.read(&name, MATRIX_ROM_SIZE)?;
+            let row = |n: usize| &cur_weights[EMBED_SIZE * n..EMBED_SIZE * (n + 1)];
+
+            // Each ROM holds two output rows; odd ROMs store them swapped.
+            let (first, second) = if i % 2 == 0 {
+                (2 * i, 2 * i + 1)
+            } else {
+                (2 * i + 1, 2 * i)
+            };
 
             for j in 0..HEADS {
-                if i % 2 == 0 {
-                    for k in 0..EMBED_SIZE {
-                        key[j][2 * i][k] = cur_weights[EMBED_SIZE * (3 * j) + k];
-                        value[j][2 * i][k] = cur_weights[EMBED_SIZE * (3 * j + 1) + k];
-                        query[j][2 * i][k] = cur_weights[EMBED_SIZE * (3 * j + 2) + k];
-                        key[j][2 * i + 1][k] = cur_weights[EMBED_SIZE * (3 * j + 20) + k];
-                        value[j][2 * i + 1][k] = cur_weights[EMBED_SIZE * (3 * j + 21) + k];
-                        query[j][2 * i + 1][k] = cur_weights[EMBED_SIZE * (3 * j + 22) + k];
-                    }
-                } else {
-                    for k in 0..EMBED_SIZE {
-                        key[j][2 * i + 1][k] = cur_weights[EMBED_SIZE * (3 * j) + k];
-                        value[j][2 * i + 1][k] = cur_weights[EMBED_SIZE * (3 * j + 1) + k];
-                        query[j][2 * i + 1][k] = cur_weights[EMBED_SIZE * (3 * j + 2) + k];
-                        key[j][2 * i][k] = cur_weights[EMBED_SIZE * (3 * j + 20) + k];
-                        value[j][2 * i][k] = cur_weights[EMBED_SIZE * (3 * j + 21) + k];
-                        query[j][2 * i][k] = cur_weights[EMBED_SIZE * (3 * j + 22) + k];
-                    }
-                }
+                set_row(&mut key[j], first, row(3 * j));
+                set_row(&mut value[j], first, row(3 * j + 1));
+                set_row(&mut query[j], first, row(3 * j + 2));
+                set_row(&mut key[j], second, row(3 * j + 20));
+                set_row(&mut value[j], second, row(3 * j + 21));
+                set_row(&mut query[j], second, row(3 * j + 22));
             }
 
             for j in 0..HEADS {
-                if i % 2 == 0 {
-                    for k in 0..EMBED_SIZE {
-                        proj[HEAD_SIZE * j + 2 * i][k] = cur_weights[EMBED_SIZE * (j + 15) + k];
-                        proj[HEAD_SIZE * j + 2 * i + 1][k] = cur_weights[EMBED_SIZE * (j + 35) + k];
-                    }
-                } else {
-                    for k in 0..EMBED_SIZE {
-                        proj[HEAD_SIZE * j + 2 * i + 1][k] = cur_weights[EMBED_SIZE * (j + 15) + k];
-                        proj[HEAD_SIZE * j + 2 * i][k] = cur_weights[EMBED_SIZE * (j + 35) + k];
-                    }
-                }
+                set_row(&mut proj, HEAD_SIZE * j + first, row(j + 15));
+                set_row(&mut proj, HEAD_SIZE * j + second, row(j + 35));
             }
         }
 
-        let matmul_key: [MatMul<EMBED_SIZE, HEAD_SIZE>; HEADS] =
-            key.map(|k| MatMul::new(&k, false));
+        let matmul_key: [MatMul<EMBED_SIZE, HEAD_SIZE>; HEADS] = key.map(|k| MatMul::new(k, false));
         let matmul_value: [MatMul<EMBED_SIZE, HEAD_SIZE>; HEADS] =
-            value.map(|v| MatMul::new(&v, false));
+            value.map(|v| MatMul::new(v, false));
         let matmul_query: [MatMul<EMBED_SIZE, HEAD_SIZE>; HEADS] =
-            query.map(|q| MatMul::new(&q, false));
-        let matmul_proj = MatMul::new(&proj, false);
-
-        let mut softmax_exp = [0u32; 1024];
-        let mut file = File::open("weights/weight_files/softmax.bin").expect("couldn't read file");
-        for i in 0..1024 {
-            let mut buf = [0u8; 3];
-            file.read_exact(&mut buf).expect("could't read into buffer");
-            softmax_exp[i] = u32::from_le_bytes([buf[0], buf[1], buf[2], 0]);
-        }
+            query.map(|q| MatMul::new(q, false));
+        let matmul_proj = MatMul::new(proj, false);
+
+        let softmax_exp = read_softmax_table(source, "softmax")?;
 
-        Attention {
+        Ok(Attention {
             matmul_key,
             matmul_value,
             matmul_query,
             matmul_proj,
             softmax_exp,
-            k_cache: std::array::from_fn(|_| Vec::new()),
-            v_cache: std::array::from_fn(|_| Vec::new()),
-        }
+            cache: Cache::new(),
+        })
     }
 
     fn to_float16(&self, value: u32, offset: i32) -> u16 {
@@ -119,31 +158,29 @@ impl Attention {
         0
     }
 
-    pub fn undo_last(&mut self) {
-        for i in 0..HEADS {
-            self.k_cache[i].pop();
-            self.v_cache[i].pop();
-        }
-    }
-
-    pub fn forward(&mut self, input: &[Fixed24; EMBED_SIZE]) -> [Fixed24; EMBED_SIZE] {
+    pub fn forward(
+        &mut self,
+        input: &[Fixed24; EMBED_SIZE],
+        slot: Slot,
+        tracer: &mut dyn Tracer,
+    ) -> [Fixed24; EMBED_SIZE] {
         let mut proj_input = [0u32; EMBED_SIZE];
         let mut proj_offset = 0;
 
         for head in 0..HEADS {
+            let mut tracer = Scoped::new(tracer, "head", head);
             let keys = self.matmul_key[head].forward(input);
             let mut keys_array = [0u16; HEAD_SIZE];
             for (i, &k) in keys.iter().enumerate() {
                 keys_array[i] = self.to_float16(k, 0);
             }
-            self.k_cache[head].push(keys_array);
 
             let values = self.matmul_value[head].forward(input);
             let mut values_array = [0u16; HEAD_SIZE];
             for (i, &v) in values.iter().enumerate() {
                 values_array[i] = self.to_float16(v, 0);
             }
-            self.v_cache[head].push(values_array);
+            self.cache.store(head, slot, keys_array, values_array);
 
             let queries = self.matmul_query[head].forward(input);
             let mut queries_array = [0u16; HEAD_SIZE];
@@ -151,34 +188,37 @@ impl Attention {
                 queries_array[i] = self.to_float16(q, 0);
             }
 
-            let cache_len = self.k_cache[head].len();
-            let mut relevance = [0u32; 1024];
-            for (i, v) in self.k_cache[head].iter().enumerate() {
+            let cache_len = self.cache.len();
+            let mut relevance = vec![0u32; cache_len];
+            for (i, v) in self.cache.keys[head].iter().enumerate() {
                 for (j, &q) in queries_array.iter().enumerate() {
                     relevance[i] = relevance[i].wrapping_add(float_mult(v[j], q, 5));
                     relevance[i] &= FIXED_POINT_MASK;
                 }
             }
 
-            let mut biggest = 0u32;
-            for i in 0..cache_len {
-                let neg = relevance[i] > FIXED_POINT_MASK / 2;
+            for r in &mut relevance {
+                let neg = *r > FIXED_POINT_MASK / 2;
                 if neg {
-                    relevance[i] = relevance[i].wrapping_neg() & (FIXED_POINT_MASK / 2);
+                    *r = r.wrapping_neg() & (FIXED_POINT_MASK / 2);
                 }
-                relevance[i] = ((relevance[i] as u64 * ATT_CONST as u64) >> 23) as u32
-                    & (FIXED_POINT_MASK / 2);
+                *r = ((*r as u64 * ATT_CONST) >> 23) as u32 & (FIXED_POINT_MASK / 2);
                 if neg {
-                    relevance[i] = relevance[i].wrapping_neg() & FIXED_POINT_MASK;
+                    *r = r.wrapping_neg() & FIXED_POINT_MASK;
                 }
-                relevance[i] ^= 1 << (FIXED_POINT_SIZE - 1);
-                biggest = biggest.max(relevance[i]);
+            }
+            tracer.record("scores", &relevance[..cache_len]);
+
+            let mut biggest = 0u32;
+            for r in &mut relevance {
+                *r ^= 1 << (FIXED_POINT_SIZE - 1);
+                biggest = biggest.max(*r);
             }
 
             let mut output = [0u32; HEAD_SIZE];
             let mut softmax_sum = 0u32;
-            for i in 0..cache_len {
-                let power = (biggest - relevance[i]) >> 10;
+            for &r in &relevance {
+                let power = (biggest - r) >> 10;
                 let res = if power >= 1024 {
                     0
                 } else {
@@ -189,8 +229,9 @@ impl Attention {
             softmax_sum &= FIXED_POINT_MASK;
             let softmax_sum_inv = (1u64 << 39) / softmax_sum as u64;
 
-            for i in 0..cache_len {
-                let power = (biggest - relevance[i]) >> 10;
+            let mut weights = Vec::with_capacity(cache_len);
+            for (&r, values) in relevance.iter().zip(&self.cache.values[head]) {
+                let power = (biggest - r) >> 10;
                 let res = if power >= 1024 {
                     0
                 } else {
@@ -198,22 +239,24 @@ impl Attention {
                 };
                 let mut res =
                     ((softmax_sum_inv * res as u64) >> 17) as u32 & (FIXED_POINT_MASK / 2);
+                weights.push(res);
                 res = self.to_float16(res, 4) as u32;
 
-                for (j, &v) in self.v_cache[head][i].iter().enumerate() {
+                for (j, &v) in values.iter().enumerate() {
                     output[j] = output[j].wrapping_add(float_mult(res as u16, v, 0));
                     output[j] &= FIXED_POINT_MASK;
                 }
             }
 
+            tracer.record("weights", &weights);
+
             for (i, &val) in output.iter().enumerate() {
                 proj_input[proj_offset + i] = val;
             }
             proj_offset += HEAD_SIZE;
         }
 
-        let result = self.matmul_proj.forward(&proj_input);
-        result
+        self.matmul_proj.forward(&proj_input)
     }
 }
 
@@ -247,3 +290,39 @@ fn float_mult(a: u16, b: u16, shift: u32) -> u32 {
     }
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ModelPaths;
+    use crate::layout::{Matrix, matrix_rows};
+    use crate::model::LAYERS;
+
+    #[test]
+    fn rom_rows_match_the_loader() {
+        let paths = ModelPaths::default();
+        for block in 0..LAYERS {
+            let attention = Attention::new(&paths, block).unwrap();
+            for head in 0..HEADS {
+                let matrices = [
+                    (Matrix::Query { block, head }, &attention.matmul_query[head]),
+                    (Matrix::Key { block, head }, &attention.matmul_key[head]),
+                    (Matrix::Value { block, head }, &attention.matmul_value[head]),
+                ];
+                for (matrix, matmul) in matrices {
+                    assert_eq!(
+                        matrix_rows(matrix, &paths).concat(),
+                        matmul.weights(),
+                        "{}",
+                        matrix
+                    );
+                }
+            }
+            let proj = Matrix::AttentionProj { block };
+            assert_eq!(
+                matrix_rows(proj, &paths).concat(),
+                attention.matmul_proj.weights()
+            );
+        }
+    }
+}
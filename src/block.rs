@@ -1,43 +1,58 @@
-use crate::attention::Attention;
+use crate::attention::{Attention, Cache, Slot};
+use crate::error::LoadError;
 use crate::layernorm::LayerNorm;
-use crate::mlp::MLP;
+use crate::mlp::Mlp;
+use crate::trace::Tracer;
+use crate::weights::WeightSource;
 use crate::{EMBED_SIZE, FIXED_POINT_MASK, Fixed24};
 
 pub struct Block {
     ln_1: LayerNorm,
     att: Attention,
     ln_2: LayerNorm,
-    mlp: MLP,
+    mlp: Mlp,
 }
 
 impl Block {
-    pub fn new(block_num: usize) -> Self {
-        Block {
-            ln_1: LayerNorm::new(2 * block_num + 1),
-            att: Attention::new(block_num),
-            ln_2: LayerNorm::new(2 * block_num + 2),
-            mlp: MLP::new(block_num),
-        }
+    pub fn new(source: &dyn WeightSource, block_num: usize) -> Result<Self, LoadError> {
+        Ok(Block {
+            ln_1: LayerNorm::new(source, 2 * block_num + 1)?,
+            att: Attention::new(source, block_num)?,
+            ln_2: LayerNorm::new(source, 2 * block_num + 2)?,
+            mlp: Mlp::new(source, block_num)?,
+        })
     }
 
-    pub fn forward(&mut self, input: &mut [Fixed24; EMBED_SIZE]) {
+    pub fn forward(
+        &mut self,
+        input: &mut [Fixed24; EMBED_SIZE],
+        slot: Slot,
+        tracer: &mut dyn Tracer,
+    ) {
         let ln1_out = self.ln_1.forward(input);
-        let att_diff = self.att.forward(&ln1_out);
+        tracer.record("ln_1", &ln1_out);
+        let att_diff = self.att.forward(&ln1_out, slot, tracer);
+        tracer.record("attn", &att_diff);
 
         for i in 0..EMBED_SIZE {
             input[i] = input[i].wrapping_add(att_diff[i]) & FIXED_POINT_MASK;
         }
 
         let ln2_out = self.ln_2.forward(input);
-        let mlp_diff = self.mlp.forward(&ln2_out);
+        tracer.record("ln_2", &ln2_out);
+        let mlp_diff = self.mlp.forward(&ln2_out, tracer);
+        tracer.record("mlp", &mlp_diff);
 
         for i in 0..EMBED_SIZE {
             input[i] = input[i].wrapping_add(mlp_diff[i]) & FIXED_POINT_MASK;
         }
     }
 
-    #[allow(dead_code)]
-    pub fn undo_last(&mut self) {
-        self.att.undo_last();
+    pub(crate) fn cache(&self) -> &Cache {
+        &self.att.cache
+    }
+
+    pub(crate) fn cache_mut(&mut self) -> &mut Cache {
+        &mut self.att.cache
     }
 }
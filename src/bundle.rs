@@ -0,0 +1,538 @@
+//! A single-file container for a whole weight set.
+//!
+//! Layout, all integers little-endian:
+//!
+//! ```text
+//! magic          8 bytes  "CGPTBNDL"
+//! version        u32
+//! dimensions     6 x u32  embed size, layers, heads, MLP hidden size,
+//!                         vocabulary size, context size
+//! tensor count   u32
+//! tensors        name length (u16), name (UTF-8), offset (u64),
+//!                size (u64), CRC32 of the data (u32)
+//! header CRC32   u32      over everything above
+//! data           the ROM contents, at the recorded offsets
+//! ```
+//!
+//! Tensors are named like the files under `weights/weight_files`, without the
+//! `.bin` extension, so a bundle can be unpacked back into an identical
+//! directory.
+
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io;
+use std::ops::Deref;
+use std::path::{Component, Path, PathBuf};
+
+use memmap2::Mmap;
+
+use crate::attention::HEADS;
+use crate::error::LoadError;
+use crate::mlp::HIDDEN_SIZE;
+use crate::model::{CONTEXT_SIZE, LAYERS};
+use crate::weights::{WeightSource, model_roms, read_rom};
+use crate::{EMBED_SIZE, VOCAB_SIZE};
+
+const MAGIC: &[u8; 8] = b"CGPTBNDL";
+/// Bytes in a tensor entry of the header, besides its name.
+const ENTRY_SIZE: usize = 2 + 8 + 8 + 4;
+pub const FORMAT_VERSION: u32 = 1;
+
+const DIMENSIONS: [u32; 6] = [
+    EMBED_SIZE as u32,
+    LAYERS as u32,
+    HEADS as u32,
+    HIDDEN_SIZE as u32,
+    VOCAB_SIZE as u32,
+    CONTEXT_SIZE as u32,
+];
+
+/// One ROM inside a bundle.
+#[derive(Clone, Debug)]
+pub struct Tensor {
+    pub name: String,
+    pub offset: usize,
+    pub size: usize,
+    pub crc: u32,
+}
+
+/// The bytes of a bundle, either read into memory or mapped from the file.
+enum Data {
+    Owned(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Deref for Data {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Data::Owned(bytes) => bytes,
+            Data::Mapped(map) => map,
+        }
+    }
+}
+
+/// A weight set read from a bundle file.
+pub struct Bundle {
+    path: PathBuf,
+    data: Data,
+    tensors: Vec<Tensor>,
+}
+
+impl Bundle {
+    /// Reads a bundle and checks its header. Tensor checksums are checked as
+    /// each tensor is read.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
+        let path = path.as_ref().to_path_buf();
+        let data = fs::read(&path).map_err(|source| LoadError::Missing {
+            path: path.clone(),
+            source,
+        })?;
+        Bundle::new(path, Data::Owned(data))
+    }
+
+    /// Like [`Bundle::open`], but maps the file into memory instead of reading
+    /// it into a buffer first. This only saves that one buffer: the model
+    /// still copies every tensor into its own matrices as it loads.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may truncate or write to the file while the bundle is alive.
+    /// Truncating it makes reads of the missing part crash the process with
+    /// SIGBUS, and writing to it changes tensors after their checksums were
+    /// checked.
+    pub unsafe fn map<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
+        let path = path.as_ref().to_path_buf();
+        let missing = |source| LoadError::Missing {
+            path: path.clone(),
+            source,
+        };
+        let file = File::open(&path).map_err(missing)?;
+        // SAFETY: the caller promises to leave the file alone while it is
+        // mapped, which is the only way the mapping could change under us.
+        let map = unsafe { Mmap::map(&file) }.map_err(missing)?;
+        Bundle::new(path, Data::Mapped(map))
+    }
+
+    fn new(path: PathBuf, data: Data) -> Result<Self, LoadError> {
+        let tensors = parse_header(&path, &data)?;
+        Ok(Bundle {
+            path,
+            data,
+            tensors,
+        })
+    }
+
+    pub fn tensors(&self) -> &[Tensor] {
+        &self.tensors
+    }
+
+    /// Returns the contents of `name` after checking its checksum.
+    pub fn get(&self, name: &str) -> Result<&[u8], LoadError> {
+        let tensor = self
+            .tensors
+            .iter()
+            .find(|t| t.name == name)
+            .ok_or_else(|| LoadError::Missing {
+                path: self.locate(name),
+                source: io::Error::new(io::ErrorKind::NotFound, "not in bundle"),
+            })?;
+        let bytes = &self.data[tensor.offset..tensor.offset + tensor.size];
+
+        let actual = crc32(bytes);
+        if actual != tensor.crc {
+            return Err(LoadError::Checksum {
+                path: self.locate(name),
+                expected: tensor.crc,
+                actual,
+            });
+        }
+
+        Ok(bytes)
+    }
+
+    /// Checks the checksum of every tensor.
+    pub fn verify(&self) -> Result<(), LoadError> {
+        for tensor in &self.tensors {
+            self.get(&tensor.name)?;
+        }
+        Ok(())
+    }
+
+    /// Writes every tensor back out as `dir/<name>.bin`.
+    pub fn unpack<P: AsRef<Path>>(&self, dir: P) -> Result<(), PackError> {
+        for tensor in &self.tensors {
+            let bytes = self.get(&tensor.name)?;
+            let path = dir.as_ref().join(format!("{}.bin", tensor.name));
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+impl WeightSource for Bundle {
+    fn read(&self, name: &str, size: usize) -> Result<Cow<'_, [u8]>, LoadError> {
+        let bytes = self.get(name)?;
+        if bytes.len() < size {
+            return Err(LoadError::ShortRead {
+                path: self.locate(name),
+                expected: size,
+                actual: bytes.len(),
+            });
+        }
+        if bytes.len() > size {
+            return Err(LoadError::WrongSize {
+                path: self.locate(name),
+                expected: size,
+                actual: bytes.len(),
+            });
+        }
+        Ok(Cow::Borrowed(bytes))
+    }
+
+    fn locate(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+/// Why a bundle could not be written or unpacked.
+#[derive(Debug)]
+pub enum PackError {
+    Io(io::Error),
+    Load(LoadError),
+}
+
+impl std::fmt::Display for PackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PackError::Io(e) => e.fmt(f),
+            PackError::Load(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for PackError {}
+
+impl From<io::Error> for PackError {
+    fn from(e: io::Error) -> Self {
+        PackError::Io(e)
+    }
+}
+
+impl From<LoadError> for PackError {
+    fn from(e: LoadError) -> Self {
+        PackError::Load(e)
+    }
+}
+
+/// Packs every `.bin` file under `weights_dir` into a bundle at `out`.
+///
+/// Every ROM the model needs must be present with the right size; other files
+/// (such as the tokenizer tables) are carried along as they are. Returns the
+/// number of tensors written.
+pub fn pack<P: AsRef<Path>, Q: AsRef<Path>>(weights_dir: P, out: Q) -> Result<usize, PackError> {
+    let weights_dir = weights_dir.as_ref();
+
+    let mut names = Vec::new();
+    collect_bins(weights_dir, weights_dir, &mut names)?;
+    names.sort();
+
+    let mut tensors = Vec::new();
+    for (name, size) in model_roms() {
+        let path = weights_dir.join(format!("{}.bin", name));
+        let bytes = read_rom(&path, size)?;
+        tensors.push((name, bytes));
+    }
+    for name in names {
+        if tensors.iter().any(|(n, _)| *n == name) {
+            continue;
+        }
+        let bytes = fs::read(weights_dir.join(format!("{}.bin", name)))?;
+        tensors.push((name, bytes));
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    for d in DIMENSIONS {
+        header.extend_from_slice(&d.to_le_bytes());
+    }
+    header.extend_from_slice(&(tensors.len() as u32).to_le_bytes());
+
+    let header_len = header.len()
+        + tensors
+            .iter()
+            .map(|(name, _)| ENTRY_SIZE + name.len())
+            .sum::<usize>()
+        + 4;
+
+    let mut offset = header_len;
+    for (name, bytes) in &tensors {
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        header.extend_from_slice(&(offset as u64).to_le_bytes());
+        header.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+        header.extend_from_slice(&crc32(bytes).to_le_bytes());
+        offset += bytes.len();
+    }
+    let header_crc = crc32(&header);
+    header.extend_from_slice(&header_crc.to_le_bytes());
+
+    let mut file = header;
+    for (_, bytes) in &tensors {
+        file.extend_from_slice(bytes);
+    }
+    fs::write(out, file)?;
+
+    Ok(tensors.len())
+}
+
+fn collect_bins(root: &Path, dir: &Path, names: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_bins(root, &path, names)?;
+        } else if path.extension().is_some_and(|e| e == "bin") {
+            let name = path.strip_prefix(root).unwrap().with_extension("");
+            let name = name
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            names.push(name);
+        }
+    }
+    Ok(())
+}
+
+fn parse_header(path: &Path, data: &[u8]) -> Result<Vec<Tensor>, LoadError> {
+    let bad = |reason: &str| LoadError::BadBundle {
+        path: path.to_path_buf(),
+        reason: reason.to_string(),
+    };
+    let mut reader = Reader { data, pos: 0 };
+    let truncated = || bad("header is truncated");
+
+    if reader.take(MAGIC.len()).ok_or_else(truncated)? != MAGIC {
+        return Err(bad("not a CraftGPT bundle"));
+    }
+    let version = reader.u32().ok_or_else(truncated)?;
+    if version != FORMAT_VERSION {
+        return Err(bad(&format!(
+            "format version {} is not supported (expected {})",
+            version, FORMAT_VERSION
+        )));
+    }
+    for expected in DIMENSIONS {
+        if reader.u32().ok_or_else(truncated)? != expected {
+            return Err(bad("bundle was built for a model of a different shape"));
+        }
+    }
+
+    // The count isn't covered by a checksum yet, so it can't be trusted
+    // with an allocation until the file has room for that many entries.
+    let count = reader.u32().ok_or_else(truncated)? as usize;
+    if count > (data.len() - reader.pos) / ENTRY_SIZE {
+        return Err(truncated());
+    }
+    let mut tensors = Vec::with_capacity(count);
+    for _ in 0..count {
+        let name_len = reader.u16().ok_or_else(truncated)? as usize;
+        let name = reader.take(name_len).ok_or_else(truncated)?;
+        let name = String::from_utf8(name.to_vec()).map_err(|_| bad("tensor name is not UTF-8"))?;
+        // Names become paths when the bundle is unpacked, so they must stay
+        // inside the directory it is unpacked into.
+        let mut components = Path::new(&name).components();
+        if name.is_empty() || !components.all(|c| matches!(c, Component::Normal(_))) {
+            return Err(bad(&format!(
+                "tensor name '{}' is not a relative path",
+                name
+            )));
+        }
+        if tensors.iter().any(|t: &Tensor| t.name == name) {
+            return Err(bad(&format!("tensor '{}' appears twice", name)));
+        }
+        let offset = reader.u64().ok_or_else(truncated)? as usize;
+        let size = reader.u64().ok_or_else(truncated)? as usize;
+        let crc = reader.u32().ok_or_else(truncated)?;
+        if offset.checked_add(size).is_none_or(|end| end > data.len()) {
+            return Err(LoadError::ShortRead {
+                path: path.join(&name),
+                expected: offset.saturating_add(size),
+                actual: data.len(),
+            });
+        }
+        tensors.push(Tensor {
+            name,
+            offset,
+            size,
+            crc,
+        });
+    }
+
+    let header_end = reader.pos;
+    let header_crc = reader.u32().ok_or_else(truncated)?;
+    if crc32(&data[..header_end]) != header_crc {
+        return Err(bad("header checksum mismatch"));
+    }
+
+    Ok(tensors)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+/// Lookup tables for CRC-32, extended to process eight bytes at a time.
+const CRC_TABLES: [[u32; 256]; 8] = {
+    let mut tables = [[0u32; 256]; 8];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xEDB88320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        tables[0][i] = c;
+        i += 1;
+    }
+    let mut t = 1;
+    while t < 8 {
+        let mut i = 0;
+        while i < 256 {
+            let prev = tables[t - 1][i];
+            tables[t][i] = (prev >> 8) ^ tables[0][(prev & 0xff) as usize];
+            i += 1;
+        }
+        t += 1;
+    }
+    tables
+};
+
+/// The CRC-32 used by zip and PNG.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let t = &CRC_TABLES;
+    let mut c = !0u32;
+
+    let mut chunks = bytes.chunks_exact(8);
+    for b in &mut chunks {
+        let lo = c ^ u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        c = t[7][(lo & 0xff) as usize]
+            ^ t[6][((lo >> 8) & 0xff) as usize]
+            ^ t[5][((lo >> 16) & 0xff) as usize]
+            ^ t[4][(lo >> 24) as usize]
+            ^ t[3][b[4] as usize]
+            ^ t[2][b[5] as usize]
+            ^ t[1][b[6] as usize]
+            ^ t[0][b[7] as usize];
+    }
+    for &b in chunks.remainder() {
+        c = t[0][((c ^ b as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+
+    !c
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A header for tensors of the given names, all empty.
+    fn header(count: u32, names: &[&str]) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        for d in DIMENSIONS {
+            header.extend_from_slice(&d.to_le_bytes());
+        }
+        header.extend_from_slice(&count.to_le_bytes());
+        for name in names {
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(name.as_bytes());
+            header.extend_from_slice(&[0; 8 + 8]);
+            header.extend_from_slice(&crc32(&[]).to_le_bytes());
+        }
+        let crc = crc32(&header);
+        header.extend_from_slice(&crc.to_le_bytes());
+        header
+    }
+
+    fn reason(data: &[u8]) -> String {
+        match parse_header(Path::new("test.cgpt"), data) {
+            Err(LoadError::BadBundle { reason, .. }) => reason,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("header was accepted"),
+        }
+    }
+
+    #[test]
+    fn reads_names() {
+        let tensors = parse_header(Path::new("test.cgpt"), &header(2, &["a", "b/c"])).unwrap();
+        let names: Vec<_> = tensors.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["a", "b/c"]);
+    }
+
+    #[test]
+    fn a_count_past_the_end_is_truncated() {
+        assert_eq!(reason(&header(u32::MAX, &["a"])), "header is truncated");
+    }
+
+    #[test]
+    fn names_must_stay_inside_the_bundle() {
+        for name in ["../escape", "a/../../b", "/etc/passwd", "./a", ""] {
+            assert!(
+                reason(&header(1, &[name])).contains("not a relative path"),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn names_must_be_unique() {
+        assert_eq!(
+            reason(&header(3, &["a", "b", "a"])),
+            "tensor 'a' appears twice"
+        );
+    }
+
+    #[test]
+    fn crc32_known_answers() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        // Long enough to go through the eight-byte path with a remainder.
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414FA339
+        );
+    }
+}
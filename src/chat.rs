@@ -0,0 +1,228 @@
+//! Turning conversations into tokens and back.
+//!
+//! The model was trained on conversations where each user turn sits between
+//! the instruction markers `_[inst]` and `_[/inst]`, and the assistant's
+//! answer follows the closing marker until the model picks a marker itself.
+
+use std::fmt;
+
+use crate::Tokenizer;
+use crate::tokenizer::TokenizeError;
+
+/// Opens a user turn.
+pub const INST_START: &str = "_[inst]";
+/// Closes a user turn; the assistant answers after it.
+pub const INST_END: &str = "_[/inst]";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Role::User => f.write_str("User"),
+            Role::Assistant => f.write_str("Assistant"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub role: Role,
+    pub text: String,
+}
+
+impl Message {
+    pub fn user<S: Into<String>>(text: S) -> Self {
+        Message {
+            role: Role::User,
+            text: text.into(),
+        }
+    }
+
+    pub fn assistant<S: Into<String>>(text: S) -> Self {
+        Message {
+            role: Role::Assistant,
+            text: text.into(),
+        }
+    }
+}
+
+/// The instruction markers of a vocabulary.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChatTemplate {
+    /// The ID of [`INST_START`].
+    pub start: usize,
+    /// The ID of [`INST_END`].
+    pub end: usize,
+}
+
+impl ChatTemplate {
+    /// Finds the markers in the vocabulary, or `None` if it lacks either.
+    pub fn new(tokenizer: &Tokenizer) -> Option<Self> {
+        Some(ChatTemplate {
+            start: tokenizer.id(INST_START)?,
+            end: tokenizer.id(INST_END)?,
+        })
+    }
+
+    /// Whether `id` is one of the markers. The model ends its answer by
+    /// picking one.
+    pub fn is_marker(&self, id: usize) -> bool {
+        id == self.start || id == self.end
+    }
+
+    /// The tokens of one user turn, markers included.
+    pub fn user_turn(
+        &self,
+        tokenizer: &Tokenizer,
+        text: &str,
+    ) -> Result<Vec<usize>, TokenizeError> {
+        let mut ids = vec![self.start];
+        ids.extend(tokenizer.encode(text)?);
+        ids.push(self.end);
+        Ok(ids)
+    }
+
+    /// The tokens of a whole conversation. If it ends with a user turn, the
+    /// model answers it next; if with the assistant's, the model carries on
+    /// with that answer.
+    pub fn prompt(
+        &self,
+        tokenizer: &Tokenizer,
+        messages: &[Message],
+    ) -> Result<Vec<usize>, TokenizeError> {
+        let mut ids = Vec::new();
+        for message in messages {
+            match message.role {
+                Role::User => ids.extend(self.user_turn(tokenizer, &message.text)?),
+                Role::Assistant => ids.extend(tokenizer.encode(&message.text)?),
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Splits processed tokens back into messages: what is between the
+    /// markers is the user's, what follows a closing marker the assistant's.
+    /// Tokens before the first marker are taken as the assistant's too.
+    pub fn messages(&self, tokenizer: &Tokenizer, ids: &[usize]) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let mut role = Role::Assistant;
+        let mut turn = Vec::new();
+        let mut flush = |role: Role, turn: &mut Vec<usize>| {
+            if !turn.is_empty() {
+                messages.push(Message {
+                    role,
+                    text: tokenizer.decode(turn).trim().to_string(),
+                });
+                turn.clear();
+            }
+        };
+
+        for &id in ids {
+            if id == self.start {
+                flush(role, &mut turn);
+                role = Role::User;
+            } else if id == self.end {
+                flush(role, &mut turn);
+                role = Role::Assistant;
+            } else {
+                turn.push(id);
+            }
+        }
+        flush(role, &mut turn);
+        messages
+    }
+
+    /// The conversation as text, one `Role: text` line per message.
+    pub fn transcript(&self, tokenizer: &Tokenizer, ids: &[usize]) -> String {
+        self.messages(tokenizer, ids)
+            .iter()
+            .map(|message| format!("{}: {}\n", message.role, message.text))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokenizer() -> Tokenizer {
+        let tokens = [
+            "_[inst]", "_[/inst]", "_hello", "_there", "_hi", "!", "_how", "_are", "_you", "?",
+        ];
+        Tokenizer::new(tokens.iter().map(|t| t.to_string()).collect())
+    }
+
+    #[test]
+    fn finds_the_markers() {
+        let template = ChatTemplate::new(&tokenizer()).unwrap();
+        assert_eq!(template, ChatTemplate { start: 0, end: 1 });
+        assert!(template.is_marker(0) && template.is_marker(1) && !template.is_marker(2));
+
+        let unmarked = Tokenizer::new(vec!["_hello".to_string()]);
+        assert_eq!(ChatTemplate::new(&unmarked), None);
+    }
+
+    #[test]
+    fn round_trips_a_conversation() {
+        let tokenizer = tokenizer();
+        let template = ChatTemplate::new(&tokenizer).unwrap();
+        let conversation = [
+            Message::user("hello there"),
+            Message::assistant("hi!"),
+            Message::user("how are you?"),
+            Message::assistant("hi"),
+        ];
+
+        let ids = template.prompt(&tokenizer, &conversation).unwrap();
+        assert_eq!(ids, [0, 2, 3, 1, 4, 5, 0, 6, 7, 8, 9, 1, 4]);
+        assert_eq!(template.messages(&tokenizer, &ids), conversation);
+        assert_eq!(
+            template.transcript(&tokenizer, &ids),
+            "User: hello there\nAssistant: hi!\nUser: how are you?\nAssistant: hi\n"
+        );
+    }
+
+    #[test]
+    fn a_trailing_user_turn_is_left_for_the_model() {
+        let tokenizer = tokenizer();
+        let template = ChatTemplate::new(&tokenizer).unwrap();
+        let conversation = [
+            Message::user("hello"),
+            Message::assistant("hi"),
+            Message::user("you?"),
+        ];
+
+        let ids = template.prompt(&tokenizer, &conversation).unwrap();
+        assert_eq!(ids.last(), Some(&template.end));
+        assert_eq!(template.messages(&tokenizer, &ids), conversation);
+    }
+
+    #[test]
+    fn tokens_before_the_first_marker_are_the_assistants() {
+        let tokenizer = tokenizer();
+        let template = ChatTemplate::new(&tokenizer).unwrap();
+        let mut ids = tokenizer.encode("hi!").unwrap();
+        ids.extend(template.user_turn(&tokenizer, "hello").unwrap());
+
+        assert_eq!(
+            template.messages(&tokenizer, &ids),
+            [Message::assistant("hi!"), Message::user("hello")]
+        );
+    }
+
+    #[test]
+    fn untokenizable_messages_are_errors() {
+        let tokenizer = tokenizer();
+        let template = ChatTemplate::new(&tokenizer).unwrap();
+        assert!(
+            template
+                .prompt(&tokenizer, &[Message::user("zzz")])
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,285 @@
+//! The model's prediction for the next token, both as the machine sees it
+//! and over the whole vocabulary.
+
+use crate::reference::to_f32;
+use crate::{Fixed24, OUTPUT_SIZE, VOCAB_SIZE};
+
+/// Scale of the machine's fixed-point probabilities.
+const PROB_SCALE: f64 = (1u64 << 23) as f64;
+
+/// One of the [`OUTPUT_SIZE`] tokens the machine passes on to its sampler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    pub token: usize,
+    /// The probability as the machine holds it, scaled by 2^23.
+    pub prob: u32,
+}
+
+impl Candidate {
+    pub fn probability(&self) -> f64 {
+        self.prob as f64 / PROB_SCALE
+    }
+}
+
+/// The output of one step of the model.
+///
+/// The hardware view is what comes out of the machine: its softmax over the
+/// logits, of which only the [`OUTPUT_SIZE`] most likely tokens are kept.
+/// The full view has every token's logit and an exact softmax over them,
+/// which never rounds a token down to nothing and sums to 1, for measuring
+/// the model rather than the machine.
+#[derive(Clone, Debug)]
+pub struct Distribution {
+    logits: Vec<Fixed24>,
+    /// The machine's softmax output for every token, scaled by 2^23.
+    machine: Vec<u32>,
+    /// The top tokens, packed as `(probability << 11) | token`.
+    packed: Vec<u64>,
+    probs: Vec<f64>,
+}
+
+impl Distribution {
+    pub(crate) fn new(logits: [Fixed24; VOCAB_SIZE], machine: Vec<u32>) -> Self {
+        let mut packed = vec![0u64; OUTPUT_SIZE];
+        for (i, &p) in machine.iter().enumerate() {
+            let mut res = (1 << 11) * p as u64 + i as u64;
+            for slot in &mut packed {
+                if res > *slot {
+                    std::mem::swap(slot, &mut res);
+                }
+            }
+        }
+
+        let real: Vec<f64> = logits.iter().map(|&l| to_f32(l) as f64).collect();
+        let max = real.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let exp: Vec<f64> = real.iter().map(|l| (l - max).exp()).collect();
+        let sum: f64 = exp.iter().sum();
+
+        Distribution {
+            logits: logits.to_vec(),
+            machine,
+            packed,
+            probs: exp.iter().map(|e| e / sum).collect(),
+        }
+    }
+
+    /// A distribution whose full view is exactly `probs`, and whose machine
+    /// view rounds them to the machine's scale, for testing samplers.
+    #[cfg(test)]
+    pub(crate) fn with_probabilities(probs: Vec<f64>) -> Self {
+        let machine = probs.iter().map(|&p| (p * PROB_SCALE) as u32).collect();
+        Distribution {
+            probs,
+            ..Distribution::new([0; VOCAB_SIZE], machine)
+        }
+    }
+
+    /// The top tokens packed as `(probability << 11) | token`, most likely
+    /// first, as [`crate::Model::process`] returns them.
+    pub fn packed(&self) -> &[u64] {
+        &self.packed
+    }
+
+    /// The top tokens, most likely first.
+    pub fn top(&self) -> impl Iterator<Item = Candidate> + '_ {
+        self.packed.iter().map(|&a| Candidate {
+            token: (a & 2047) as usize,
+            prob: (a >> 11) as u32,
+        })
+    }
+
+    /// The raw fixed-point logits, one per token.
+    pub fn logits(&self) -> &[Fixed24] {
+        &self.logits
+    }
+
+    /// The real value of `token`'s logit.
+    pub fn logit(&self, token: usize) -> f32 {
+        to_f32(self.logits[token])
+    }
+
+    /// What the machine's softmax gives `token`, scaled by 2^23. Tokens far
+    /// enough below the most likely come out as 0.
+    pub fn machine_prob(&self, token: usize) -> u32 {
+        self.machine[token]
+    }
+
+    /// The exact softmax over every logit.
+    pub fn probabilities(&self) -> &[f64] {
+        &self.probs
+    }
+
+    pub fn probability(&self, token: usize) -> f64 {
+        self.probs[token]
+    }
+
+    /// Every token with its probability, most likely first. Ties go to the
+    /// higher ID, as they do in the machine.
+    pub fn ranked(&self) -> impl Iterator<Item = (usize, f64)> + '_ {
+        let mut order: Vec<usize> = (0..VOCAB_SIZE).collect();
+        order.sort_by(|&a, &b| self.probs[b].total_cmp(&self.probs[a]).then(b.cmp(&a)));
+        order.into_iter().map(|id| (id, self.probs[id]))
+    }
+
+    /// How many tokens are ranked above `token`; 0 for the most likely.
+    pub fn rank(&self, token: usize) -> usize {
+        let p = self.probs[token];
+        (0..VOCAB_SIZE)
+            .filter(|&id| self.probs[id] > p || (self.probs[id] == p && id > token))
+            .count()
+    }
+
+    /// Probability of everything the machine drops, outside its top tokens.
+    pub fn tail_mass(&self) -> f64 {
+        1.0 - self.top().map(|c| self.probs[c.token]).sum::<f64>()
+    }
+
+    /// Takes out the tokens `allowed` rejects, so no sampler picks them: they
+    /// drop out of the top tokens, keeping the rest in order, and get no
+    /// probability in the full view. If that would leave no top tokens at
+    /// all, nothing is taken out.
+    pub fn mask<F: Fn(usize) -> bool>(&self, allowed: F) -> Distribution {
+        let packed: Vec<u64> = self
+            .packed
+            .iter()
+            .copied()
+            .filter(|&a| allowed((a & 2047) as usize))
+            .collect();
+        if packed.is_empty() {
+            return self.clone();
+        }
+
+        let mut probs = self.probs.clone();
+        let mut machine = self.machine.clone();
+        for id in (0..VOCAB_SIZE).filter(|&id| !allowed(id)) {
+            probs[id] = 0.0;
+            machine[id] = 0;
+        }
+        let sum: f64 = probs.iter().sum();
+        for p in &mut probs {
+            *p /= sum;
+        }
+
+        Distribution {
+            logits: self.logits.clone(),
+            machine,
+            packed,
+            probs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tokens 0 to 9 each with probability 0.1.
+    fn tenths() -> Distribution {
+        let mut probs = vec![0.0; VOCAB_SIZE];
+        probs[..10].fill(0.1);
+        Distribution::with_probabilities(probs)
+    }
+
+    #[test]
+    fn softmax_of_the_logits() {
+        let mut logits = [0; VOCAB_SIZE];
+        // 1.0 and -1.0 in the logits' fixed point.
+        logits[4] = 1 << 18;
+        logits[5] = (1 << 24) - (1 << 18);
+        let dist = Distribution::new(logits, vec![0; VOCAB_SIZE]);
+
+        assert_eq!((dist.logit(4), dist.logit(5)), (1.0, -1.0));
+        let sum: f64 = dist.probabilities().iter().sum();
+        assert!((sum - 1.0).abs() < 1e-9);
+        let ratio = dist.probability(4) / dist.probability(0);
+        assert!((ratio - 1f64.exp()).abs() < 1e-9);
+        let ratio = dist.probability(0) / dist.probability(5);
+        assert!((ratio - 1f64.exp()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ranks_by_probability_then_higher_id() {
+        let mut probs = vec![0.0; VOCAB_SIZE];
+        probs[3] = 0.5;
+        probs[5] = 0.2;
+        probs[7] = 0.2;
+        probs[9] = 0.1;
+        let dist = Distribution::with_probabilities(probs);
+
+        let ranked: Vec<(usize, f64)> = dist.ranked().collect();
+        assert_eq!(ranked[..4], [(3, 0.5), (7, 0.2), (5, 0.2), (9, 0.1)]);
+        assert_eq!(ranked[4], (VOCAB_SIZE - 1, 0.0));
+        assert_eq!(ranked[VOCAB_SIZE - 1], (0, 0.0));
+        for (i, &(id, _)) in ranked.iter().enumerate() {
+            assert_eq!(dist.rank(id), i, "token {}", id);
+        }
+    }
+
+    #[test]
+    fn packs_the_most_likely_tokens() {
+        let mut machine = vec![0; VOCAB_SIZE];
+        for (i, p) in machine.iter_mut().enumerate() {
+            *p = ((i * 7919) % 1000) as u32 * 4000;
+        }
+        // Ties go to the higher ID.
+        machine[100] = 1 << 23;
+        machine[50] = 1 << 23;
+
+        // The original `Unembedding::forward` kept the largest packed values.
+        let mut expected: Vec<u64> = machine
+            .iter()
+            .enumerate()
+            .map(|(i, &p)| (1 << 11) * p as u64 + i as u64)
+            .collect();
+        expected.sort_unstable_by(|a, b| b.cmp(a));
+        expected.truncate(OUTPUT_SIZE);
+
+        let dist = Distribution::new([0; VOCAB_SIZE], machine);
+        assert_eq!(dist.packed(), expected);
+        let top: Vec<Candidate> = dist.top().collect();
+        assert_eq!(
+            top[0],
+            Candidate {
+                token: 100,
+                prob: 1 << 23
+            }
+        );
+        assert_eq!(
+            top[1],
+            Candidate {
+                token: 50,
+                prob: 1 << 23
+            }
+        );
+        assert_eq!(top[0].probability(), 1.0);
+    }
+
+    #[test]
+    fn tail_mass_is_what_the_top_tokens_miss() {
+        let dist = tenths();
+        let top: Vec<usize> = dist.top().map(|c| c.token).collect();
+        assert_eq!(top, [9, 8, 7, 6, 5, 4, 3, 2]);
+        assert!((dist.tail_mass() - 0.2).abs() < 1e-12);
+    }
+
+    #[test]
+    fn masking_drops_tokens_and_renormalizes() {
+        let dist = tenths().mask(|id| id != 9 && id != 5);
+
+        let top: Vec<usize> = dist.top().map(|c| c.token).collect();
+        assert_eq!(top, [8, 7, 6, 4, 3, 2]);
+        assert_eq!((dist.probability(9), dist.machine_prob(9)), (0.0, 0));
+        assert!((dist.probability(8) - 0.125).abs() < 1e-12);
+        assert_eq!(dist.rank(8), 0);
+        assert!(dist.rank(9) > dist.rank(0));
+    }
+
+    #[test]
+    fn masking_out_every_top_token_changes_nothing() {
+        let dist = tenths();
+        let masked = dist.mask(|id| id < 2);
+        assert_eq!(masked.packed(), dist.packed());
+        assert_eq!(masked.probabilities(), dist.probabilities());
+        assert_eq!(masked.machine_prob(9), dist.machine_prob(9));
+    }
+}
@@ -1,7 +1,9 @@
-use std::fs::File;
-use std::io::Read;
+use crate::error::LoadError;
+use crate::weights::{EMBEDDING_ROM_SIZE, WeightSource, u24_words};
+use crate::{EMBED_SIZE, FIXED_POINT_MASK, Fixed24};
 
-use crate::{Fixed24, EMBED_SIZE, FIXED_POINT_MASK};
+/// Each embedding ROM holds 32 rows of 240 24-bit values.
+const ROWS_PER_ROM: usize = 32;
 
 pub struct Embedding {
     wte: Vec<Vec<u32>>,
@@ -9,52 +11,22 @@ pub struct Embedding {
 }
 
 impl Embedding {
-    pub fn new() -> Self {
+    pub fn new(source: &dyn WeightSource) -> Result<Self, LoadError> {
         let mut wte = Vec::new();
 
         for i in 0..60 {
-            let path = format!("weights/weight_files/embedding/wte_{}.bin", i + 1);
-            let mut file = File::open(path).expect("couldn't read file");
-
-            for _ in 0..32 {
-                let mut embedding = Vec::with_capacity(EMBED_SIZE);
-                for _ in 0..EMBED_SIZE {
-                    let mut buf = [0u8; 3];
-                    file.read_exact(&mut buf)
-                        .expect("couldn't read into buffer");
-                    let mut cur = u32::from_le_bytes([buf[0], buf[1], buf[2], 0]);
-                    if cur >= (1 << 17) {
-                        cur |= ((1 << 18) * ((1 << 6) - 1)) & 0xFFFFFF;
-                    }
-                    embedding.push(cur);
-                }
-                wte.push(embedding);
-            }
+            let name = format!("embedding/wte_{}", i + 1);
+            read_rows(source, &name, &mut wte)?;
         }
 
         let mut wpe = Vec::new();
 
         for i in 0..2 {
-            let path = format!("weights/weight_files/embedding/wpe_{}.bin", i + 1);
-            let mut file = File::open(path).expect("couldn't read file");
-
-            for _ in 0..32 {
-                let mut embedding = Vec::with_capacity(EMBED_SIZE);
-                for _ in 0..EMBED_SIZE {
-                    let mut buf = [0u8; 3];
-                    file.read_exact(&mut buf)
-                        .expect("couldn't read into buffer");
-                    let mut cur = u32::from_le_bytes([buf[0], buf[1], buf[2], 0]);
-                    if cur >= (1 << 17) {
-                        cur |= ((1 << 18) * ((1 << 6) - 1)) & 0xFFFFFF;
-                    }
-                    embedding.push(cur);
-                }
-                wpe.push(embedding);
-            }
+            let name = format!("embedding/wpe_{}", i + 1);
+            read_rows(source, &name, &mut wpe)?;
         }
 
-        Embedding { wte, wpe }
+        Ok(Embedding { wte, wpe })
     }
 
     pub fn get_weights(&self, token: usize, pos: Option<usize>) -> Vec<Fixed24> {
@@ -62,11 +34,58 @@ impl Embedding {
 
         if let Some(pos) = pos {
             assert!(pos < 64);
-            for i in 0..EMBED_SIZE {
-                weights[i] = weights[i].wrapping_add(self.wpe[pos][i]) & FIXED_POINT_MASK;
+            for (w, &p) in weights.iter_mut().zip(&self.wpe[pos]) {
+                *w = w.wrapping_add(p) & FIXED_POINT_MASK;
             }
         }
 
         weights
     }
 }
+
+fn read_rows(
+    source: &dyn WeightSource,
+    name: &str,
+    rows: &mut Vec<Vec<u32>>,
+) -> Result<(), LoadError> {
+    let bytes = source.read(name, EMBEDDING_ROM_SIZE)?;
+    let mut words = u24_words(&bytes);
+
+    for _ in 0..ROWS_PER_ROM {
+        let mut embedding = Vec::with_capacity(EMBED_SIZE);
+        for _ in 0..EMBED_SIZE {
+            let mut cur = words.next().unwrap();
+            if cur >= (1 << 17) {
+                cur |= ((1 << 18) * ((1 << 6) - 1)) & 0xFFFFFF;
+            }
+            embedding.push(cur);
+        }
+        rows.push(embedding);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ModelPaths;
+    use crate::layout::{Matrix, matrix_rows};
+
+    #[test]
+    fn rom_rows_match_the_loader() {
+        let paths = ModelPaths::default();
+        let embedding = Embedding::new(&paths).unwrap();
+        for (matrix, loaded) in [(Matrix::Wte, &embedding.wte), (Matrix::Wpe, &embedding.wpe)] {
+            let rows = matrix_rows(matrix, &paths);
+            assert_eq!(rows.len(), matrix.rows());
+            for (row, (bytes, loaded)) in rows.iter().zip(loaded).enumerate() {
+                // The loader sign-extends the 18-bit values to 24 bits.
+                let words: Vec<u32> = u24_words(bytes)
+                    .map(|w| if w >= 1 << 17 { w | 0xfc0000 } else { w })
+                    .collect();
+                assert_eq!(&words, loaded, "{} row {}", matrix, row);
+            }
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::VOCAB_SIZE;
+
+/// Why a weight file could not be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The file could not be opened.
+    Missing { path: PathBuf, source: io::Error },
+    /// The file ended before all the expected bytes were read.
+    ShortRead {
+        path: PathBuf,
+        expected: usize,
+        actual: usize,
+    },
+    /// The file has trailing data after the expected bytes.
+    WrongSize {
+        path: PathBuf,
+        expected: usize,
+        actual: usize,
+    },
+    /// A lookup table has contents the circuits can't work with.
+    BadTable { path: PathBuf, reason: String },
+    /// A bundle file has a malformed or incompatible header.
+    BadBundle { path: PathBuf, reason: String },
+    /// A tensor in a bundle doesn't match its recorded CRC32.
+    Checksum {
+        path: PathBuf,
+        expected: u32,
+        actual: u32,
+    },
+    /// The vocabulary has more tokens than the model has outputs.
+    Vocabulary { path: PathBuf, tokens: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Missing { path, source } => {
+                write!(f, "couldn't open {}: {}", path.display(), source)
+            }
+            LoadError::ShortRead {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} is truncated: expected {} bytes, file ends at byte {}",
+                path.display(),
+                expected,
+                actual
+            ),
+            LoadError::WrongSize {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} has the wrong size: expected {} bytes, found {}",
+                path.display(),
+                expected,
+                actual
+            ),
+            LoadError::BadTable { path, reason } => {
+                write!(f, "{} is not a valid table: {}", path.display(), reason)
+            }
+            LoadError::BadBundle { path, reason } => {
+                write!(f, "{} is not a valid bundle: {}", path.display(), reason)
+            }
+            LoadError::Checksum {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} is corrupt: CRC32 is {:08x}, expected {:08x}",
+                path.display(),
+                actual,
+                expected
+            ),
+            LoadError::Vocabulary { path, tokens } => write!(
+                f,
+                "{} has {} tokens, but the model only has {}",
+                path.display(),
+                tokens,
+                VOCAB_SIZE
+            ),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Missing { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
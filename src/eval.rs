@@ -0,0 +1,143 @@
+//! Measuring how well the model predicts text it is given, rather than text
+//! it writes itself.
+
+use crate::{Distribution, Model};
+
+/// Scores of teacher-forced conversations, summed over every target: each
+/// token of a conversation after the first, predicted from the ones before
+/// it. Probabilities and ranks come from the full distribution, so they
+/// measure the model rather than the machine's rounding.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Evaluation {
+    pub conversations: usize,
+    pub targets: usize,
+    /// Sum of the targets' negative log-likelihoods, in nats.
+    pub nll: f64,
+    /// Targets whose probability came out as 0, which are left out of `nll`
+    /// rather than making it infinite.
+    pub impossible: usize,
+    /// Targets that were the most likely token.
+    pub top1: usize,
+    /// Targets among the 8 most likely tokens.
+    pub top8: usize,
+    /// Targets left out of the machine's top tokens, which its sampler could
+    /// never have picked.
+    pub outside: usize,
+}
+
+impl Evaluation {
+    /// Feeds `ids` through `model` from where it is, scoring the prediction
+    /// of each token after the first.
+    pub fn add(&mut self, model: &mut Model, ids: &[usize]) {
+        self.conversations += 1;
+        for pair in ids.windows(2) {
+            let dist = model.predict(pair[0]);
+            self.score(&dist, pair[1]);
+        }
+    }
+
+    fn score(&mut self, dist: &Distribution, target: usize) {
+        let rank = dist.rank(target);
+        let prob = dist.probability(target);
+        self.targets += 1;
+        if prob > 0.0 {
+            self.nll -= prob.ln();
+        } else {
+            self.impossible += 1;
+        }
+        self.top1 += (rank == 0) as usize;
+        self.top8 += (rank < 8) as usize;
+        self.outside += dist.top().all(|c| c.token != target) as usize;
+    }
+
+    /// Mean negative log-likelihood per target, in nats, over the targets
+    /// that got any probability; `None` if none did.
+    pub fn mean_nll(&self) -> Option<f64> {
+        let scored = self.targets - self.impossible;
+        (scored > 0).then(|| self.nll / scored as f64)
+    }
+
+    pub fn perplexity(&self) -> Option<f64> {
+        self.mean_nll().map(f64::exp)
+    }
+
+    /// `count` as a fraction of the targets.
+    pub fn fraction(&self, count: usize) -> f64 {
+        count as f64 / self.targets as f64
+    }
+}
+
+/// Scores each conversation, starting each one from where `model` is now.
+/// The model is left where the last conversation took it.
+pub fn evaluate<'a, I>(model: &mut Model, conversations: I) -> Evaluation
+where
+    I: IntoIterator<Item = &'a [usize]>,
+{
+    let start = model.snapshot();
+    let mut evaluation = Evaluation::default();
+    for ids in conversations {
+        model.restore(&start);
+        evaluation.add(model, ids);
+    }
+    evaluation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VOCAB_SIZE;
+
+    /// A distribution where tokens 0, 1, 2, ... are ever less likely.
+    fn distribution() -> Distribution {
+        let logits = std::array::from_fn(|i| (VOCAB_SIZE - i) as u32 * 64);
+        let machine = (0..VOCAB_SIZE as u32)
+            .map(|i| (1 << 20) >> i.min(31))
+            .collect();
+        Distribution::new(logits, machine)
+    }
+
+    #[test]
+    fn counts_ranks() {
+        let dist = distribution();
+        let mut evaluation = Evaluation::default();
+        for target in [0, 5, 7, 8, 100] {
+            evaluation.score(&dist, target);
+        }
+        assert_eq!(evaluation.targets, 5);
+        assert_eq!(evaluation.top1, 1);
+        assert_eq!(evaluation.top8, 3);
+        assert_eq!(evaluation.outside, 2);
+        assert_eq!(evaluation.impossible, 0);
+        assert!(evaluation.mean_nll().unwrap().is_finite());
+        assert!(evaluation.perplexity().unwrap() > 1.0);
+    }
+
+    #[test]
+    fn impossible_targets_are_left_out_of_the_nll() {
+        let dist = distribution();
+        let mut evaluation = Evaluation::default();
+        evaluation.score(&dist, 0);
+        let nll = evaluation.mean_nll();
+
+        evaluation.score(&dist.mask(|id| id != 3), 3);
+        assert_eq!(evaluation.targets, 2);
+        assert_eq!(evaluation.impossible, 1);
+        assert_eq!(evaluation.mean_nll(), nll);
+    }
+
+    #[test]
+    fn no_nll_without_a_possible_target() {
+        let mut evaluation = Evaluation::default();
+        assert_eq!(
+            (evaluation.mean_nll(), evaluation.perplexity()),
+            (None, None)
+        );
+
+        evaluation.score(&distribution().mask(|id| id != 3), 3);
+        assert_eq!(evaluation.impossible, 1);
+        assert_eq!(
+            (evaluation.mean_nll(), evaluation.perplexity()),
+            (None, None)
+        );
+    }
+}
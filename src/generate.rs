@@ -0,0 +1,319 @@
+//! Answering prompts: feeding a user turn to the model and sampling until it
+//! picks an instruction marker or runs out of tokens.
+
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::distribution::Candidate;
+use crate::sampler::{Pick, Sampler};
+use crate::tokenizer::TokenizeError;
+use crate::{ChatTemplate, Distribution, Model, PRNG, Tokenizer};
+
+/// One token picked by [`Generator::generate`].
+#[derive(Clone, Debug)]
+pub struct Step {
+    pub token: usize,
+    /// The machine's top tokens it was picked from.
+    pub top: Vec<Candidate>,
+    /// The PRNG value the sampler drew, if it drew one.
+    pub drawn: Option<u32>,
+    /// Natural log of the token's probability in the full distribution.
+    pub log_prob: f64,
+    /// Time since the previous step, or since generation started.
+    pub elapsed: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stop {
+    /// The model picked an instruction marker.
+    EndToken,
+    MaxTokens,
+}
+
+/// An answer generated by [`Generator::generate`]. If it ended with a marker
+/// token, that is the last step.
+#[derive(Clone, Debug)]
+pub struct Generation {
+    pub steps: Vec<Step>,
+    pub stop: Stop,
+}
+
+impl Generation {
+    /// The answer, without the marker that ended it.
+    pub fn tokens(&self) -> Vec<usize> {
+        let answer = match self.stop {
+            Stop::EndToken => &self.steps[..self.steps.len() - 1],
+            Stop::MaxTokens => &self.steps[..],
+        };
+        answer.iter().map(|step| step.token).collect()
+    }
+
+    pub fn mean_log_prob(&self) -> f64 {
+        self.steps.iter().map(|step| step.log_prob).sum::<f64>() / self.steps.len() as f64
+    }
+}
+
+/// Everything about answering a prompt that stays the same from one answer
+/// to the next.
+#[derive(Clone, Copy)]
+pub struct Generator<'a> {
+    pub tokenizer: &'a Tokenizer,
+    pub template: ChatTemplate,
+    pub max_tokens: usize,
+    /// Never pick the padding IDs past the end of the vocabulary.
+    pub mask_unused: bool,
+}
+
+impl Generator<'_> {
+    /// Feeds `text` to the model as a user turn. Returns the tokens it was fed
+    /// and the prediction after the last one.
+    pub fn prompt(
+        &self,
+        model: &mut Model,
+        text: &str,
+    ) -> Result<(Vec<usize>, Distribution), TokenizeError> {
+        let ids = self.template.user_turn(self.tokenizer, text)?;
+        let mut dist = None;
+        for &token in &ids {
+            dist = Some(model.predict(token));
+        }
+        Ok((ids, dist.unwrap()))
+    }
+
+    /// Samples an answer starting from `dist`, the prediction after the last
+    /// token of the prompt, until the model ends it or it reaches the token
+    /// limit. Each token of the answer is passed to `each` as soon as it is
+    /// picked.
+    pub fn generate(
+        &self,
+        model: &mut Model,
+        mut dist: Distribution,
+        sampler: &mut dyn Sampler,
+        rng: &mut PRNG,
+        each: &mut dyn FnMut(usize),
+    ) -> Generation {
+        let mut steps = Vec::new();
+        let mut start = Instant::now();
+        while steps.len() < self.max_tokens {
+            if self.mask_unused {
+                dist = dist.mask(|id| !self.tokenizer.is_unused(id));
+            }
+            let Pick { token, drawn } = sampler.sample(&dist, rng);
+            let mut step = Step {
+                token,
+                top: dist.top().collect(),
+                drawn,
+                log_prob: dist.probability(token).ln(),
+                elapsed: Duration::ZERO,
+            };
+            if self.template.is_marker(token) {
+                step.elapsed = start.elapsed();
+                steps.push(step);
+                return Generation {
+                    steps,
+                    stop: Stop::EndToken,
+                };
+            }
+            each(token);
+            dist = model.predict(token);
+            step.elapsed = start.elapsed();
+            start = Instant::now();
+            steps.push(step);
+        }
+        Generation {
+            steps,
+            stop: Stop::MaxTokens,
+        }
+    }
+
+    /// Answers the prompt `model` has just processed, whose prediction is
+    /// `first`, once with each seed, each time from where the model is now.
+    /// The answers come back best first, by mean log-probability.
+    pub fn sweep<I: IntoIterator<Item = u32>>(
+        &self,
+        model: &mut Model,
+        first: &Distribution,
+        seeds: I,
+        sampler: &mut dyn Sampler,
+    ) -> Vec<(u32, Generation)> {
+        let state = model.snapshot();
+        let mut results = Vec::new();
+        for seed in seeds {
+            model.restore(&state);
+            let mut rng = PRNG::new(seed);
+            let generation = self.generate(model, first.clone(), sampler, &mut rng, &mut |_| {});
+            results.push((seed, generation));
+        }
+
+        results.sort_by(|(_, a), (_, b)| b.mean_log_prob().total_cmp(&a.mean_log_prob()));
+        results
+    }
+}
+
+/// A record of `generate --format jsonl`, one per line.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// A prompt was processed, with the instruction markers around it.
+    Prompt {
+        line: usize,
+        text: &'a str,
+        tokens: Vec<TokenRecord<'a>>,
+        millis: f64,
+    },
+    /// A token of the answer was picked; the last one of an answer the
+    /// model ended is the marker that ended it.
+    Token {
+        line: usize,
+        index: usize,
+        id: usize,
+        text: &'a str,
+        candidates: Vec<CandidateRecord<'a>>,
+        prng: Option<u32>,
+        /// Where the token is among the candidates, if it is one of them.
+        chosen_index: Option<usize>,
+        log_prob: f64,
+        millis: f64,
+    },
+    End {
+        line: usize,
+        stop_reason: Stop,
+        answer: &'a str,
+        tokens: usize,
+        /// Time for the whole prompt and answer.
+        millis: f64,
+    },
+    /// The most likely next tokens after a prompt, for `--mode top8`.
+    Top8 {
+        line: usize,
+        candidates: Vec<CandidateRecord<'a>>,
+    },
+    Error {
+        line: usize,
+        message: String,
+    },
+}
+
+impl<'a> Event<'a> {
+    /// The event for step `index` of the answer to prompt `line`.
+    pub fn token(tokenizer: &'a Tokenizer, line: usize, index: usize, step: &Step) -> Self {
+        Event::Token {
+            line,
+            index,
+            id: step.token,
+            text: tokenizer.token(step.token),
+            candidates: CandidateRecord::list(tokenizer, step.top.iter().copied()),
+            prng: step.drawn,
+            chosen_index: step.top.iter().position(|c| c.token == step.token),
+            log_prob: step.log_prob,
+            millis: millis(step.elapsed),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TokenRecord<'a> {
+    pub id: usize,
+    pub text: &'a str,
+}
+
+impl<'a> TokenRecord<'a> {
+    pub fn new(tokenizer: &'a Tokenizer, id: usize) -> Self {
+        TokenRecord {
+            id,
+            text: tokenizer.token(id),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CandidateRecord<'a> {
+    pub id: usize,
+    pub text: &'a str,
+    pub probability: f64,
+}
+
+impl<'a> CandidateRecord<'a> {
+    pub fn list<I: IntoIterator<Item = Candidate>>(tokenizer: &'a Tokenizer, top: I) -> Vec<Self> {
+        top.into_iter()
+            .map(|c| CandidateRecord {
+                id: c.token,
+                text: tokenizer.token(c.token),
+                probability: c.probability(),
+            })
+            .collect()
+    }
+}
+
+/// A duration in milliseconds, as the events give them.
+pub fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn events_serialize_with_their_kind() {
+        let tokenizer = Tokenizer::new(vec!["_[inst]".into(), "_[/inst]".into(), "_hi".into()]);
+        let step = Step {
+            token: 2,
+            top: vec![
+                Candidate {
+                    token: 1,
+                    prob: 1 << 22,
+                },
+                Candidate {
+                    token: 2,
+                    prob: 1 << 21,
+                },
+            ],
+            drawn: Some(5),
+            log_prob: -1.5,
+            elapsed: Duration::from_micros(2500),
+        };
+
+        let event = serde_json::to_value(Event::token(&tokenizer, 3, 0, &step)).unwrap();
+        assert_eq!(
+            event,
+            json!({
+                "event": "token",
+                "line": 3,
+                "index": 0,
+                "id": 2,
+                "text": "_hi",
+                "candidates": [
+                    {"id": 1, "text": "_[/inst]", "probability": 0.5},
+                    {"id": 2, "text": "_hi", "probability": 0.25},
+                ],
+                "prng": 5,
+                "chosen_index": 1,
+                "log_prob": -1.5,
+                "millis": 2.5,
+            })
+        );
+
+        let end = Event::End {
+            line: 3,
+            stop_reason: Stop::EndToken,
+            answer: "hi",
+            tokens: 1,
+            millis: 10.0,
+        };
+        assert_eq!(
+            serde_json::to_value(end).unwrap(),
+            json!({
+                "event": "end",
+                "line": 3,
+                "stop_reason": "end_token",
+                "answer": "hi",
+                "tokens": 1,
+                "millis": 10.0,
+            })
+        );
+    }
+}
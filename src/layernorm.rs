@@ -1,7 +1,7 @@
+use crate::error::LoadError;
 use crate::matmul::MATMUL_FIXED_POINT;
+use crate::weights::{LAYERNORM_ROM_SIZE, WeightSource, u24_words};
 use crate::{EMBED_SIZE, FIXED_POINT_MASK, FIXED_POINT_SIZE, Fixed24};
-use std::fs::File;
-use std::io::Read;
 
 const LAYERNORM_CONST: u64 = (1u64 << 32) / (EMBED_SIZE as u64);
 const LAYERNORM_CONST_2: u64 = 8663717; // int((1 << 27) / sqrt(EMBED_SIZE))
@@ -13,22 +13,18 @@ pub struct LayerNorm {
 }
 
 impl LayerNorm {
-    pub fn new(index: usize) -> Self {
-        let path = format!("weights/weight_files/layernorm/ln_{}.bin", index);
-        let mut file = File::open(path).expect("couldn't read file");
+    pub fn new(source: &dyn WeightSource, index: usize) -> Result<Self, LoadError> {
+        let name = format!("layernorm/ln_{}", index);
+        let bytes = source.read(&name, LAYERNORM_ROM_SIZE)?;
         let mut weights = [0u32; EMBED_SIZE];
 
-        for i in 0..EMBED_SIZE {
-            let mut buf = [0u8; 3];
-            file.read_exact(&mut buf)
-                .expect("couldn't read into buffer");
-            let cur = u32::from_le_bytes([buf[0], buf[1], buf[2], 0]);
+        for (i, cur) in u24_words(&bytes).enumerate() {
             weights[i] = cur / 2;
         }
 
-        LayerNorm {
+        Ok(LayerNorm {
             weights: Box::new(weights),
-        }
+        })
     }
 
     pub fn forward(&self, input: &[Fixed24; EMBED_SIZE]) -> [Fixed24; EMBED_SIZE] {
@@ -47,7 +43,7 @@ impl LayerNorm {
         if neg {
             mean = mean.wrapping_neg() & ((1 << (FIXED_POINT_SIZE + 7)) - 1);
         }
-        mean = ((mean as u64 * LAYERNORM_CONST as u64) >> 32) as u32;
+        mean = ((mean as u64 * LAYERNORM_CONST) >> 32) as u32;
         if neg {
             mean = mean.wrapping_neg() & FIXED_POINT_MASK;
         }
@@ -69,8 +65,7 @@ impl LayerNorm {
         }
 
         let sigma2_sqrt = (sigma2 as f64).sqrt() as u64;
-        let mut sigma2_final =
-            ((LAYERNORM_CONST_2 as u64 * sigma2_sqrt) >> 27) & FIXED_POINT_MASK as u64;
+        let mut sigma2_final = ((LAYERNORM_CONST_2 * sigma2_sqrt) >> 27) & FIXED_POINT_MASK as u64;
         sigma2_final =
             ((1u64 << (2 * MATMUL_FIXED_POINT)) / sigma2_final) & FIXED_POINT_MASK as u64;
         let sigma2_final = sigma2_final as u32;
@@ -102,3 +97,21 @@ impl LayerNorm {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ModelPaths;
+    use crate::layout::{Matrix, matrix_rows};
+
+    #[test]
+    fn rom_rows_match_the_loader() {
+        let paths = ModelPaths::default();
+        for n in 1..=13 {
+            let layernorm = LayerNorm::new(&paths, n).unwrap();
+            let rows = matrix_rows(Matrix::Layernorm(n), &paths);
+            let words: Vec<u32> = u24_words(&rows[0]).map(|w| w / 2).collect();
+            assert_eq!(words[..], layernorm.weights[..], "ln.{}", n);
+        }
+    }
+}
@@ -0,0 +1,716 @@
+//! Where each ROM sits in the world, as recorded in `weights/layout.json`.
+//!
+//! Each entry gives a ROM's origin, its storage format and whether it is the
+//! mirrored half of a pair. Matrix ROMs come in pairs sharing an origin: the
+//! unflipped one extends towards +z and its flipped twin towards -z.
+//!
+//! Within a ROM, every bit is one block, [`ONE_BLOCK`] for a set bit and
+//! [`ZERO_BLOCK`] for a clear one. Words are vertical columns of bits, least
+//! significant at the bottom:
+//!
+//! - format 0 (layernorm): 240 words of 24 bits, word `i` at `dx = i`.
+//! - format 1 (matrix): 40 rows of 240 bytes. Rows 0-19 and 20-39 are stacked
+//!   16 blocks apart, and each row is split into two 120-byte halves stacked 8
+//!   blocks apart, so byte `c` of row `r` is at `dx = c % 120`,
+//!   `dy = 8 * (c / 120) + 16 * (r / 20)` and `dz = r % 20`.
+//! - format 2 (embedding): 32 rows of 240 words of 24 bits, word `c` of row
+//!   `r` at `dx = r` and `dz = -c`.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::attention::{HEAD_SIZE, HEADS};
+use crate::error::LoadError;
+use crate::mlp::HIDDEN_SIZE;
+use crate::model::{CONTEXT_SIZE, LAYERS};
+use crate::weights::{EMBEDDING_ROM_SIZE, LAYERNORM_ROM_SIZE, MATRIX_ROM_SIZE, WeightSource};
+use crate::{EMBED_SIZE, VOCAB_SIZE};
+
+/// Block holding a set bit.
+pub const ONE_BLOCK: &str = "minecraft:redstone_block";
+/// Block holding a clear bit.
+pub const ZERO_BLOCK: &str = "minecraft:glass";
+
+/// A block position in the world.
+pub type Pos = (i32, i32, i32);
+
+/// How a ROM stores its bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "u8")]
+pub enum RomFormat {
+    /// 240 24-bit words.
+    Layernorm,
+    /// 40 rows of 240 bytes.
+    Matrix,
+    /// 32 rows of 240 24-bit words.
+    Embedding,
+}
+
+impl TryFrom<u8> for RomFormat {
+    type Error = String;
+
+    fn try_from(format: u8) -> Result<Self, String> {
+        match format {
+            0 => Ok(RomFormat::Layernorm),
+            1 => Ok(RomFormat::Matrix),
+            2 => Ok(RomFormat::Embedding),
+            _ => Err(format!("unknown ROM format {}", format)),
+        }
+    }
+}
+
+impl RomFormat {
+    /// Size of the ROM's weight file in bytes.
+    pub fn size(self) -> usize {
+        match self {
+            RomFormat::Layernorm => LAYERNORM_ROM_SIZE,
+            RomFormat::Matrix => MATRIX_ROM_SIZE,
+            RomFormat::Embedding => EMBEDDING_ROM_SIZE,
+        }
+    }
+
+    /// Bits per stored word.
+    pub fn word_bits(self) -> usize {
+        match self {
+            RomFormat::Matrix => 8,
+            RomFormat::Layernorm | RomFormat::Embedding => 24,
+        }
+    }
+
+    /// Number of words in the ROM.
+    pub fn words(self) -> usize {
+        8 * self.size() / self.word_bits()
+    }
+
+    /// Offset from the ROM's origin of bit `bit` of word `word`, for an
+    /// unflipped ROM.
+    pub fn offset(self, word: usize, bit: usize) -> Pos {
+        let bit = bit as i32;
+        match self {
+            RomFormat::Layernorm => (word as i32, bit, 0),
+            RomFormat::Matrix => {
+                let (r, c) = (word / EMBED_SIZE, word % EMBED_SIZE);
+                let dx = (c % 120) as i32;
+                let dy = bit + 8 * (c / 120) as i32 + 16 * (r / 20) as i32;
+                let dz = (r % 20) as i32;
+                (dx, dy, dz)
+            }
+            RomFormat::Embedding => {
+                let (r, c) = (word / EMBED_SIZE, word % EMBED_SIZE);
+                (r as i32, bit, -(c as i32))
+            }
+        }
+    }
+}
+
+/// One ROM's entry in `layout.json`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RomPlacement {
+    /// The ROM's name, which is also its weight file without `.bin`.
+    pub name: String,
+    pub format: RomFormat,
+    /// Whether the ROM is mirrored in z about its origin.
+    pub flipped: bool,
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+    pub index: usize,
+}
+
+impl RomPlacement {
+    /// World coordinates of bit `bit` of word `word`.
+    pub fn position(&self, word: usize, bit: usize) -> Pos {
+        let (dx, dy, dz) = self.format.offset(word, bit);
+        let dz = if self.flipped { -1 - dz } else { dz };
+        (self.x + dx, self.y + dy, self.z + dz)
+    }
+
+    /// Smallest box, as inclusive min and max corners, containing words
+    /// `words` of this ROM.
+    pub fn bounds(&self, words: std::ops::Range<usize>) -> (Pos, Pos) {
+        let mut min = (i32::MAX, i32::MAX, i32::MAX);
+        let mut max = (i32::MIN, i32::MIN, i32::MIN);
+        for word in words {
+            for bit in [0, self.format.word_bits() - 1] {
+                let (x, y, z) = self.position(word, bit);
+                min = (min.0.min(x), min.1.min(y), min.2.min(z));
+                max = (max.0.max(x), max.1.max(y), max.2.max(z));
+            }
+        }
+        (min, max)
+    }
+
+    /// Calls `set` with the position and value of every bit of `bytes`, the
+    /// contents of this ROM's weight file.
+    pub fn for_each_bit<F: FnMut(Pos, bool)>(&self, bytes: &[u8], mut set: F) {
+        let word_bytes = self.format.word_bits() / 8;
+        for word in 0..self.format.words() {
+            for bit in 0..self.format.word_bits() {
+                let byte = bytes[word * word_bytes + bit / 8];
+                set(self.position(word, bit), (byte >> (bit % 8)) & 1 != 0);
+            }
+        }
+    }
+
+    /// Reads this ROM's contents back out of the world, given the value of
+    /// the bit at each position. Fails with the first position that doesn't
+    /// hold a bit.
+    pub fn decode<F: FnMut(Pos) -> Option<bool>>(&self, mut bit_at: F) -> Result<Vec<u8>, Pos> {
+        let word_bytes = self.format.word_bits() / 8;
+        let mut bytes = vec![0u8; self.format.size()];
+        for word in 0..self.format.words() {
+            for bit in 0..self.format.word_bits() {
+                let pos = self.position(word, bit);
+                if bit_at(pos).ok_or(pos)? {
+                    bytes[word * word_bytes + bit / 8] |= 1 << (bit % 8);
+                }
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Compares `actual`, read back from the world, against `expected`, the
+    /// ROM's weight file, word by word.
+    pub fn compare(&self, expected: &[u8], actual: &[u8]) -> Vec<Mismatch> {
+        let word_bytes = self.format.word_bits() / 8;
+        let word = |bytes: &[u8], i: usize| {
+            bytes[i * word_bytes..(i + 1) * word_bytes]
+                .iter()
+                .rev()
+                .fold(0u32, |acc, &b| (acc << 8) | b as u32)
+        };
+
+        (0..self.format.words())
+            .filter(|&i| word(expected, i) != word(actual, i))
+            .map(|i| Mismatch {
+                row: i / EMBED_SIZE,
+                column: i % EMBED_SIZE,
+                expected: word(expected, i),
+                actual: word(actual, i),
+            })
+            .collect()
+    }
+}
+
+/// A ROM read back out of a world by [`Layout::decode`].
+#[derive(Clone, Debug)]
+pub struct DecodedRom<'a> {
+    pub rom: &'a RomPlacement,
+    /// The ROM's contents, or the first position that doesn't hold a bit.
+    pub contents: Result<Vec<u8>, Pos>,
+    /// The words that differ from the weight file.
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl DecodedRom<'_> {
+    /// Whether the ROM could be read and matches its weight file.
+    pub fn matches(&self) -> bool {
+        self.contents.is_ok() && self.mismatches.is_empty()
+    }
+}
+
+/// A word of a ROM whose contents in the world differ from its weight file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Row of the ROM, in rows of 240 words.
+    pub row: usize,
+    pub column: usize,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+/// A weight matrix of the model, as the loaders assemble it from ROMs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Matrix {
+    /// Token embeddings, one row per token.
+    Wte,
+    /// Position embeddings, one row per position.
+    Wpe,
+    /// Layernorm `n` as the weight files number them: `2b + 1` and `2b + 2`
+    /// are the two in block `b`, and 13 is the final one. Has a single row.
+    Layernorm(usize),
+    Query {
+        block: usize,
+        head: usize,
+    },
+    Key {
+        block: usize,
+        head: usize,
+    },
+    Value {
+        block: usize,
+        head: usize,
+    },
+    AttentionProj {
+        block: usize,
+    },
+    MlpUp {
+        block: usize,
+    },
+    MlpDown {
+        block: usize,
+    },
+    LmHead,
+}
+
+impl Matrix {
+    /// Number of rows in the matrix.
+    pub fn rows(self) -> usize {
+        match self {
+            Matrix::Wte => VOCAB_SIZE,
+            Matrix::Wpe => CONTEXT_SIZE,
+            Matrix::Layernorm(_) => 1,
+            Matrix::Query { .. } | Matrix::Key { .. } | Matrix::Value { .. } => HEAD_SIZE,
+            Matrix::AttentionProj { .. } | Matrix::MlpDown { .. } => EMBED_SIZE,
+            Matrix::MlpUp { .. } => HIDDEN_SIZE,
+            Matrix::LmHead => VOCAB_SIZE,
+        }
+    }
+
+    /// Which ROM holds `row`, and where in the ROM's file it starts and how
+    /// many bytes it takes. This inverts the interleaving the loaders undo.
+    pub fn rom_row(self, row: usize) -> Option<RomRow> {
+        let exists = match self {
+            Matrix::Layernorm(n) => (1..=2 * LAYERS + 1).contains(&n),
+            Matrix::Query { block, head }
+            | Matrix::Key { block, head }
+            | Matrix::Value { block, head } => block < LAYERS && head < HEADS,
+            Matrix::AttentionProj { block }
+            | Matrix::MlpUp { block }
+            | Matrix::MlpDown { block } => block < LAYERS,
+            Matrix::Wte | Matrix::Wpe | Matrix::LmHead => true,
+        };
+        if !exists || row >= self.rows() {
+            return None;
+        }
+
+        // Matrix ROMs hold two rows per output pair; odd ROMs swap them.
+        let slot = |i: usize, parity: usize, first: usize, second: usize| {
+            if (parity == 0) == i.is_multiple_of(2) {
+                first
+            } else {
+                second
+            }
+        };
+
+        let (name, offset, len) = match self {
+            Matrix::Wte | Matrix::Wpe => {
+                let prefix = if self == Matrix::Wte { "wte" } else { "wpe" };
+                let name = format!("embedding/{}_{}", prefix, row / 32 + 1);
+                (name, (row % 32) * EMBED_SIZE * 3, EMBED_SIZE * 3)
+            }
+            Matrix::Layernorm(n) => (format!("layernorm/ln_{}", n), 0, EMBED_SIZE * 3),
+            Matrix::Query { block, head }
+            | Matrix::Key { block, head }
+            | Matrix::Value { block, head } => {
+                let kind = match self {
+                    Matrix::Key { .. } => 0,
+                    Matrix::Value { .. } => 1,
+                    _ => 2,
+                };
+                let i = row / 2;
+                let n = slot(i, row % 2, 3 * head + kind, 3 * head + kind + 20);
+                let name = format!("attention/att_{}", 1 + 24 * block + i);
+                (name, n * EMBED_SIZE, EMBED_SIZE)
+            }
+            Matrix::AttentionProj { block } => {
+                let (j, k) = (row / HEAD_SIZE, row % HEAD_SIZE);
+                let i = k / 2;
+                let n = slot(i, k % 2, j + 15, j + 35);
+                let name = format!("attention/att_{}", 1 + 24 * block + i);
+                (name, n * EMBED_SIZE, EMBED_SIZE)
+            }
+            Matrix::MlpUp { block } => {
+                let (j, k) = (row / 48, row % 48);
+                let i = k / 2;
+                let n = slot(i, k % 2, j, j + 20);
+                let name = format!("mlp/mlp_{}", 1 + 48 * block + i);
+                (name, n * EMBED_SIZE, EMBED_SIZE)
+            }
+            Matrix::MlpDown { block } => {
+                let (j, k) = (row / 48, row % 48);
+                let i = k / 2;
+                let n = slot(i, k % 2, j, j + 5);
+                let name = format!("mlp/mlp_{}", 25 + 48 * block + i);
+                (name, n * HIDDEN_SIZE, HIDDEN_SIZE)
+            }
+            Matrix::LmHead => {
+                let (half, w) = (row / 960, row % 960);
+                let (j, k) = (w / 48, w % 48);
+                let i = k / 2 + 24 * half;
+                let n = slot(i, k % 2, j, j + 20);
+                let name = format!("unembedding/lm_head_{}", i + 1);
+                (name, n * EMBED_SIZE, EMBED_SIZE)
+            }
+        };
+
+        Some(RomRow { name, offset, len })
+    }
+}
+
+impl fmt::Display for Matrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Matrix::Wte => write!(f, "wte"),
+            Matrix::Wpe => write!(f, "wpe"),
+            Matrix::Layernorm(n) => write!(f, "ln.{}", n),
+            Matrix::Query { block, head } => write!(f, "block{}.head{}.query", block, head),
+            Matrix::Key { block, head } => write!(f, "block{}.head{}.key", block, head),
+            Matrix::Value { block, head } => write!(f, "block{}.head{}.value", block, head),
+            Matrix::AttentionProj { block } => write!(f, "block{}.proj", block),
+            Matrix::MlpUp { block } => write!(f, "block{}.mlp_up", block),
+            Matrix::MlpDown { block } => write!(f, "block{}.mlp_down", block),
+            Matrix::LmHead => write!(f, "lm_head"),
+        }
+    }
+}
+
+impl FromStr for Matrix {
+    type Err = String;
+
+    /// Parses the names [`Matrix`]'s `Display` produces, e.g. `wte`,
+    /// `ln.13`, `block3.mlp_up` or `block0.head2.query`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("unknown matrix '{}'", s);
+        let number = |part: &str, prefix: &str| {
+            part.strip_prefix(prefix)
+                .and_then(|n| n.parse::<usize>().ok())
+                .ok_or_else(bad)
+        };
+
+        let parts: Vec<&str> = s.split('.').collect();
+        match parts.as_slice() {
+            ["wte"] => Ok(Matrix::Wte),
+            ["wpe"] => Ok(Matrix::Wpe),
+            ["lm_head"] => Ok(Matrix::LmHead),
+            ["ln", n] => Ok(Matrix::Layernorm(n.parse().map_err(|_| bad())?)),
+            [b, kind] => {
+                let block = number(b, "block")?;
+                match *kind {
+                    "proj" => Ok(Matrix::AttentionProj { block }),
+                    "mlp_up" => Ok(Matrix::MlpUp { block }),
+                    "mlp_down" => Ok(Matrix::MlpDown { block }),
+                    _ => Err(bad()),
+                }
+            }
+            [b, h, kind] => {
+                let block = number(b, "block")?;
+                let head = number(h, "head")?;
+                match *kind {
+                    "query" => Ok(Matrix::Query { block, head }),
+                    "key" => Ok(Matrix::Key { block, head }),
+                    "value" => Ok(Matrix::Value { block, head }),
+                    _ => Err(bad()),
+                }
+            }
+            _ => Err(bad()),
+        }
+    }
+}
+
+/// Every row of `matrix` as [`Matrix::rom_row`] finds it in `source`, for
+/// checking the loaders against it.
+#[cfg(test)]
+pub(crate) fn matrix_rows(matrix: Matrix, source: &dyn WeightSource) -> Vec<Vec<u8>> {
+    let sizes: HashMap<String, usize> = crate::weights::model_roms().into_iter().collect();
+    let mut roms = HashMap::new();
+    (0..matrix.rows())
+        .map(|row| {
+            let rom = matrix.rom_row(row).unwrap();
+            let bytes = roms.entry(rom.name.clone()).or_insert_with(|| {
+                source
+                    .read(&rom.name, sizes[&rom.name])
+                    .unwrap()
+                    .into_owned()
+            });
+            bytes[rom.offset..rom.offset + rom.len].to_vec()
+        })
+        .collect()
+}
+
+/// Where one row of a [`Matrix`] is stored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RomRow {
+    /// The ROM's name.
+    pub name: String,
+    /// Byte offset of the row within the ROM's weight file.
+    pub offset: usize,
+    /// Length of the row in bytes.
+    pub len: usize,
+}
+
+/// A row of a matrix located in the world.
+#[derive(Clone, Debug)]
+pub struct RowLocation<'a> {
+    pub rom: &'a RomPlacement,
+    pub row: RomRow,
+    /// Inclusive corners of the box of blocks holding the row.
+    pub min: Pos,
+    pub max: Pos,
+}
+
+/// Why `layout.json` could not be read.
+#[derive(Debug)]
+pub enum LayoutError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    Duplicate {
+        name: String,
+    },
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutError::Io { path, source } => {
+                write!(f, "couldn't read {}: {}", path.display(), source)
+            }
+            LayoutError::Parse { path, source } => {
+                write!(f, "couldn't parse {}: {}", path.display(), source)
+            }
+            LayoutError::Duplicate { name } => write!(f, "ROM {} is placed twice", name),
+        }
+    }
+}
+
+impl Error for LayoutError {}
+
+/// Every ROM placement in the world.
+#[derive(Clone, Debug)]
+pub struct Layout {
+    roms: Vec<RomPlacement>,
+    by_name: HashMap<String, usize>,
+}
+
+impl Layout {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LayoutError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|source| LayoutError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let roms = serde_json::from_str(&text).map_err(|source| LayoutError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Layout::new(roms)
+    }
+
+    pub fn new(roms: Vec<RomPlacement>) -> Result<Self, LayoutError> {
+        let mut by_name = HashMap::new();
+        for (i, rom) in roms.iter().enumerate() {
+            if by_name.insert(rom.name.clone(), i).is_some() {
+                return Err(LayoutError::Duplicate {
+                    name: rom.name.clone(),
+                });
+            }
+        }
+        Ok(Layout { roms, by_name })
+    }
+
+    pub fn roms(&self) -> &[RomPlacement] {
+        &self.roms
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RomPlacement> {
+        self.by_name.get(name).map(|&i| &self.roms[i])
+    }
+
+    /// Checks that every placed ROM has a weight file of the size its format
+    /// implies, returning every problem found.
+    pub fn validate(&self, source: &dyn WeightSource) -> Vec<LoadError> {
+        self.roms
+            .iter()
+            .filter_map(|rom| source.read(&rom.name, rom.format.size()).err())
+            .collect()
+    }
+
+    /// Reads every ROM `wanted` accepts back out of a world, given the value
+    /// of the bit at each position, and compares it with its weight file in
+    /// `source`.
+    pub fn decode<W, F>(
+        &self,
+        source: &dyn WeightSource,
+        wanted: W,
+        mut bit_at: F,
+    ) -> Result<Vec<DecodedRom<'_>>, LoadError>
+    where
+        W: Fn(&RomPlacement) -> bool,
+        F: FnMut(Pos) -> Option<bool>,
+    {
+        let mut decoded = Vec::new();
+        for rom in self.roms.iter().filter(|rom| wanted(rom)) {
+            let expected = source.read(&rom.name, rom.format.size())?;
+            let contents = rom.decode(&mut bit_at);
+            let mismatches = match &contents {
+                Ok(actual) => rom.compare(&expected, actual),
+                Err(_) => Vec::new(),
+            };
+            decoded.push(DecodedRom {
+                rom,
+                contents,
+                mismatches,
+            });
+        }
+        Ok(decoded)
+    }
+
+    /// Finds the ROM and the blocks holding `row` of `matrix`.
+    pub fn locate(&self, matrix: Matrix, row: usize) -> Option<RowLocation<'_>> {
+        let rom_row = matrix.rom_row(row)?;
+        let rom = self.get(&rom_row.name)?;
+        let word_bytes = rom.format.word_bits() / 8;
+        let words = rom_row.offset / word_bytes..(rom_row.offset + rom_row.len) / word_bytes;
+        let (min, max) = rom.bounds(words);
+        Some(RowLocation {
+            rom,
+            row: rom_row,
+            min,
+            max,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ModelPaths;
+
+    fn all_matrices() -> Vec<Matrix> {
+        let mut matrices = vec![Matrix::Wte, Matrix::Wpe, Matrix::LmHead];
+        matrices.extend((1..=2 * LAYERS + 1).map(Matrix::Layernorm));
+        for block in 0..LAYERS {
+            matrices.push(Matrix::AttentionProj { block });
+            matrices.push(Matrix::MlpUp { block });
+            matrices.push(Matrix::MlpDown { block });
+            for head in 0..HEADS {
+                matrices.push(Matrix::Query { block, head });
+                matrices.push(Matrix::Key { block, head });
+                matrices.push(Matrix::Value { block, head });
+            }
+        }
+        matrices
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for matrix in all_matrices() {
+            assert_eq!(matrix.to_string().parse::<Matrix>(), Ok(matrix));
+        }
+        for bad in [
+            "",
+            "wte.1",
+            "ln",
+            "ln.x",
+            "block.mlp_up",
+            "block3.mlp",
+            "block0.head.key",
+        ] {
+            assert!(bad.parse::<Matrix>().is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn finds_mlp_up_row_517() {
+        let row = Matrix::MlpUp { block: 3 }.rom_row(517).unwrap();
+        let expected = RomRow {
+            name: "mlp/mlp_163".to_string(),
+            offset: 30 * EMBED_SIZE,
+            len: EMBED_SIZE,
+        };
+        assert_eq!(row, expected);
+    }
+
+    #[test]
+    fn rows_outside_the_model_are_none() {
+        assert_eq!(Matrix::Wte.rom_row(VOCAB_SIZE), None);
+        assert_eq!(Matrix::Wpe.rom_row(CONTEXT_SIZE), None);
+        assert_eq!(Matrix::Layernorm(0).rom_row(0), None);
+        assert_eq!(Matrix::Layernorm(2 * LAYERS + 2).rom_row(0), None);
+        assert_eq!(Matrix::MlpUp { block: LAYERS }.rom_row(0), None);
+        assert_eq!(
+            Matrix::Key {
+                block: 0,
+                head: HEADS
+            }
+            .rom_row(0),
+            None
+        );
+        assert_eq!(Matrix::MlpDown { block: 0 }.rom_row(EMBED_SIZE), None);
+    }
+
+    #[test]
+    fn locates_every_row_in_the_world() {
+        let layout = Layout::load(ModelPaths::default().layout).unwrap();
+        for matrix in all_matrices() {
+            for row in 0..matrix.rows() {
+                assert!(
+                    layout.locate(matrix, row).is_some(),
+                    "{} row {}",
+                    matrix,
+                    row
+                );
+            }
+        }
+
+        // mlp_163 is unflipped at (966, 79, 1113); its row 30 is in the upper
+        // stack, 10 blocks along z.
+        let location = layout.locate(Matrix::MlpUp { block: 3 }, 517).unwrap();
+        assert_eq!(location.rom.name, "mlp/mlp_163");
+        assert_eq!(location.min, (966, 95, 1123));
+        assert_eq!(location.max, (1085, 110, 1123));
+    }
+
+    #[test]
+    fn decodes_roms_back_out_of_a_world() {
+        let paths = ModelPaths::default();
+        let layout = Layout::load(&paths.layout).unwrap();
+        let wanted = |rom: &RomPlacement| rom.name == "layernorm/ln_3" || rom.name == "mlp/mlp_9";
+        let mut world = HashMap::new();
+        for rom in layout.roms().iter().filter(|rom| wanted(rom)) {
+            let bytes = paths.read(&rom.name, rom.format.size()).unwrap();
+            rom.for_each_bit(&bytes, |pos, bit| {
+                world.insert(pos, bit);
+            });
+        }
+
+        let decoded = layout
+            .decode(&paths, wanted, |pos| world.get(&pos).copied())
+            .unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert!(decoded.iter().all(|rom| rom.matches()));
+
+        // Flip the top bit of word 7 of ln_3, and lose a bit of mlp_9.
+        let ln = layout.get("layernorm/ln_3").unwrap();
+        let flipped = ln.position(7, 23);
+        *world.get_mut(&flipped).unwrap() ^= true;
+        let lost = layout.get("mlp/mlp_9").unwrap().position(100, 0);
+        world.remove(&lost);
+
+        let decoded = layout
+            .decode(&paths, wanted, |pos| world.get(&pos).copied())
+            .unwrap();
+        for rom in &decoded {
+            assert!(!rom.matches());
+            if rom.rom.name == "mlp/mlp_9" {
+                assert_eq!(rom.contents, Err(lost));
+            } else {
+                assert_eq!(rom.mismatches.len(), 1);
+                let m = rom.mismatches[0];
+                assert_eq!((m.row, m.column, m.expected ^ m.actual), (0, 7, 1 << 23));
+            }
+        }
+    }
+}
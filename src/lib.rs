@@ -0,0 +1,49 @@
+//! Bit-exact emulator of CraftGPT, the small language model built out of
+//! redstone.
+//!
+//! The emulator reproduces the fixed-point arithmetic of the in-game machine,
+//! so the same prompt and RNG seed produce the same tokens as the world does.
+
+pub mod anvil;
+mod attention;
+mod block;
+pub mod bundle;
+pub mod chat;
+pub mod distribution;
+mod embedding;
+mod error;
+pub mod eval;
+pub mod generate;
+mod layernorm;
+pub mod layout;
+mod matmul;
+mod mlp;
+mod model;
+pub mod nbt;
+mod prng;
+pub mod reference;
+pub mod sampler;
+pub mod schematic;
+pub mod server;
+pub mod session;
+pub mod tokenizer;
+pub mod trace;
+mod unembedding;
+pub mod weights;
+
+pub use bundle::Bundle;
+pub use chat::ChatTemplate;
+pub use distribution::Distribution;
+pub use embedding::Embedding;
+pub use error::LoadError;
+pub use model::{CONTEXT_SIZE, ContextPolicy, Model, ModelState};
+pub use prng::PRNG;
+pub use tokenizer::Tokenizer;
+pub use unembedding::{OUTPUT_SIZE, Unembedding, VOCAB_SIZE};
+pub use weights::{ModelPaths, WeightSource};
+
+pub const EMBED_SIZE: usize = 240;
+pub const FIXED_POINT_SIZE: u32 = 24;
+pub const FIXED_POINT_MASK: u32 = (1 << FIXED_POINT_SIZE) - 1;
+
+pub type Fixed24 = u32;
@@ -1,159 +1,1180 @@
-use std::fs::File;
-use std::io::{self, BufRead, Write};
-
-mod layernorm;
-mod matmul;
-mod mlp;
-mod prng;
-use prng::PRNG;
-mod attention;
-mod block;
-mod model;
-use model::Model;
-mod embedding;
-pub use embedding::Embedding;
-mod unembedding;
-pub use unembedding::Unembedding;
-
-const EMBED_SIZE: usize = 240;
-const FIXED_POINT_SIZE: u32 = 24;
-const FIXED_POINT_MASK: u32 = (1 << FIXED_POINT_SIZE) - 1;
-
-type Fixed24 = u32;
-
-fn get_prompt(tokens: &[String]) -> io::Result<Vec<usize>> {
-    print!("Enter prompt: ");
-    io::stdout().flush()?;
-
-    let mut input = String::new();
-    io::stdin().read_line(&mut input)?;
-    let mut prompt = format!(" {}", input.trim().to_lowercase());
-
-    let mut ans = vec![0];
-
-    while !prompt.is_empty() {
-        let mut maxlength = 0;
-        let mut best = None;
-
-        for (i, token) in tokens.iter().enumerate() {
-            let token_str = token.replace('_', " ");
-            if token_str.len() <= prompt.len()
-                && token_str == &prompt[..token_str.len()]
-                && token_str.len() > maxlength
-            {
-                maxlength = token_str.len();
-                best = Some(i);
-            }
-        }
-
-        if let Some(best_idx) = best {
-            ans.push(best_idx);
-            prompt = prompt[maxlength..].to_string();
-        } else {
-            println!("Could not parse prompt: '{}'", prompt);
-            std::process::exit(1);
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::net::TcpListener;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+
+use craftgpt::anvil::World;
+use craftgpt::bundle::{self, PackError};
+use craftgpt::chat;
+use craftgpt::eval;
+use craftgpt::generate::{CandidateRecord, Event, Generator, TokenRecord, millis};
+use craftgpt::layout::{Layout, Matrix, ONE_BLOCK, Pos, RomPlacement, ZERO_BLOCK};
+use craftgpt::reference::{self, FloatModel};
+use craftgpt::sampler::{FullSampler, Greedy, RedstoneSampler, Sampler};
+use craftgpt::schematic::Schematic;
+use craftgpt::server::{Server, ServerOptions};
+use craftgpt::session::Session;
+use craftgpt::tokenizer::{RomTokenizer, SAMPLE_PROMPTS};
+use craftgpt::trace::ProcessTrace;
+use craftgpt::{
+    Bundle, ChatTemplate, ContextPolicy, Distribution, LoadError, Model, ModelPaths, ModelState,
+    PRNG, Tokenizer, VOCAB_SIZE, WeightSource,
+};
+
+const USAGE: &str = "\
+Usage: craftgpt [OPTIONS] [COMMAND]
+
+Commands:
+  (none)                  chat with the model interactively
+  pack OUT                pack the weights directory into a single bundle
+  unpack BUNDLE DIR       write a bundle back out as individual ROM files
+  check-layout            check every ROM in the layout has a weight file
+  locate MATRIX ROW       show which ROM and blocks hold a row of a matrix,
+                          e.g. `locate block3.mlp_up 517`
+  export-schem OUT        write the ROMs as a Sponge schematic for WorldEdit
+  check-schem FILE        compare the ROMs in a schematic with the weights
+  extract-world WORLD [OUT]
+                          read the ROMs out of a world save and compare them
+                          with the weights, optionally writing them to OUT
+  compare TEXT            run TEXT through the emulator and a float32 model
+                          and report how far each layer's outputs differ
+  check-tokenizer [PROMPTS]
+                          check the in-game tokenizer ROMs agree with
+                          tokens.txt on every token and on each line of
+                          PROMPTS, or on some built-in prompts
+  trace TEXT OUT          record every intermediate activation while
+                          processing TEXT, as JSON if OUT ends in .json and
+                          in a compact binary format otherwise
+  sweep                   answer --prompt once for every seed in --seeds,
+                          best answers first
+  generate                answer --prompt, or each line of --prompts-file,
+                          with --seed, printing one answer per line
+  serve                   answer OpenAI-style completion and chat requests
+                          on http://127.0.0.1:PORT/v1
+  eval                    feed each conversation in --corpus to the model
+                          and report how well it predicts every next token
+
+Options:
+  --weights-dir DIR       directory of ROM files [env: CRAFTGPT_WEIGHTS_DIR]
+  --bundle FILE           load the weights from a bundle instead
+  --mmap                  map the bundle into memory instead of reading it
+                          into a buffer; the file must not change while the
+                          model loads
+  --tokens FILE           vocabulary file [env: CRAFTGPT_TOKENS]
+  --layout FILE           world layout of the ROMs [env: CRAFTGPT_LAYOUT]
+  --only PREFIX           only use ROMs whose name starts with PREFIX,
+                          e.g. `mlp/` or `attention/att_5`
+  --mask-unused           never sample the padding IDs past the end of
+                          the vocabulary
+  --window N              attend to only the last N tokens, instead of
+                          overwriting the oldest once 64 have been seen
+                          like the machine does
+  --session FILE          resume the chat saved in FILE, if there is one,
+                          and save it there after every response
+  --greedy                always answer with the most likely token
+  --temperature T         sample from the whole vocabulary instead of the
+                          machine's top 8, at temperature T
+  --top-k K               sample from the K most likely tokens
+  --top-p P               sample from the most likely tokens that make up
+                          probability P
+  --min-p P               sample from the tokens at least P times as likely
+                          as the most likely one
+  --prompt TEXT           the prompt to answer
+  --prompts-file FILE     answer each line of FILE on its own
+  --seed N                RNG seed
+  --seeds RANGE           RNG seeds to try, e.g. `1..=5000` or `42`
+  --max-tokens N          stop answers after N tokens; `serve` lets requests
+                          ask for fewer [default: 128]
+  --mode MODE             `sample` to answer, or `top8` to print the 8 most
+                          likely next tokens instead [default: sample]
+  --format FORMAT         `text` for one answer per line, or `jsonl` for a
+                          JSON record of every prompt and token, with the
+                          candidates and timings [default: text]
+  --port PORT             port for `serve` to listen on [default: 8080]
+  --cors ORIGIN           let pages from ORIGIN, or `*` for any, call `serve`
+                          from a browser
+  --corpus FILE           conversations for `eval`, one per line, with the
+                          user's turns between [INST] and [/INST]";
+
+struct Options {
+    paths: ModelPaths,
+    bundle: Option<PathBuf>,
+    mmap: bool,
+    only: Option<String>,
+    mask_unused: bool,
+    context: ContextPolicy,
+    session: Option<PathBuf>,
+    greedy: bool,
+    full: Option<FullSampler>,
+    prompt: Option<String>,
+    prompts_file: Option<PathBuf>,
+    seed: Option<u32>,
+    seeds: Option<RangeInclusive<u32>>,
+    max_tokens: usize,
+    mode: Mode,
+    format: Format,
+    port: u16,
+    cors: Option<String>,
+    corpus: Option<PathBuf>,
+    command: Vec<String>,
+}
+
+/// How `generate` writes its results.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// One answer per line.
+    Text,
+    /// One JSON record per event, see [`Event`].
+    Jsonl,
+}
+
+/// What `generate` prints for each prompt.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// The sampled answer.
+    Sample,
+    /// The machine's 8 most likely next tokens.
+    Top8,
+}
+
+/// A command was run without something it needs; exits with status 2 and
+/// the usage, like a bad flag.
+#[derive(Debug)]
+struct Usage(String);
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for Usage {}
+
+/// Reads the flags, falling back to the environment, and collects the
+/// positional arguments.
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        paths: ModelPaths::from_env(),
+        bundle: None,
+        mmap: false,
+        only: None,
+        mask_unused: false,
+        context: ContextPolicy::Faithful,
+        session: None,
+        greedy: false,
+        full: None,
+        prompt: None,
+        prompts_file: None,
+        seed: None,
+        seeds: None,
+        max_tokens: 128,
+        mode: Mode::Sample,
+        format: Format::Text,
+        port: 8080,
+        cors: None,
+        corpus: None,
+        command: Vec::new(),
+    };
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| {
+            args.next()
+                .map(PathBuf::from)
+                .ok_or_else(|| format!("{} needs a value", flag))
+        };
+        match arg.as_str() {
+            "--weights-dir" => options.paths.weights_dir = value("--weights-dir")?,
+            "--bundle" => options.bundle = Some(value("--bundle")?),
+            "--tokens" => options.paths.tokens = value("--tokens")?,
+            "--layout" => options.paths.layout = value("--layout")?,
+            "--mmap" => options.mmap = true,
+            "--mask-unused" => options.mask_unused = true,
+            "--session" => options.session = Some(value("--session")?),
+            "--window" => {
+                let size = number("--window", value("--window")?, |&size: &usize| size > 0)?;
+                options.context = ContextPolicy::SlidingWindow(size);
+            }
+            "--greedy" => options.greedy = true,
+            "--temperature" => {
+                let t = number("--temperature", value("--temperature")?, |&t: &f64| t > 0.0)?;
+                options
+                    .full
+                    .get_or_insert_with(FullSampler::default)
+                    .temperature = t;
+            }
+            "--top-k" => {
+                let k = number("--top-k", value("--top-k")?, |&k: &usize| k > 0)?;
+                options.full.get_or_insert_with(FullSampler::default).top_k = Some(k);
+            }
+            "--top-p" => {
+                let p = number("--top-p", value("--top-p")?, |&p: &f64| p > 0.0 && p <= 1.0)?;
+                options.full.get_or_insert_with(FullSampler::default).top_p = Some(p);
+            }
+            "--min-p" => {
+                let p = number("--min-p", value("--min-p")?, |&p: &f64| {
+                    (0.0..=1.0).contains(&p)
+                })?;
+                options.full.get_or_insert_with(FullSampler::default).min_p = Some(p);
+            }
+            "--only" => options.only = Some(value("--only")?.to_string_lossy().into_owned()),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            "--prompt" => options.prompt = Some(value("--prompt")?.to_string_lossy().into_owned()),
+            "--prompts-file" => options.prompts_file = Some(value("--prompts-file")?),
+            "--seed" => {
+                let seed = number("--seed", value("--seed")?, |&seed: &u32| seed < 1 << 23)?;
+                options.seed = Some(seed);
+            }
+            "--mode" => {
+                options.mode = match value("--mode")?.to_str() {
+                    Some("sample") => Mode::Sample,
+                    Some("top8") => Mode::Top8,
+                    _ => return Err("--mode must be `sample` or `top8`".to_string()),
+                }
+            }
+            "--format" => {
+                options.format = match value("--format")?.to_str() {
+                    Some("text") => Format::Text,
+                    Some("jsonl") => Format::Jsonl,
+                    _ => return Err("--format must be `text` or `jsonl`".to_string()),
+                }
+            }
+            "--port" => options.port = number("--port", value("--port")?, |_| true)?,
+            "--cors" => options.cors = Some(value("--cors")?.to_string_lossy().into_owned()),
+            "--corpus" => options.corpus = Some(value("--corpus")?),
+            "--seeds" => {
+                let seeds = value("--seeds")?;
+                options.seeds = Some(
+                    parse_seeds(&seeds.to_string_lossy())
+                        .ok_or_else(|| format!("invalid seed range '{}'", seeds.display()))?,
+                );
+            }
+            "--max-tokens" => {
+                options.max_tokens = number("--max-tokens", value("--max-tokens")?, |&n| n > 0)?;
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown argument '{}'", arg)),
+            _ => options.command.push(arg),
         }
     }
 
-    Ok(ans)
+    if options.greedy && options.full.is_some() {
+        return Err("--greedy can't be combined with the other sampling options".to_string());
+    }
+    Ok(options)
+}
+
+/// Parses a flag's value, rejecting it unless `valid`.
+fn number<T, F>(flag: &str, value: PathBuf, valid: F) -> Result<T, String>
+where
+    T: FromStr,
+    F: Fn(&T) -> bool,
+{
+    match value.to_string_lossy().parse() {
+        Ok(n) if valid(&n) => Ok(n),
+        _ => Err(format!("invalid value '{}' for {}", value.display(), flag)),
+    }
+}
+
+/// Reads `A..=B`, `A..B` or a single seed. Seeds are 23 bits, like the PRNG.
+fn parse_seeds(text: &str) -> Option<RangeInclusive<u32>> {
+    let range = if let Some((start, end)) = text.split_once("..=") {
+        start.parse().ok()?..=end.parse().ok()?
+    } else if let Some((start, end)) = text.split_once("..") {
+        start.parse().ok()?..=end.parse::<u32>().ok()?.checked_sub(1)?
+    } else {
+        let seed = text.parse().ok()?;
+        seed..=seed
+    };
+    (!range.is_empty() && *range.end() < 1 << 23).then_some(range)
+}
+
+/// The sampler the flags ask for, the machine's unless told otherwise.
+fn sampler(options: &Options) -> Box<dyn Sampler> {
+    match options.full {
+        _ if options.greedy => Box::new(Greedy),
+        Some(full) => Box::new(full),
+        None => Box::new(RedstoneSampler),
+    }
+}
+
+fn chat_template(tokenizer: &Tokenizer) -> Result<ChatTemplate, String> {
+    ChatTemplate::new(tokenizer).ok_or_else(|| {
+        format!(
+            "the vocabulary has no {} and {} instruction markers",
+            chat::INST_START,
+            chat::INST_END
+        )
+    })
+}
+
+fn load_model(options: &Options) -> Result<Model, LoadError> {
+    let mut model = match &options.bundle {
+        // SAFETY: whoever passes `--mmap` takes on leaving the file alone
+        // while the model loads, as its help says. The bundle is dropped
+        // once the model has copied the tensors out.
+        Some(path) if options.mmap => Model::new(&unsafe { Bundle::map(path) }?)?,
+        Some(path) => Model::new(&Bundle::open(path)?)?,
+        None => Model::new(&options.paths)?,
+    };
+    model.set_context_policy(options.context);
+    Ok(model)
+}
+
+enum Input {
+    /// A user turn, markers included.
+    Prompt(Vec<usize>),
+    /// A line starting with `/`, without the slash.
+    Command(String),
+}
+
+/// Asks for a prompt until one tokenizes or a command is given. Returns
+/// `None` at the end of input.
+fn get_prompt(tokenizer: &Tokenizer, template: &ChatTemplate) -> io::Result<Option<Input>> {
+    loop {
+        print!("Enter prompt: ");
+        io::stdout().flush()?;
+
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input)? == 0 {
+            return Ok(None);
+        }
+
+        if let Some(command) = input.trim().strip_prefix('/') {
+            return Ok(Some(Input::Command(command.to_string())));
+        }
+        match template.user_turn(tokenizer, &input) {
+            Ok(ids) => return Ok(Some(Input::Prompt(ids))),
+            Err(e) => println!("Could not parse prompt: {}", e),
+        }
+    }
 }
 
 fn main() -> io::Result<()> {
-    let tokens: Vec<String> = {
-        let file = File::open("tokens.txt")?;
-        let reader = io::BufReader::new(file);
-        reader.lines().collect::<Result<_, _>>()?
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let command: Vec<&str> = options.command.iter().map(String::as_str).collect();
+    let result = match command.as_slice() {
+        [] => chat(&options),
+        ["pack", out] => bundle::pack(&options.paths.weights_dir, out)
+            .map(|n| println!("Packed {} tensors into {}.", n, out))
+            .map_err(Box::from),
+        ["unpack", path, dir] => Bundle::open(path)
+            .map_err(PackError::from)
+            .and_then(|bundle| bundle.unpack(dir))
+            .map(|()| println!("Unpacked {} into {}.", path, dir))
+            .map_err(Box::from),
+        ["check-layout"] => check_layout(&options),
+        ["locate", matrix, row] => locate(&options, matrix, row),
+        ["export-schem", out] => export_schem(&options, out),
+        ["check-schem", path] => check_schem(&options, path),
+        ["compare", text] => compare(&options, text),
+        ["trace", text, out] => trace(&options, text, out),
+        ["sweep"] => sweep(&options),
+        ["generate"] => generate_answers(&options),
+        ["serve"] => serve(&options),
+        ["eval"] => eval(&options),
+        ["check-tokenizer"] => check_tokenizer(&options, None),
+        ["check-tokenizer", prompts] => check_tokenizer(&options, Some(prompts)),
+        ["extract-world", world] => extract_world(&options, world, None),
+        ["extract-world", world, out] => extract_world(&options, world, Some(out)),
+        _ => {
+            eprintln!("unknown command '{}'\n{}", options.command.join(" "), USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = result {
+        if e.is::<Usage>() {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// The weights to compare against or export: the bundle if one was given,
+/// otherwise the weights directory.
+fn weight_source(options: &Options) -> Result<Box<dyn WeightSource>, LoadError> {
+    Ok(match &options.bundle {
+        Some(path) => Box::new(Bundle::open(path)?),
+        None => Box::new(options.paths.clone()),
+    })
+}
+
+fn selected(options: &Options, rom: &RomPlacement) -> bool {
+    options
+        .only
+        .as_ref()
+        .is_none_or(|prefix| rom.name.starts_with(prefix.as_str()))
+}
+
+/// Decodes every selected ROM with `bit_at` and reports where it differs
+/// from the weights, writing the decoded ROMs to `out` if given. Fails if any
+/// ROM differs or couldn't be read.
+fn compare_roms<F: FnMut(Pos) -> Option<bool>>(
+    options: &Options,
+    layout: &Layout,
+    out: Option<&Path>,
+    bit_at: F,
+) -> Result<(), Box<dyn Error>> {
+    let source = weight_source(options)?;
+    let decoded = layout.decode(source.as_ref(), |rom| selected(options, rom), bit_at)?;
+
+    for rom in &decoded {
+        let name = &rom.rom.name;
+        let actual = match &rom.contents {
+            Ok(actual) => actual,
+            Err((x, y, z)) => {
+                println!("{}: no ROM bit at {} {} {}", name, x, y, z);
+                continue;
+            }
+        };
+        if let Some(out) = out {
+            let path = out.join(format!("{}.bin", name));
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, actual)?;
+        }
+
+        for m in rom.mismatches.iter().take(10) {
+            println!(
+                "{}: row {} column {} is {:#x}, expected {:#x}",
+                name, m.row, m.column, m.actual, m.expected
+            );
+        }
+        if rom.mismatches.len() > 10 {
+            println!("{}: ... {} mismatches in total", name, rom.mismatches.len());
+        }
+    }
+
+    let broken = decoded.iter().filter(|rom| !rom.matches()).count();
+    if broken > 0 {
+        return Err(format!(
+            "{} of {} ROMs don't match the weights",
+            broken,
+            decoded.len()
+        )
+        .into());
+    }
+    println!("All {} ROMs match the weights.", decoded.len());
+    Ok(())
+}
+
+fn export_schem(options: &Options, out: &str) -> Result<(), Box<dyn Error>> {
+    let layout = Layout::load(&options.paths.layout)?;
+    let source = weight_source(options)?;
+    let schematic = Schematic::from_layout(&layout, source.as_ref(), |rom| selected(options, rom))?;
+    schematic.save(out)?;
+    println!(
+        "Wrote a {}x{}x{} schematic at {} {} {} to {}.",
+        schematic.width,
+        schematic.height,
+        schematic.length,
+        schematic.origin.0,
+        schematic.origin.1,
+        schematic.origin.2,
+        out
+    );
+    Ok(())
+}
+
+fn check_schem(options: &Options, path: &str) -> Result<(), Box<dyn Error>> {
+    let layout = Layout::load(&options.paths.layout)?;
+    let schematic = Schematic::load(path).map_err(|e| format!("couldn't read {}: {}", path, e))?;
+    compare_roms(options, &layout, None, |pos| {
+        match schematic.block(pos)? {
+            ONE_BLOCK => Some(true),
+            ZERO_BLOCK => Some(false),
+            _ => None,
+        }
+    })
+}
+
+fn extract_world(options: &Options, dir: &str, out: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let layout = Layout::load(&options.paths.layout)?;
+    let mut world = World::open(dir).map_err(|e| format!("couldn't open {}: {}", dir, e))?;
+    let mut error = None;
+
+    let result = compare_roms(options, &layout, out.map(Path::new), |pos| {
+        match world.block(pos) {
+            Ok(ONE_BLOCK) => Some(true),
+            Ok(ZERO_BLOCK) => Some(false),
+            Ok(_) => None,
+            Err(e) => {
+                error.get_or_insert(e);
+                None
+            }
+        }
+    });
+
+    if let Some(e) = error {
+        return Err(format!("couldn't read {}: {}", dir, e).into());
+    }
+    result
+}
+
+fn check_layout(options: &Options) -> Result<(), Box<dyn Error>> {
+    let layout = Layout::load(&options.paths.layout)?;
+    let errors = layout.validate(weight_source(options)?.as_ref());
+
+    for e in &errors {
+        println!("{}", e);
+    }
+    if !errors.is_empty() {
+        return Err(format!(
+            "{} of {} ROMs are broken",
+            errors.len(),
+            layout.roms().len()
+        )
+        .into());
+    }
+    println!(
+        "All {} ROMs in the layout are present.",
+        layout.roms().len()
+    );
+    Ok(())
+}
+
+fn locate(options: &Options, matrix: &str, row: &str) -> Result<(), Box<dyn Error>> {
+    let layout = Layout::load(&options.paths.layout)?;
+    let matrix: Matrix = matrix.parse()?;
+    let row: usize = row
+        .parse()
+        .map_err(|_| format!("'{}' is not a row number", row))?;
+    let location = layout.locate(matrix, row).ok_or_else(|| {
+        format!(
+            "{} has no row {} (it has {} rows), or its ROM isn't in the layout",
+            matrix,
+            row,
+            matrix.rows()
+        )
+    })?;
+
+    let rom = location.rom;
+    println!(
+        "{} row {}: {} bytes {}..{}",
+        matrix,
+        row,
+        rom.name,
+        location.row.offset,
+        location.row.offset + location.row.len
+    );
+    println!(
+        "ROM at {} {} {}{}, blocks {} {} {} to {} {} {}",
+        rom.x,
+        rom.y,
+        rom.z,
+        if rom.flipped { " (flipped)" } else { "" },
+        location.min.0,
+        location.min.1,
+        location.min.2,
+        location.max.0,
+        location.max.1,
+        location.max.2
+    );
+    Ok(())
+}
+
+fn check_tokenizer(options: &Options, prompts: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let tokenizer = Tokenizer::load(&options.paths.tokens)?;
+    let template = chat_template(&tokenizer)?;
+    let rom = RomTokenizer::new(weight_source(options)?.as_ref())?;
+
+    let prompts: Vec<String> = match prompts {
+        Some(path) => fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read {}: {}", path, e))?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(String::from)
+            .collect(),
+        None => SAMPLE_PROMPTS.iter().map(|&p| p.to_string()).collect(),
+    };
+    let divergences = rom.divergences(&tokenizer, &template, &prompts);
+
+    for divergence in &divergences {
+        println!("{}", divergence);
+    }
+    if !divergences.is_empty() {
+        return Err(format!("{} divergences between the tokenizers", divergences.len()).into());
+    }
+    println!(
+        "The ROM tokenizer agrees on all {} tokens and {} prompts.",
+        tokenizer.used(),
+        prompts.len()
+    );
+    Ok(())
+}
+
+fn trace(options: &Options, text: &str, out: &str) -> Result<(), Box<dyn Error>> {
+    let ids = prompt_ids(options, text)?;
+    let mut model = load_model(options)?;
+    let mut trace = ProcessTrace::default();
+    for &token in &ids {
+        model.predict_traced(token, &mut trace);
+    }
+
+    if out.ends_with(".json") {
+        trace.save_json(out)?;
+    } else {
+        trace.save_binary(out)?;
+    }
+    println!("Wrote the activations of {} tokens to {}.", ids.len(), out);
+    Ok(())
+}
+
+/// The tokens the machine processes for a one-turn prompt before it starts
+/// generating.
+fn prompt_ids(options: &Options, text: &str) -> Result<Vec<usize>, Box<dyn Error>> {
+    let tokenizer = Tokenizer::load(&options.paths.tokens)?;
+    let template = chat_template(&tokenizer)?;
+    Ok(template
+        .user_turn(&tokenizer, text)
+        .map_err(|e| format!("could not parse prompt: {}", e))?)
+}
+
+fn generator<'a>(
+    options: &Options,
+    tokenizer: &'a Tokenizer,
+    template: ChatTemplate,
+) -> Generator<'a> {
+    Generator {
+        tokenizer,
+        template,
+        max_tokens: options.max_tokens,
+        mask_unused: options.mask_unused,
+    }
+}
+
+fn serve(options: &Options) -> Result<(), Box<dyn Error>> {
+    let tokenizer = Tokenizer::load(&options.paths.tokens)?;
+    let template = chat_template(&tokenizer)?;
+    let model = load_model(options)?;
+    let listener = TcpListener::bind(("127.0.0.1", options.port))?;
+    println!("Listening on http://127.0.0.1:{}/v1", options.port);
+
+    let server_options = ServerOptions {
+        sampler: sampler(options),
+        max_tokens: options.max_tokens,
+        mask_unused: options.mask_unused,
+        cors_origin: options.cors.clone(),
+        ..ServerOptions::default()
+    };
+    Server::new(model, tokenizer, template, server_options).run(&listener);
+    Ok(())
+}
+
+fn sweep(options: &Options) -> Result<(), Box<dyn Error>> {
+    let (Some(prompt), Some(seeds)) = (&options.prompt, &options.seeds) else {
+        return Err(Usage("sweep needs --prompt and --seeds".to_string()).into());
+    };
+    let tokenizer = Tokenizer::load(&options.paths.tokens)?;
+    let template = chat_template(&tokenizer)?;
+    let mut model = load_model(options)?;
+    let mut sampler = sampler(options);
+    let generator = generator(options, &tokenizer, template);
+
+    let (_, first) = generator
+        .prompt(&mut model, prompt)
+        .map_err(|e| format!("could not parse prompt: {}", e))?;
+    let results = generator.sweep(&mut model, &first, seeds.clone(), sampler.as_mut());
+    println!("seed\tbinary\ttokens\tmean log-prob\tresponse");
+    for (seed, generation) in &results {
+        println!(
+            "{}\t{:023b}\t{}\t{:.4}\t{}",
+            seed,
+            seed,
+            generation.tokens().len(),
+            generation.mean_log_prob(),
+            tokenizer.decode(&generation.tokens()).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Answers every prompt from a fresh start with the same seed, so each line
+/// of output only depends on its own prompt. A prompt that doesn't tokenize
+/// gets an empty line, so the output still lines up with the input.
+fn generate_answers(options: &Options) -> Result<(), Box<dyn Error>> {
+    let prompts: Vec<String> = match (&options.prompt, &options.prompts_file) {
+        (Some(prompt), None) => vec![prompt.clone()],
+        (None, Some(path)) => fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read {}: {}", path.display(), e))?
+            .lines()
+            .map(String::from)
+            .collect(),
+        _ => return Err(Usage("generate needs one of --prompt and --prompts-file".into()).into()),
+    };
+    let seed = match (options.seed, options.mode) {
+        (Some(seed), _) => seed,
+        (None, Mode::Top8) => 0,
+        (None, Mode::Sample) => return Err(Usage("generate needs --seed".to_string()).into()),
+    };
+
+    let tokenizer = Tokenizer::load(&options.paths.tokens)?;
+    let template = chat_template(&tokenizer)?;
+    let mut model = load_model(options)?;
+    let mut sampler = sampler(options);
+    let generator = generator(options, &tokenizer, template);
+    let start = model.snapshot();
+    let mut failed = 0;
+
+    let emit = |event: Event| println!("{}", serde_json::to_string(&event).unwrap());
+
+    for (line, prompt) in prompts.iter().enumerate() {
+        let line = line + 1;
+        model.restore(&start);
+        let timer = Instant::now();
+        let (ids, first) = match generator.prompt(&mut model, prompt) {
+            Ok(processed) => processed,
+            Err(e) => {
+                let e = format!("could not parse prompt: {}", e);
+                eprintln!("line {}: {}", line, e);
+                match options.format {
+                    Format::Text => println!(),
+                    Format::Jsonl => emit(Event::Error { line, message: e }),
+                }
+                failed += 1;
+                continue;
+            }
+        };
+        if options.format == Format::Jsonl {
+            emit(Event::Prompt {
+                line,
+                text: prompt,
+                tokens: ids
+                    .iter()
+                    .map(|&id| TokenRecord::new(&tokenizer, id))
+                    .collect(),
+                millis: millis(timer.elapsed()),
+            });
+        }
+
+        match (options.mode, options.format) {
+            (Mode::Sample, format) => {
+                let mut rng = PRNG::new(seed);
+                let generation =
+                    generator.generate(&mut model, first, sampler.as_mut(), &mut rng, &mut |_| {});
+                let answer = tokenizer.decode(&generation.tokens());
+                if format == Format::Text {
+                    println!("{}", answer.trim());
+                    continue;
+                }
+
+                for (index, step) in generation.steps.iter().enumerate() {
+                    emit(Event::token(&tokenizer, line, index, step));
+                }
+                emit(Event::End {
+                    line,
+                    stop_reason: generation.stop,
+                    answer: answer.trim(),
+                    tokens: generation.tokens().len(),
+                    millis: millis(timer.elapsed()),
+                });
+            }
+            (Mode::Top8, Format::Text) => {
+                let top: Vec<String> = first
+                    .top()
+                    .map(|c| {
+                        let text = tokenizer.token(c.token);
+                        format!("{} {:.5} {}", c.token, c.probability(), text)
+                    })
+                    .collect();
+                println!("{}", top.join("\t"));
+            }
+            (Mode::Top8, Format::Jsonl) => emit(Event::Top8 {
+                line,
+                candidates: CandidateRecord::list(&tokenizer, first.top()),
+            }),
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!(
+            "{} of {} prompts could not be parsed",
+            failed,
+            prompts.len()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn compare(options: &Options, text: &str) -> Result<(), Box<dyn Error>> {
+    let ids = prompt_ids(options, text)?;
+    let mut model = load_model(options)?;
+    let mut float = FloatModel::new(weight_source(options)?.as_ref())?;
+    let drift = reference::compare(&mut model, &mut float, &ids);
+
+    println!("{:<12} {:>12} {:>12}", "layer", "max error", "mean error");
+    for (name, error) in &drift.layers {
+        println!("{:<12} {:>12.6} {:>12.6}", name, error.max, error.mean());
+    }
+    println!(
+        "The most likely next token agrees at {} of {} positions.",
+        drift.agree, drift.tokens
+    );
+    Ok(())
+}
+
+/// Teacher-forces every conversation in the corpus through the model, each
+/// from a fresh start, and scores the full distribution's prediction of each
+/// token after the first.
+fn eval(options: &Options) -> Result<(), Box<dyn Error>> {
+    let Some(path) = &options.corpus else {
+        return Err(Usage("eval needs --corpus".to_string()).into());
     };
+    let corpus =
+        fs::read_to_string(path).map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+    let tokenizer = Tokenizer::load(&options.paths.tokens)?;
+    let mut model = load_model(options)?;
+
+    let mut conversations = Vec::new();
+    let mut failed = 0;
+    for (line, text) in corpus.lines().enumerate() {
+        if text.trim().is_empty() {
+            continue;
+        }
+        match tokenizer.encode(text) {
+            Ok(ids) => conversations.push(ids),
+            Err(e) => {
+                eprintln!("line {}: could not parse conversation: {}", line + 1, e);
+                failed += 1;
+            }
+        }
+    }
+
+    let scores = eval::evaluate(&mut model, conversations.iter().map(Vec::as_slice));
+    if scores.targets == 0 {
+        return Err(format!("{} has no conversations to score", path.display()).into());
+    }
+    let percent = |n: usize| 100.0 * scores.fraction(n);
+    println!(
+        "Scored {} tokens in {} conversations.",
+        scores.targets, scores.conversations
+    );
+    match (scores.mean_nll(), scores.perplexity()) {
+        (Some(nll), Some(perplexity)) => {
+            println!("{:<28} {:>10.4}", "mean NLL (nats per token)", nll);
+            println!("{:<28} {:>10.4}", "perplexity", perplexity);
+        }
+        _ => println!("{:<28} {:>10}", "mean NLL (nats per token)", "none"),
+    }
+    println!("{:<28} {:>9.2}%", "top-1 accuracy", percent(scores.top1));
+    println!("{:<28} {:>9.2}%", "top-8 accuracy", percent(scores.top8));
+    println!(
+        "{:<28} {:>9.2}%",
+        "outside the machine's top 8",
+        percent(scores.outside)
+    );
+    if scores.impossible > 0 {
+        println!(
+            "{} tokens had no probability at all and are left out of the NLL.",
+            scores.impossible
+        );
+    }
+
+    if failed > 0 {
+        return Err(format!("{} lines couldn't be tokenized", failed).into());
+    }
+    Ok(())
+}
+
+/// Prints the top 8 tokens and their probabilities, most likely first.
+fn print_top(tokenizer: &Tokenizer, dist: &Distribution) {
+    for (i, c) in dist.top().enumerate() {
+        let prob_normalized = (c.probability() * 100000.0).round() / 100000.0;
+        println!(
+            "{}: {:>4}, probability {:.5}, {}",
+            i + 1,
+            c.token,
+            prob_normalized,
+            tokenizer.token(c.token)
+        );
+    }
+}
+
+/// Points out padding IDs among the top 8, which only a model whose weights
+/// don't match the vocabulary should give any probability.
+fn report_unused(tokenizer: &Tokenizer, dist: &Distribution) {
+    for c in dist.top() {
+        if tokenizer.is_unused(c.token) && c.prob > 0 {
+            println!(
+                "(the model gave probability {:.5} to {})",
+                c.probability(),
+                tokenizer.token(c.token)
+            );
+        }
+    }
+}
+
+/// Asks for the next token in the probability view until it is a valid ID.
+fn get_token() -> io::Result<Option<usize>> {
+    loop {
+        print!("Enter next token ID: ");
+        io::stdout().flush()?;
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input)? == 0 {
+            return Ok(None);
+        }
+        match input.trim().parse() {
+            Ok(id) if id < VOCAB_SIZE => return Ok(Some(id)),
+            _ => println!("Token IDs go from 0 to {}.", VOCAB_SIZE - 1),
+        }
+    }
+}
+
+const COMMANDS: &str = "\
+/reset      forget the conversation and start afresh
+/undo [N]   take back the last N tokens, 1 if not given
+/seed N     reseed the RNG, or -1 to pick each token yourself
+/tokens     show how the last prompt was tokenized
+/probs      toggle showing the top 8 tokens while sampling
+/save [FILE], /load [FILE]
+            save or load a session, by default the --session one
+/help       show this list";
+
+/// What the chat carries from one prompt to the next.
+struct ChatState {
+    /// Every token the model has processed, in order.
+    conversation: Vec<usize>,
+    /// -1 to pick each token by hand.
+    seed: i32,
+    rng: PRNG,
+    show_probs: bool,
+    last_prompt: Vec<usize>,
+}
+
+/// Runs one of the [`COMMANDS`]. Mistakes are reported and otherwise ignored,
+/// so that a typo doesn't end the chat.
+fn run_command(
+    command: &str,
+    options: &Options,
+    tokenizer: &Tokenizer,
+    template: &ChatTemplate,
+    model: &mut Model,
+    start: &ModelState,
+    state: &mut ChatState,
+) {
+    let mut words = command.split_whitespace();
+    let name = words.next().unwrap_or("");
+    let arg = words.next();
+    let session_path = || arg.map(PathBuf::from).or_else(|| options.session.clone());
+
+    match name {
+        "reset" => {
+            model.set_context_policy(options.context);
+            model.restore(start);
+            state.conversation.clear();
+            println!("Forgot the conversation.");
+        }
+        "undo" => {
+            let Some(n) = arg.map_or(Some(1), |arg| arg.parse::<usize>().ok()) else {
+                println!("Usage: /undo [N]");
+                return;
+            };
+            if n > model.undoable() {
+                println!("Can only undo the last {} tokens.", model.undoable());
+                return;
+            }
+            model.undo(n);
+            state.conversation.truncate(state.conversation.len() - n);
+            print!("{}", template.transcript(tokenizer, &state.conversation));
+        }
+        "seed" => match arg.map(str::parse::<i32>) {
+            Some(Ok(seed)) => {
+                state.seed = seed;
+                state.rng = PRNG::new(seed as u32);
+                println!("Seed set to {}.", seed);
+            }
+            _ => println!("Usage: /seed N"),
+        },
+        "tokens" => {
+            for &id in &state.last_prompt {
+                println!("{:>4} {}", id, tokenizer.token(id));
+            }
+        }
+        "probs" => {
+            state.show_probs = !state.show_probs;
+            let shown = if state.show_probs { "on" } else { "off" };
+            println!("Showing the top 8 tokens while sampling: {}", shown);
+        }
+        "save" => {
+            let Some(path) = session_path() else {
+                println!("Usage: /save FILE");
+                return;
+            };
+            let session = Session::new(model, state.conversation.clone(), state.rng.clone());
+            match session.save(&path) {
+                Ok(()) => println!(
+                    "Saved {} tokens to {}.",
+                    session.tokens.len(),
+                    path.display()
+                ),
+                Err(e) => println!("Could not save session {}: {}", path.display(), e),
+            }
+        }
+        "load" => {
+            let Some(path) = session_path() else {
+                println!("Usage: /load FILE");
+                return;
+            };
+            match Session::load(&path) {
+                Ok(session) => {
+                    session.restore(model);
+                    state.conversation = session.tokens;
+                    state.rng = session.rng;
+                    println!("Loaded a session of {} tokens:", state.conversation.len());
+                    print!("{}", template.transcript(tokenizer, &state.conversation));
+                }
+                Err(e) => println!("Could not load session {}: {}", path.display(), e),
+            }
+        }
+        "help" => println!("{}", COMMANDS),
+        _ => println!("Unknown command /{}. /help lists them.", name),
+    }
+}
+
+fn chat(options: &Options) -> Result<(), Box<dyn Error>> {
+    let tokenizer = Tokenizer::load(&options.paths.tokens)
+        .map_err(|e| format!("could not load vocabulary: {}", e))?;
+    let template = chat_template(&tokenizer)?;
 
     let mut conversation = Vec::new();
-    let mut model = Model::new();
+    let mut model = load_model(options).map_err(|e| format!("could not load model: {}", e))?;
+    let start = model.snapshot();
+    println!("Model loaded.");
+
+    let session = match &options.session {
+        Some(path) if path.exists() => Some(
+            Session::load(path)
+                .map_err(|e| format!("could not load session {}: {}", path.display(), e))?,
+        ),
+        _ => None,
+    };
+    if let Some(session) = &session {
+        session.restore(&mut model);
+        conversation = session.tokens.clone();
+        println!("Resumed a session of {} tokens:", conversation.len());
+        print!("{}", template.transcript(&tokenizer, &conversation));
+    }
 
-    print!("Enter RNG seed, or -1 to view next token probability distribution: ");
-    io::stdout().flush()?;
-    let mut seed_input = String::new();
-    io::stdin().read_line(&mut seed_input)?;
-    let seed: i32 = seed_input.trim().parse().unwrap();
+    let mut sampler = sampler(options);
+    let (seed, rng) = loop {
+        if session.is_some() {
+            print!("Enter RNG seed, -1 to view next token probability distribution, ");
+            print!("or nothing to carry on with the saved RNG: ");
+        } else {
+            print!("Enter RNG seed, or -1 to view next token probability distribution: ");
+        }
+        io::stdout().flush()?;
+        let mut seed_input = String::new();
+        if io::stdin().read_line(&mut seed_input)? == 0 {
+            return Ok(());
+        }
 
-    let mut rng = PRNG::new(seed as u32);
+        let seed_input = seed_input.trim();
+        match (&session, seed_input.parse::<i32>()) {
+            (Some(session), _) if seed_input.is_empty() => break (0, session.rng.clone()),
+            (_, Ok(seed)) => break (seed, PRNG::new(seed as u32)),
+            (_, Err(_)) => println!("Could not parse seed {:?}.", seed_input),
+        }
+    };
 
+    let mut state = ChatState {
+        conversation,
+        seed,
+        rng,
+        show_probs: false,
+        last_prompt: Vec::new(),
+    };
     loop {
-        let prompt = get_prompt(&tokens)?;
-        conversation.extend_from_slice(&prompt);
-        conversation.push(1);
+        let prompt = match get_prompt(&tokenizer, &template)? {
+            None => return Ok(()),
+            Some(Input::Command(command)) => {
+                let (model, start) = (&mut model, &start);
+                run_command(
+                    &command, options, &tokenizer, &template, model, start, &mut state,
+                );
+                continue;
+            }
+            Some(Input::Prompt(prompt)) => prompt,
+        };
+        state.conversation.extend_from_slice(&prompt);
+        state.last_prompt = prompt.clone();
 
-        for &token in &prompt {
-            println!("Processing token '{}'", tokens[token]);
-            assert!(token < unembedding::VOCAB_SIZE);
+        // The closing marker is fed as the first step of the answer, so that
+        // its prediction can be shown.
+        let (&end, prompt) = prompt.split_last().unwrap();
+        for &token in prompt {
+            println!("Processing token '{}'", tokenizer.token(token));
+            assert!(token < VOCAB_SIZE);
             model.process(token);
         }
 
-        if seed == -1 {
-            let mut nxt = 1;
+        if state.seed == -1 {
+            let mut nxt = end;
             loop {
-                println!("Processing token '{}'", tokens[nxt]);
-                let ans = model.process(nxt);
-                for i in 0..8 {
-                    let token = (ans[i] & 2047) as usize;
-                    let prob = (ans[i] >> 11) as f64;
-                    let prob_normalized =
-                        (prob / ((1u64 << 23) as f64) * 100000.0).round() / 100000.0;
-                    println!(
-                        "{}: {:>4}, probability {:.5}, {}",
-                        i + 1,
-                        token,
-                        prob_normalized,
-                        tokens[token]
-                    );
-                }
+                println!("Processing token '{}'", tokenizer.token(nxt));
+                let dist = model.predict(nxt);
+                print_top(&tokenizer, &dist);
+                report_unused(&tokenizer, &dist);
 
-                print!("Enter next token ID: ");
-                io::stdout().flush()?;
-                let mut nxt_input = String::new();
-                io::stdin().read_line(&mut nxt_input)?;
-                nxt = nxt_input.trim().parse().unwrap();
+                let Some(next) = get_token()? else {
+                    return Ok(());
+                };
+                nxt = next;
 
-                if nxt == 0 || nxt == 1 {
+                if template.is_marker(nxt) {
                     break;
                 }
-                conversation.push(nxt);
+                state.conversation.push(nxt);
             }
         } else {
-            let mut nxt = 1;
+            let mut nxt = end;
             loop {
-                println!("Processing token '{}'", tokens[nxt]);
-                let act = model.process(nxt);
-                let mut cur = rng.next() as i32;
-                let mut here = -1i32;
-
-                for j in (1..=7).rev() {
-                    if (act[j] >> 11) < (1 << 20) {
-                        continue;
-                    }
-                    cur -= (act[j] >> 11) as i32;
-                    if cur < 0 {
-                        here = (act[j] & 2047) as i32;
-                        break;
-                    }
+                println!("Processing token '{}'", tokenizer.token(nxt));
+                let mut dist = model.predict(nxt);
+                if state.show_probs {
+                    print_top(&tokenizer, &dist);
                 }
-
-                if here == -1 {
-                    here = (act[0] & 2047) as i32;
+                report_unused(&tokenizer, &dist);
+                if options.mask_unused {
+                    dist = dist.mask(|id| !tokenizer.is_unused(id));
                 }
+                let here = sampler.sample(&dist, &mut state.rng).token;
 
-                if here == 0 || here == 1 {
+                if template.is_marker(here) {
                     break;
                 }
 
-                conversation.push(here as usize);
-                nxt = here as usize;
+                state.conversation.push(here);
+                nxt = here;
             }
         }
 
-        let output: String = conversation
-            .iter()
-            .map(|&i| tokens[i].as_str())
-            .collect::<String>()
-            .replace('_', " ");
-        println!("{}", output);
+        println!("{}", tokenizer.decode(&state.conversation));
+
+        if let Some(path) = &options.session {
+            let session = Session::new(&model, state.conversation.clone(), state.rng.clone());
+            if let Err(e) = session.save(path) {
+                println!("Could not save session {}: {}", path.display(), e);
+            }
+        }
     }
 }
@@ -4,46 +4,73 @@ pub const MATMUL_FIXED_POINT: u32 = 18;
 const MATMUL_EXTRA_PRECISION: u32 = 4;
 const MATMUL_BIG_MASK: u32 = (1 << (FIXED_POINT_SIZE + MATMUL_EXTRA_PRECISION)) - 1;
 
+/// A decoded weight: (negative, shift, big multiplier, small multiplier).
+type Weight = (bool, u32, u32, u32);
+
+/// How each of the 256 weight bytes decodes, so matrices can keep the raw
+/// bytes instead of the decoded tuples.
+const DECODE: [Weight; 256] = {
+    let mut table = [(false, 0, 0, 0); 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = decode(i as u8);
+        i += 1;
+    }
+    table
+};
+
+const fn decode(w: u8) -> Weight {
+    let neg = w >= 128;
+    let w = w % 128;
+
+    if w < 64 {
+        (neg, 8u32, (w / 8) as u32, (w % 8) as u32)
+    } else if w < 96 {
+        let w = w - 64;
+        (neg, 7u32, (4 + (w / 8)) as u32, (w % 8) as u32)
+    } else if w < 112 {
+        let w = w - 96;
+        (neg, 5u32, (2 + (w / 8)) as u32, (w % 8) as u32)
+    } else if w < 120 {
+        let w = w - 112;
+        (neg, 3u32, (1 + (w / 8)) as u32, (w % 8) as u32)
+    } else {
+        let w = w - 120;
+        (neg, 2u32, (1 + (w / 8)) as u32, (w % 8) as u32)
+    }
+}
+
+/// The real number a weight byte multiplies its input by.
+pub(crate) fn dequantize(w: u8) -> f32 {
+    let (neg, shift, big, small) = DECODE[w as usize];
+    let value = (8 * big + small) as f32 / (1u32 << (shift + 3)) as f32;
+    if neg { -value } else { value }
+}
+
 pub struct MatMul<const INPUT_SIZE: usize, const OUTPUT_SIZE: usize> {
-    weights: Box<[[(bool, u32, u32, u32); INPUT_SIZE]; OUTPUT_SIZE]>,
+    /// The raw weight bytes, one row of `INPUT_SIZE` per output.
+    weights: Box<[u8]>,
     relu: bool,
 }
 
 impl<const INPUT_SIZE: usize, const OUTPUT_SIZE: usize> MatMul<INPUT_SIZE, OUTPUT_SIZE> {
-    pub fn new(weights: &[[u8; INPUT_SIZE]; OUTPUT_SIZE], relu: bool) -> Self {
-        let mut parsed_weights: [[(bool, u32, u32, u32); INPUT_SIZE]; OUTPUT_SIZE] =
-            [[(false, 0, 0, 0); INPUT_SIZE]; OUTPUT_SIZE];
-
-        for (i, &row) in weights.iter().enumerate() {
-            for (j, &w) in row.iter().enumerate() {
-                let neg = w >= 128;
-                let w = w % 128;
-
-                let parsed = if w < 64 {
-                    (neg, 8u32, (w / 8) as u32, (w % 8) as u32)
-                } else if w < 96 {
-                    let w = w - 64;
-                    (neg, 7u32, (4 + (w / 8)) as u32, (w % 8) as u32)
-                } else if w < 112 {
-                    let w = w - 96;
-                    (neg, 5u32, (2 + (w / 8)) as u32, (w % 8) as u32)
-                } else if w < 120 {
-                    let w = w - 112;
-                    (neg, 3u32, (1 + (w / 8)) as u32, (w % 8) as u32)
-                } else {
-                    let w = w - 120;
-                    (neg, 2u32, (1 + (w / 8)) as u32, (w % 8) as u32)
-                };
-                parsed_weights[i][j] = parsed;
-            }
-        }
+    /// Takes the weight bytes in row-major order, `OUTPUT_SIZE` rows of
+    /// `INPUT_SIZE`.
+    pub fn new(weights: Vec<u8>, relu: bool) -> Self {
+        assert_eq!(weights.len(), INPUT_SIZE * OUTPUT_SIZE);
 
         Self {
-            weights: Box::new(parsed_weights),
+            weights: weights.into_boxed_slice(),
             relu,
         }
     }
 
+    /// The raw weight bytes, as given to [`MatMul::new`].
+    #[cfg(test)]
+    pub(crate) fn weights(&self) -> &[u8] {
+        &self.weights
+    }
+
     pub fn forward(&self, input: &[Fixed24; INPUT_SIZE]) -> [Fixed24; OUTPUT_SIZE] {
         let mut output = [0u32; OUTPUT_SIZE];
         let mut normed: [u32; INPUT_SIZE] = [0; INPUT_SIZE];
@@ -56,20 +83,20 @@ impl<const INPUT_SIZE: usize, const OUTPUT_SIZE: usize> MatMul<INPUT_SIZE, OUTPU
             normed[i] = val;
         }
 
-        for i in 0..OUTPUT_SIZE {
+        for (out, row) in output.iter_mut().zip(self.weights.chunks_exact(INPUT_SIZE)) {
             let mut cur: u32 = 0;
 
-            for j in 0..INPUT_SIZE {
-                let w = &self.weights[i][j];
+            for (&x, &byte) in normed.iter().zip(row) {
+                let w = &DECODE[byte as usize];
 
-                let mut big = (normed[j] as u64 * w.2 as u64) & MATMUL_BIG_MASK as u64;
+                let mut big = (x as u64 * w.2 as u64) & MATMUL_BIG_MASK as u64;
                 if big > (MATMUL_BIG_MASK / 2) as u64 {
-                    big = big + ((255u64) << (MATMUL_EXTRA_PRECISION + FIXED_POINT_SIZE));
+                    big += 255u64 << (MATMUL_EXTRA_PRECISION + FIXED_POINT_SIZE);
                 }
 
-                let mut small = (normed[j] as u64 * w.3 as u64) & MATMUL_BIG_MASK as u64;
+                let mut small = (x as u64 * w.3 as u64) & MATMUL_BIG_MASK as u64;
                 if small > (MATMUL_BIG_MASK / 2) as u64 {
-                    small = small + ((255u64) << (MATMUL_EXTRA_PRECISION + FIXED_POINT_SIZE));
+                    small += 255u64 << (MATMUL_EXTRA_PRECISION + FIXED_POINT_SIZE);
                 }
 
                 let mut cont = ((big >> w.1) + (small >> (w.1 + 3))) as u32;
@@ -82,13 +109,20 @@ impl<const INPUT_SIZE: usize, const OUTPUT_SIZE: usize> MatMul<INPUT_SIZE, OUTPU
                 cur = cur.wrapping_add(cont) & FIXED_POINT_MASK;
             }
 
-            if self.relu && cur > FIXED_POINT_MASK / 2 {
-                output[i] = 0;
+            *out = if self.relu && cur > FIXED_POINT_MASK / 2 {
+                0
             } else {
-                output[i] = cur;
-            }
+                cur
+            };
         }
 
         output
     }
 }
+
+/// Copies `values` into row `row` of a row-major matrix with rows of
+/// `values.len()` bytes.
+pub fn set_row(weights: &mut [u8], row: usize, values: &[u8]) {
+    let n = values.len();
+    weights[n * row..n * (row + 1)].copy_from_slice(values);
+}
@@ -1,107 +1,92 @@
-use crate::matmul::MatMul;
-use crate::{Fixed24, EMBED_SIZE};
-use std::fs::File;
-use std::io::Read;
+use crate::error::LoadError;
+use crate::matmul::{MatMul, set_row};
+use crate::trace::Tracer;
+use crate::weights::{MATRIX_ROM_SIZE, WeightSource};
+use crate::{EMBED_SIZE, Fixed24};
 
 const MLP_SCALE: usize = 4;
-const HIDDEN_SIZE: usize = EMBED_SIZE * MLP_SCALE;
+pub(crate) const HIDDEN_SIZE: usize = EMBED_SIZE * MLP_SCALE;
 
-pub struct MLP {
+pub struct Mlp {
     matmul_up: MatMul<EMBED_SIZE, HIDDEN_SIZE>,
     matmul_down: MatMul<HIDDEN_SIZE, EMBED_SIZE>,
 }
 
-impl MLP {
-    pub fn new(block_num: usize) -> Self {
-        let mut weights_up = vec![vec![0u8; EMBED_SIZE]; HIDDEN_SIZE];
-        let mut weights_down = vec![vec![0u8; HIDDEN_SIZE]; EMBED_SIZE];
+impl Mlp {
+    pub fn new(source: &dyn WeightSource, block_num: usize) -> Result<Self, LoadError> {
+        let mut weights_up = vec![0u8; HIDDEN_SIZE * EMBED_SIZE];
+        let mut weights_down = vec![0u8; EMBED_SIZE * HIDDEN_SIZE];
 
         for i in 0..24 {
-            let path = format!(
-                "weights/weight_files/mlp/mlp_{}.bin",
-                25 + 48 * block_num + i
-            );
-            let mut file = File::open(path).expect("couldn't read file");
-            let mut cur_weights = vec![0u8; 9600];
-            file.read_exact(&mut cur_weights)
-                .expect("couldn't read into buffer");
+            let name = format!("mlp/mlp_{}", 25 + 48 * block_num + i);
+            let cur_weights = source.read(&name, MATRIX_ROM_SIZE)?;
+            let row = |n: usize| &cur_weights[HIDDEN_SIZE * n..HIDDEN_SIZE * (n + 1)];
+
+            let (first, second) = if i % 2 == 0 {
+                (2 * i, 2 * i + 1)
+            } else {
+                (2 * i + 1, 2 * i)
+            };
 
             for j in 0..5 {
-                if i % 2 == 0 {
-                    for k in 0..HIDDEN_SIZE {
-                        weights_down[48 * j + 2 * i][k] = cur_weights[HIDDEN_SIZE * j + k];
-                    }
-                    for k in 0..HIDDEN_SIZE {
-                        weights_down[48 * j + 2 * i + 1][k] =
-                            cur_weights[HIDDEN_SIZE * (j + 5) + k];
-                    }
-                } else {
-                    for k in 0..HIDDEN_SIZE {
-                        weights_down[48 * j + 2 * i + 1][k] = cur_weights[HIDDEN_SIZE * j + k];
-                    }
-                    for k in 0..HIDDEN_SIZE {
-                        weights_down[48 * j + 2 * i][k] = cur_weights[HIDDEN_SIZE * (j + 5) + k];
-                    }
-                }
+                set_row(&mut weights_down, 48 * j + first, row(j));
+                set_row(&mut weights_down, 48 * j + second, row(j + 5));
             }
         }
 
         for i in 0..24 {
-            let path = format!(
-                "weights/weight_files/mlp/mlp_{}.bin",
-                1 + 48 * block_num + i
-            );
-            let mut file = File::open(path).expect("couldn't read file");
-            let mut cur_weights = vec![0u8; 9600];
-            file.read_exact(&mut cur_weights)
-                .expect("couldn't read into buffer");
-
-            for j in 0..20 {
-                if i % 2 == 0 {
-                    for k in 0..EMBED_SIZE {
-                        weights_up[48 * j + 2 * i][k] = cur_weights[EMBED_SIZE * j + k];
-                    }
-                    for k in 0..EMBED_SIZE {
-                        weights_up[48 * j + 2 * i + 1][k] = cur_weights[EMBED_SIZE * (j + 20) + k];
-                    }
-                } else {
-                    for k in 0..EMBED_SIZE {
-                        weights_up[48 * j + 2 * i + 1][k] = cur_weights[EMBED_SIZE * j + k];
-                    }
-                    for k in 0..EMBED_SIZE {
-                        weights_up[48 * j + 2 * i][k] = cur_weights[EMBED_SIZE * (j + 20) + k];
-                    }
-                }
-            }
-        }
-
-        let mut weights_up_array: [[u8; EMBED_SIZE]; HIDDEN_SIZE] = [[0; EMBED_SIZE]; HIDDEN_SIZE];
-        let mut weights_down_array: [[u8; HIDDEN_SIZE]; EMBED_SIZE] =
-            [[0; HIDDEN_SIZE]; EMBED_SIZE];
+            let name = format!("mlp/mlp_{}", 1 + 48 * block_num + i);
+            let cur_weights = source.read(&name, MATRIX_ROM_SIZE)?;
+            let row = |n: usize| &cur_weights[EMBED_SIZE * n..EMBED_SIZE * (n + 1)];
 
-        for i in 0..HIDDEN_SIZE {
-            for j in 0..EMBED_SIZE {
-                weights_up_array[i][j] = weights_up[i][j];
-            }
-        }
+            let (first, second) = if i % 2 == 0 {
+                (2 * i, 2 * i + 1)
+            } else {
+                (2 * i + 1, 2 * i)
+            };
 
-        for i in 0..EMBED_SIZE {
-            for j in 0..HIDDEN_SIZE {
-                weights_down_array[i][j] = weights_down[i][j];
+            for j in 0..20 {
+                set_row(&mut weights_up, 48 * j + first, row(j));
+                set_row(&mut weights_up, 48 * j + second, row(j + 20));
             }
         }
 
-        let matmul_up = MatMul::new(&weights_up_array, true);
-        let matmul_down = MatMul::new(&weights_down_array, false);
+        let matmul_up = MatMul::new(weights_up, true);
+        let matmul_down = MatMul::new(weights_down, false);
 
-        MLP {
+        Ok(Mlp {
             matmul_up,
             matmul_down,
-        }
+        })
     }
 
-    pub fn forward(&self, input: &[Fixed24; EMBED_SIZE]) -> [Fixed24; EMBED_SIZE] {
+    pub fn forward(
+        &self,
+        input: &[Fixed24; EMBED_SIZE],
+        tracer: &mut dyn Tracer,
+    ) -> [Fixed24; EMBED_SIZE] {
         let res = self.matmul_up.forward(input);
+        tracer.record("mlp_hidden", &res);
         self.matmul_down.forward(&res)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ModelPaths;
+    use crate::layout::{Matrix, matrix_rows};
+    use crate::model::LAYERS;
+
+    #[test]
+    fn rom_rows_match_the_loader() {
+        let paths = ModelPaths::default();
+        for block in 0..LAYERS {
+            let mlp = Mlp::new(&paths, block).unwrap();
+            let up = matrix_rows(Matrix::MlpUp { block }, &paths);
+            assert_eq!(up.concat(), mlp.matmul_up.weights(), "block {}", block);
+            let down = matrix_rows(Matrix::MlpDown { block }, &paths);
+            assert_eq!(down.concat(), mlp.matmul_down.weights(), "block {}", block);
+        }
+    }
+}
@@ -1,65 +1,304 @@
 use crate::EMBED_SIZE;
+use crate::attention::{Cache, Slot};
 use crate::block::Block;
+use crate::distribution::Distribution;
 use crate::embedding::Embedding;
+use crate::error::LoadError;
 use crate::layernorm::LayerNorm;
+use crate::trace::{Scoped, Tracer};
 use crate::unembedding::Unembedding;
+use crate::weights::WeightSource;
 
-const LAYERS: usize = 6;
+pub(crate) const LAYERS: usize = 6;
+/// Number of positions the machine has position embeddings for.
+pub const CONTEXT_SIZE: usize = 64;
+
+/// What happens to the attention caches once more than [`CONTEXT_SIZE`]
+/// tokens have been processed.
+///
+/// Either way, every token from then on gets the last position embedding,
+/// as in the original emulator, even though the ring below wraps its slots.
+/// The machine has a token counter at each attention block, and nobody has
+/// run a world far enough to see what they, or the position embeddings, do
+/// past 64 tokens. Clamping the position leaves
+/// [`ContextPolicy::SlidingWindow`] and the ring holding the same 64 tokens,
+/// and attention doesn't depend on their order, so the two policies give the
+/// same predictions and differ only in how the cache is laid out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ContextPolicy {
+    /// Treat the cache as the machine's 64 slots per head, addressed by a
+    /// token counter assumed to wrap around, so token `n` overwrites slot
+    /// `n % CONTEXT_SIZE`. That assumption comes from the slot count alone;
+    /// the name is what the ring is meant to model, not a verified claim.
+    /// Up to 64 tokens, nothing is overwritten. Traced scores and weights are
+    /// in slot order.
+    #[default]
+    Faithful,
+    /// Keep only the most recent tokens, up to this many, dropping the
+    /// oldest. Scores and weights are oldest first.
+    SlidingWindow(usize),
+}
+
+impl ContextPolicy {
+    /// Where the token at `index` is cached.
+    pub(crate) fn slot(self, index: usize) -> Slot {
+        match self {
+            ContextPolicy::Faithful => Slot::Ring(index % CONTEXT_SIZE),
+            ContextPolicy::SlidingWindow(size) => Slot::Window(size),
+        }
+    }
+}
+
+/// Everything a [`Model`] remembers about the tokens it has processed: the
+/// attention caches of every block and the position. Taken with
+/// [`Model::snapshot`] and put back with [`Model::restore`], to branch a
+/// conversation or try several seeds without reprocessing the prompt.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModelState {
+    pub(crate) index: usize,
+    pub(crate) caches: Vec<Cache>,
+}
+
+impl ModelState {
+    /// Number of tokens processed when the snapshot was taken.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
 
 pub struct Model {
     tokens: Embedding,
-    transformer: [Block; LAYERS],
+    transformer: Vec<Block>,
     ln_f: LayerNorm,
     unembedding: Unembedding,
+    policy: ContextPolicy,
     index: usize,
 }
 
 impl Model {
-    pub fn new() -> Self {
-        let tokens = Embedding::new();
-        let transformer = std::array::from_fn(Block::new);
-
-        let ln_f = LayerNorm::new(13);
-        let unembedding = Unembedding::new();
+    /// Loads every ROM of the model from `source`.
+    pub fn new(source: &dyn WeightSource) -> Result<Self, LoadError> {
+        let tokens = Embedding::new(source)?;
+        let transformer = (0..LAYERS)
+            .map(|i| Block::new(source, i))
+            .collect::<Result<_, _>>()?;
 
-        println!("Model loaded.");
+        let ln_f = LayerNorm::new(source, 13)?;
+        let unembedding = Unembedding::new(source)?;
 
-        Model {
+        Ok(Model {
             tokens,
             transformer,
             ln_f,
             unembedding,
+            policy: ContextPolicy::default(),
             index: 0,
-        }
+        })
+    }
+
+    pub fn context_policy(&self) -> ContextPolicy {
+        self.policy
     }
 
+    /// # Panics
+    ///
+    /// If the sliding window has room for no tokens at all.
+    pub fn set_context_policy(&mut self, policy: ContextPolicy) {
+        assert!(
+            policy != ContextPolicy::SlidingWindow(0),
+            "the sliding window needs room for at least one token"
+        );
+        self.policy = policy;
+    }
+
+    /// Number of tokens processed so far.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Feeds `token` through the network and returns the machine's top
+    /// tokens, packed as `(probability << 11) | token`.
     pub fn process(&mut self, token: usize) -> Vec<u64> {
-        let pos = if self.index < 64 {
-            Some(self.index)
-        } else {
-            Some(63)
-        };
+        self.predict(token).packed().to_vec()
+    }
+
+    /// Like [`Model::process`], but returns the whole [`Distribution`].
+    pub fn predict(&mut self, token: usize) -> Distribution {
+        self.predict_traced(token, &mut ())
+    }
+
+    /// Like [`Model::predict`], but passes the activations between layers to
+    /// `tracer`; see [`crate::trace`] for what gets recorded.
+    pub fn predict_traced(&mut self, token: usize, tracer: &mut dyn Tracer) -> Distribution {
+        tracer.begin(token, self.index);
+        let pos = Some(self.index.min(CONTEXT_SIZE - 1));
+        let slot = self.policy.slot(self.index);
         let weights_vec = self.tokens.get_weights(token, pos);
         let mut value = [0u32; EMBED_SIZE];
         for (i, &w) in weights_vec.iter().enumerate() {
             value[i] = w;
         }
 
-        for block in &mut self.transformer {
-            block.forward(&mut value);
+        tracer.record("embedding", &value);
+
+        for (i, block) in self.transformer.iter_mut().enumerate() {
+            block.forward(&mut value, slot, &mut Scoped::new(tracer, "block", i));
+            if tracer.enabled() {
+                tracer.record(&format!("block{}", i), &value);
+            }
         }
 
         let value = self.ln_f.forward(&value);
-        let ans = self.unembedding.forward(&value);
+        tracer.record("ln_f", &value);
+        let logits = self.unembedding.logits(&value);
+        tracer.record("logits", &logits);
         self.index += 1;
-        ans
+        self.unembedding.distribution(logits)
+    }
+
+    pub fn snapshot(&self) -> ModelState {
+        ModelState {
+            index: self.index,
+            caches: self.transformer.iter().map(|b| b.cache().clone()).collect(),
+        }
+    }
+
+    pub fn restore(&mut self, state: &ModelState) {
+        self.index = state.index;
+        for (block, cache) in self.transformer.iter_mut().zip(&state.caches) {
+            block.cache_mut().clone_from(cache);
+        }
+    }
+
+    /// How many of the last tokens [`Model::undo`] can take back. Once the
+    /// caches are full each token overwrites an older one, which is lost, so
+    /// from then on only a snapshot can go back.
+    pub fn undoable(&self) -> usize {
+        let capacity = match self.policy {
+            ContextPolicy::Faithful => CONTEXT_SIZE,
+            ContextPolicy::SlidingWindow(size) => size,
+        };
+        if self.index <= capacity {
+            self.index
+        } else {
+            0
+        }
     }
 
-    #[allow(dead_code)]
-    pub fn undo_last(&mut self) {
-        self.index -= 1;
+    /// Forgets the last `tokens` tokens, as if they had never been processed.
+    ///
+    /// # Panics
+    ///
+    /// If that is more than [`Model::undoable`].
+    pub fn undo(&mut self, tokens: usize) {
+        assert!(tokens <= self.undoable(), "can't undo {} tokens", tokens);
+        self.index -= tokens;
         for block in &mut self.transformer {
-            block.undo_last();
+            block.cache_mut().truncate(self.index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attention::HEAD_SIZE;
+
+    /// A cache that has seen `tokens` tokens under `policy`, each token's
+    /// key and value filled with its index.
+    fn cache(policy: ContextPolicy, tokens: usize) -> Cache {
+        let mut cache = Cache::new();
+        for index in 0..tokens {
+            let entry = [index as u16; HEAD_SIZE];
+            cache.store(0, policy.slot(index), entry, entry);
         }
+        cache
+    }
+
+    /// The token in each slot of the cache, in slot order.
+    fn slots(cache: &Cache) -> Vec<usize> {
+        cache.keys[0].iter().map(|key| key[0] as usize).collect()
+    }
+
+    #[test]
+    fn both_policies_stop_growing_at_the_context_size() {
+        for policy in [
+            ContextPolicy::Faithful,
+            ContextPolicy::SlidingWindow(CONTEXT_SIZE),
+        ] {
+            for (tokens, len) in [(63, 63), (64, 64), (65, 64), (1024, 64), (1025, 64)] {
+                assert_eq!(cache(policy, tokens).len(), len, "{:?}, {}", policy, tokens);
+            }
+        }
+        assert_eq!(cache(ContextPolicy::SlidingWindow(10), 65).len(), 10);
+    }
+
+    #[test]
+    fn faithful_overwrites_slot_n_mod_64() {
+        assert_eq!(
+            slots(&cache(ContextPolicy::Faithful, 64)),
+            (0..64).collect::<Vec<_>>()
+        );
+
+        let expected: Vec<usize> = [64, 65].into_iter().chain(2..64).collect();
+        assert_eq!(slots(&cache(ContextPolicy::Faithful, 66)), expected);
+
+        // Token 1024 wraps around to slot 0 like any other multiple of 64.
+        let around: Vec<usize> = [1024, 961].into_iter().chain(962..1024).collect();
+        assert_eq!(slots(&cache(ContextPolicy::Faithful, 1025)), around);
+
+        let slots = slots(&cache(ContextPolicy::Faithful, 1100));
+        assert!(
+            slots
+                .iter()
+                .enumerate()
+                .all(|(slot, &n)| n % CONTEXT_SIZE == slot)
+        );
+        assert!(slots.iter().all(|&n| n >= 1100 - CONTEXT_SIZE));
+    }
+
+    #[test]
+    fn sliding_window_evicts_the_oldest() {
+        let window = ContextPolicy::SlidingWindow(CONTEXT_SIZE);
+        assert_eq!(slots(&cache(window, 65)), (1..65).collect::<Vec<_>>());
+        assert_eq!(
+            slots(&cache(window, 1100)),
+            (1036..1100).collect::<Vec<_>>()
+        );
+        assert_eq!(slots(&cache(ContextPolicy::SlidingWindow(3), 5)), [2, 3, 4]);
+    }
+
+    #[test]
+    fn the_window_holds_the_same_tokens_as_the_ring() {
+        let window = ContextPolicy::SlidingWindow(CONTEXT_SIZE);
+        for tokens in [10, 64, 100, 1023, 1024, 1025, 1100] {
+            let mut ring = slots(&cache(ContextPolicy::Faithful, tokens));
+            ring.sort();
+            assert_eq!(ring, slots(&cache(window, tokens)));
+        }
+    }
+
+    #[test]
+    fn tokens_past_1024_are_processed_like_those_past_64() {
+        let paths = crate::ModelPaths::default();
+        let tokens = [1, 14, 6, 13];
+        let run = |policy, start| {
+            let mut model = Model::new(&paths).unwrap();
+            model.set_context_policy(policy);
+            // Start with empty caches at `start`, as if the earlier tokens
+            // had been forgotten.
+            model.index = start;
+            let packed: Vec<Vec<u64>> = tokens.iter().map(|&t| model.process(t)).collect();
+            (packed, model.transformer[0].cache().len(), model.index())
+        };
+
+        let expected = run(ContextPolicy::Faithful, 64);
+        assert_eq!((expected.1, expected.2), (4, 68));
+        // The ring fills slots 0 to 3 from token 1024 just as from token 64;
+        // the window doesn't look at the index at all.
+        let ring = run(ContextPolicy::Faithful, 1024);
+        assert_eq!(ring, (expected.0.clone(), 4, 1028));
+        let window = run(ContextPolicy::SlidingWindow(CONTEXT_SIZE), 1022);
+        assert_eq!(window, (expected.0, 4, 1026));
     }
 }
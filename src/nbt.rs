@@ -0,0 +1,240 @@
+//! Minecraft's Named Binary Tag format, as used by schematics and region
+//! files. Compression is left to the caller.
+
+use std::io::{self, Read, Write};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<u8>),
+    String(String),
+    List(Vec<Tag>),
+    Compound(Vec<(String, Tag)>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Tag {
+    fn id(&self) -> u8 {
+        match self {
+            Tag::Byte(_) => 1,
+            Tag::Short(_) => 2,
+            Tag::Int(_) => 3,
+            Tag::Long(_) => 4,
+            Tag::Float(_) => 5,
+            Tag::Double(_) => 6,
+            Tag::ByteArray(_) => 7,
+            Tag::String(_) => 8,
+            Tag::List(_) => 9,
+            Tag::Compound(_) => 10,
+            Tag::IntArray(_) => 11,
+            Tag::LongArray(_) => 12,
+        }
+    }
+
+    /// Looks up `key` in a compound.
+    pub fn get(&self, key: &str) -> Option<&Tag> {
+        match self {
+            Tag::Compound(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// The value of any integer tag, widened.
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Tag::Byte(v) => Some(v as i64),
+            Tag::Short(v) => Some(v as i64),
+            Tag::Int(v) => Some(v as i64),
+            Tag::Long(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Tag::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Tag]> {
+        match self {
+            Tag::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_compound(&self) -> Option<&[(String, Tag)]> {
+        match self {
+            Tag::Compound(entries) => Some(entries),
+            _ => None,
+        }
+    }
+}
+
+/// Writes `tag` as the root tag named `name`.
+pub fn write<W: Write>(w: &mut W, name: &str, tag: &Tag) -> io::Result<()> {
+    w.write_all(&[tag.id()])?;
+    write_string(w, name)?;
+    write_payload(w, tag)
+}
+
+/// Reads a root tag and its name.
+pub fn read<R: Read>(r: &mut R) -> io::Result<(String, Tag)> {
+    let id = read_u8(r)?;
+    let name = read_string(r)?;
+    let tag = read_payload(r, id, 0)?;
+    Ok((name, tag))
+}
+
+fn write_string<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    // Real NBT uses modified UTF-8, which only differs for NUL and characters
+    // outside the BMP; block names never contain either.
+    w.write_all(&(s.len() as u16).to_be_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+fn write_payload<W: Write>(w: &mut W, tag: &Tag) -> io::Result<()> {
+    match tag {
+        Tag::Byte(v) => w.write_all(&v.to_be_bytes()),
+        Tag::Short(v) => w.write_all(&v.to_be_bytes()),
+        Tag::Int(v) => w.write_all(&v.to_be_bytes()),
+        Tag::Long(v) => w.write_all(&v.to_be_bytes()),
+        Tag::Float(v) => w.write_all(&v.to_be_bytes()),
+        Tag::Double(v) => w.write_all(&v.to_be_bytes()),
+        Tag::ByteArray(v) => {
+            w.write_all(&(v.len() as i32).to_be_bytes())?;
+            w.write_all(v)
+        }
+        Tag::String(s) => write_string(w, s),
+        Tag::List(items) => {
+            let id = items.first().map_or(0, Tag::id);
+            w.write_all(&[id])?;
+            w.write_all(&(items.len() as i32).to_be_bytes())?;
+            for item in items {
+                if item.id() != id {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "NBT list items must all have the same type",
+                    ));
+                }
+                write_payload(w, item)?;
+            }
+            Ok(())
+        }
+        Tag::Compound(entries) => {
+            for (name, tag) in entries {
+                write(w, name, tag)?;
+            }
+            w.write_all(&[0])
+        }
+        Tag::IntArray(v) => {
+            w.write_all(&(v.len() as i32).to_be_bytes())?;
+            for x in v {
+                w.write_all(&x.to_be_bytes())?;
+            }
+            Ok(())
+        }
+        Tag::LongArray(v) => {
+            w.write_all(&(v.len() as i32).to_be_bytes())?;
+            for x in v {
+                w.write_all(&x.to_be_bytes())?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_bytes<R: Read, const N: usize>(r: &mut R) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    Ok(read_bytes::<R, 1>(r)?[0])
+}
+
+fn read_len<R: Read>(r: &mut R) -> io::Result<usize> {
+    let len = i32::from_be_bytes(read_bytes(r)?);
+    usize::try_from(len).map_err(|_| invalid("negative NBT length"))
+}
+
+fn read_string<R: Read>(r: &mut R) -> io::Result<String> {
+    let len = u16::from_be_bytes(read_bytes(r)?) as usize;
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| invalid("NBT string is not UTF-8"))
+}
+
+fn read_payload<R: Read>(r: &mut R, id: u8, depth: usize) -> io::Result<Tag> {
+    if depth > 512 {
+        return Err(invalid("NBT is nested too deeply"));
+    }
+    Ok(match id {
+        1 => Tag::Byte(i8::from_be_bytes(read_bytes(r)?)),
+        2 => Tag::Short(i16::from_be_bytes(read_bytes(r)?)),
+        3 => Tag::Int(i32::from_be_bytes(read_bytes(r)?)),
+        4 => Tag::Long(i64::from_be_bytes(read_bytes(r)?)),
+        5 => Tag::Float(f32::from_be_bytes(read_bytes(r)?)),
+        6 => Tag::Double(f64::from_be_bytes(read_bytes(r)?)),
+        7 => {
+            let len = read_len(r)?;
+            let mut buf = Vec::new();
+            r.take(len as u64).read_to_end(&mut buf)?;
+            if buf.len() != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            Tag::ByteArray(buf)
+        }
+        8 => Tag::String(read_string(r)?),
+        9 => {
+            let item_id = read_u8(r)?;
+            let len = read_len(r)?;
+            let mut items = Vec::new();
+            for _ in 0..len {
+                items.push(read_payload(r, item_id, depth + 1)?);
+            }
+            Tag::List(items)
+        }
+        10 => {
+            let mut entries = Vec::new();
+            loop {
+                let id = read_u8(r)?;
+                if id == 0 {
+                    break;
+                }
+                let name = read_string(r)?;
+                entries.push((name, read_payload(r, id, depth + 1)?));
+            }
+            Tag::Compound(entries)
+        }
+        11 => {
+            let len = read_len(r)?;
+            let mut v = Vec::new();
+            for _ in 0..len {
+                v.push(i32::from_be_bytes(read_bytes(r)?));
+            }
+            Tag::IntArray(v)
+        }
+        12 => {
+            let len = read_len(r)?;
+            let mut v = Vec::new();
+            for _ in 0..len {
+                v.push(i64::from_be_bytes(read_bytes(r)?));
+            }
+            Tag::LongArray(v)
+        }
+        _ => return Err(invalid("unknown NBT tag type")),
+    })
+}
@@ -1,3 +1,4 @@
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct PRNG {
     seed: u32,
 }
@@ -7,6 +8,16 @@ impl PRNG {
         PRNG { seed }
     }
 
+    /// The current register value; `PRNG::new(rng.state())` carries on
+    /// exactly where `rng` is.
+    pub fn state(&self) -> u32 {
+        self.seed
+    }
+
+    /// Clocks the register 256 times and returns its new value. Not an
+    /// [`Iterator`], since the sequence never ends and callers only ever want
+    /// one value at a time.
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> u32 {
         for _ in 0..256 {
             let next_bit = ((self.seed >> 22) & 1) ^ ((self.seed >> 17) & 1);
@@ -0,0 +1,435 @@
+//! A plain f32 forward pass of the same network, for measuring how far the
+//! machine's fixed-point arithmetic drifts from the real thing.
+//!
+//! The weights are dequantized from the same ROMs, and the architecture
+//! follows the machine rather than stock GPT-2 where the two differ: there
+//! are no biases, the MLP uses ReLU, the layer norms have no shift, attention
+//! scores are scaled by `1/sqrt(EMBED_SIZE)`, and positions past the last
+//! position embedding reuse it.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use crate::attention::{HEAD_SIZE, HEADS};
+use crate::error::LoadError;
+use crate::layout::Matrix;
+use crate::matmul::{MATMUL_FIXED_POINT, dequantize};
+use crate::model::LAYERS;
+use crate::trace::ProcessTrace;
+use crate::weights::{
+    EMBEDDING_ROM_SIZE, LAYERNORM_ROM_SIZE, MATRIX_ROM_SIZE, WeightSource, u24_words,
+};
+use crate::{
+    CONTEXT_SIZE, EMBED_SIZE, FIXED_POINT_MASK, FIXED_POINT_SIZE, Fixed24, Model, VOCAB_SIZE,
+};
+
+const EPS: f32 = 1e-5;
+
+/// The real value of a fixed-point activation.
+pub fn to_f32(x: Fixed24) -> f32 {
+    let x = x & FIXED_POINT_MASK;
+    let signed = ((x << (32 - FIXED_POINT_SIZE)) as i32) >> (32 - FIXED_POINT_SIZE);
+    signed as f32 / (1 << MATMUL_FIXED_POINT) as f32
+}
+
+/// Reads matrix rows, keeping each ROM around once it has been read.
+struct Rows<'a> {
+    source: &'a dyn WeightSource,
+    roms: HashMap<String, Cow<'a, [u8]>>,
+}
+
+impl<'a> Rows<'a> {
+    fn bytes(&mut self, matrix: Matrix, row: usize) -> Result<&[u8], LoadError> {
+        let rom = matrix.rom_row(row).expect("row out of range");
+        if !self.roms.contains_key(&rom.name) {
+            let size = match matrix {
+                Matrix::Wte | Matrix::Wpe => EMBEDDING_ROM_SIZE,
+                Matrix::Layernorm(_) => LAYERNORM_ROM_SIZE,
+                _ => MATRIX_ROM_SIZE,
+            };
+            let bytes = self.source.read(&rom.name, size)?;
+            self.roms.insert(rom.name.clone(), bytes);
+        }
+        Ok(&self.roms[&rom.name][rom.offset..rom.offset + rom.len])
+    }
+
+    /// A matrix of weight bytes, one output per row.
+    fn linear(&mut self, matrix: Matrix) -> Result<Linear, LoadError> {
+        let mut weights = Vec::new();
+        for row in 0..matrix.rows() {
+            weights.extend(self.bytes(matrix, row)?.iter().map(|&w| dequantize(w)));
+        }
+        Ok(Linear {
+            inputs: weights.len() / matrix.rows(),
+            weights,
+        })
+    }
+
+    fn heads<F: Fn(usize) -> Matrix>(&mut self, matrix: F) -> Result<Vec<Linear>, LoadError> {
+        (0..HEADS).map(|head| self.linear(matrix(head))).collect()
+    }
+
+    fn layernorm(&mut self, n: usize, scale: f32) -> Result<Vec<f32>, LoadError> {
+        Ok(self.words(Matrix::Layernorm(n), scale)?.remove(0))
+    }
+
+    /// A table of 24-bit words, one entry per row.
+    fn words(&mut self, matrix: Matrix, scale: f32) -> Result<Vec<Vec<f32>>, LoadError> {
+        (0..matrix.rows())
+            .map(|row| {
+                let bytes = self.bytes(matrix, row)?;
+                Ok(u24_words(bytes)
+                    .map(|w| scale_word(matrix, w) / scale)
+                    .collect())
+            })
+            .collect()
+    }
+}
+
+/// Embedding words are 18-bit two's complement; layer norm weights are
+/// unsigned.
+fn scale_word(matrix: Matrix, w: u32) -> f32 {
+    match matrix {
+        Matrix::Wte | Matrix::Wpe => (((w << 14) as i32) >> 14) as f32,
+        _ => w as f32,
+    }
+}
+
+struct Linear {
+    inputs: usize,
+    weights: Vec<f32>,
+}
+
+impl Linear {
+    fn forward(&self, input: &[f32]) -> Vec<f32> {
+        assert_eq!(input.len(), self.inputs);
+        self.weights
+            .chunks_exact(self.inputs)
+            .map(|row| row.iter().zip(input).map(|(w, x)| w * x).sum())
+            .collect()
+    }
+}
+
+struct Block {
+    ln_1: Vec<f32>,
+    query: Vec<Linear>,
+    key: Vec<Linear>,
+    value: Vec<Linear>,
+    proj: Linear,
+    ln_2: Vec<f32>,
+    mlp_up: Linear,
+    mlp_down: Linear,
+    k_cache: Vec<Vec<Vec<f32>>>,
+    v_cache: Vec<Vec<Vec<f32>>>,
+}
+
+impl Block {
+    fn forward(&mut self, x: &mut [f32]) {
+        let h = layer_norm(x, &self.ln_1);
+        let mut heads = Vec::with_capacity(EMBED_SIZE);
+        for head in 0..HEADS {
+            let q = self.query[head].forward(&h);
+            self.k_cache[head].push(self.key[head].forward(&h));
+            self.v_cache[head].push(self.value[head].forward(&h));
+
+            let scale = 1.0 / (EMBED_SIZE as f32).sqrt();
+            let scores: Vec<f32> = self.k_cache[head]
+                .iter()
+                .map(|k| scale * k.iter().zip(&q).map(|(a, b)| a * b).sum::<f32>())
+                .collect();
+            let weights = softmax(&scores);
+
+            let mut out = vec![0.0; HEAD_SIZE];
+            for (p, v) in weights.iter().zip(&self.v_cache[head]) {
+                for (o, &v) in out.iter_mut().zip(v) {
+                    *o += p * v;
+                }
+            }
+            heads.extend(out);
+        }
+        for (x, d) in x.iter_mut().zip(self.proj.forward(&heads)) {
+            *x += d;
+        }
+
+        let h = layer_norm(x, &self.ln_2);
+        let mut hidden = self.mlp_up.forward(&h);
+        for v in &mut hidden {
+            *v = v.max(0.0);
+        }
+        for (x, d) in x.iter_mut().zip(self.mlp_down.forward(&hidden)) {
+            *x += d;
+        }
+    }
+}
+
+/// The activations for one token, from [`FloatModel::process`], for lining
+/// up against a [`crate::trace::ProcessTrace`] of the emulator.
+pub struct FloatLayers {
+    /// The residual stream after the embedding and after each block.
+    pub residual: Vec<Vec<f32>>,
+    /// The output of the final layer norm.
+    pub ln_f: Vec<f32>,
+    pub logits: Vec<f32>,
+    pub probs: Vec<f32>,
+}
+
+pub struct FloatModel {
+    wte: Vec<Vec<f32>>,
+    wpe: Vec<Vec<f32>>,
+    blocks: Vec<Block>,
+    ln_f: Vec<f32>,
+    lm_head: Linear,
+    index: usize,
+}
+
+impl FloatModel {
+    /// Loads and dequantizes every ROM of the model from `source`.
+    pub fn new(source: &dyn WeightSource) -> Result<Self, LoadError> {
+        let mut rows = Rows {
+            source,
+            roms: HashMap::new(),
+        };
+        let activation = (1 << MATMUL_FIXED_POINT) as f32;
+        // The layer norm circuit halves each weight and shifts by 3 more than
+        // the activations.
+        let gain = (1 << (MATMUL_FIXED_POINT + 4)) as f32;
+
+        let mut blocks = Vec::with_capacity(LAYERS);
+        for block in 0..LAYERS {
+            blocks.push(Block {
+                ln_1: rows.layernorm(2 * block + 1, gain)?,
+                query: rows.heads(|head| Matrix::Query { block, head })?,
+                key: rows.heads(|head| Matrix::Key { block, head })?,
+                value: rows.heads(|head| Matrix::Value { block, head })?,
+                proj: rows.linear(Matrix::AttentionProj { block })?,
+                ln_2: rows.layernorm(2 * block + 2, gain)?,
+                mlp_up: rows.linear(Matrix::MlpUp { block })?,
+                mlp_down: rows.linear(Matrix::MlpDown { block })?,
+                k_cache: vec![Vec::new(); HEADS],
+                v_cache: vec![Vec::new(); HEADS],
+            });
+        }
+
+        Ok(FloatModel {
+            wte: rows.words(Matrix::Wte, activation)?,
+            wpe: rows.words(Matrix::Wpe, activation)?,
+            blocks,
+            ln_f: rows.layernorm(2 * LAYERS + 1, gain)?,
+            lm_head: rows.linear(Matrix::LmHead)?,
+            index: 0,
+        })
+    }
+
+    /// Feeds one token through the network and returns every layer's output.
+    pub fn process(&mut self, token: usize) -> FloatLayers {
+        let pos = self.index.min(CONTEXT_SIZE - 1);
+        let mut x: Vec<f32> = self.wte[token]
+            .iter()
+            .zip(&self.wpe[pos])
+            .map(|(a, b)| a + b)
+            .collect();
+
+        let mut residual = vec![x.clone()];
+        for block in &mut self.blocks {
+            block.forward(&mut x);
+            residual.push(x.clone());
+        }
+
+        let ln_f = layer_norm(&x, &self.ln_f);
+        let logits = self.lm_head.forward(&ln_f);
+        let probs = softmax(&logits);
+        self.index += 1;
+
+        FloatLayers {
+            residual,
+            ln_f,
+            logits,
+            probs,
+        }
+    }
+}
+
+/// Largest and mean absolute difference between two layers' outputs, summed
+/// over every token.
+#[derive(Clone, Debug, Default)]
+pub struct LayerError {
+    pub max: f32,
+    pub sum: f64,
+    pub count: usize,
+}
+
+impl LayerError {
+    pub fn add(&mut self, fixed: &[f32], float: &[f32]) {
+        for (a, b) in fixed.iter().zip(float) {
+            let diff = (a - b).abs();
+            self.max = self.max.max(diff);
+            self.sum += diff as f64;
+            self.count += 1;
+        }
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count.max(1) as f64
+    }
+}
+
+/// How far the emulator drifted from the [`FloatModel`] over some tokens.
+#[derive(Clone, Debug)]
+pub struct Drift {
+    /// The error at the embedding, after each block, at `ln_f`, in the
+    /// logits and in the machine's top-8 probabilities, by name.
+    pub layers: Vec<(String, LayerError)>,
+    /// How many tokens both models predicted the same most likely next token
+    /// after.
+    pub agree: usize,
+    pub tokens: usize,
+}
+
+/// Feeds `ids` through both models side by side and measures how far the
+/// fixed-point activations are from the real ones.
+pub fn compare(model: &mut Model, float: &mut FloatModel, ids: &[usize]) -> Drift {
+    let mut layers: Vec<(String, LayerError)> = Vec::new();
+    let mut agree = 0;
+
+    for &token in ids {
+        let mut trace = ProcessTrace::default();
+        let dist = model.predict_traced(token, &mut trace);
+        let fixed = &trace.tokens[0];
+        let float = float.process(token);
+        let dequantize = |name: &str| -> Vec<f32> {
+            fixed
+                .get(name)
+                .unwrap()
+                .iter()
+                .map(|&v| to_f32(v))
+                .collect()
+        };
+
+        let mut outputs: Vec<(String, Vec<f32>, Vec<f32>)> = Vec::new();
+        for (i, b) in float.residual.into_iter().enumerate() {
+            let name = match i {
+                0 => "embedding".to_string(),
+                i => format!("block{}", i - 1),
+            };
+            outputs.push((name.clone(), dequantize(&name), b));
+        }
+        outputs.push(("ln_f".to_string(), dequantize("ln_f"), float.ln_f));
+        outputs.push(("logits".to_string(), dequantize("logits"), float.logits));
+        // Only the top 8 probabilities come out of the machine.
+        outputs.push((
+            "top-8 probs".to_string(),
+            dist.top().map(|c| c.probability() as f32).collect(),
+            dist.top().map(|c| float.probs[c.token]).collect(),
+        ));
+
+        if layers.is_empty() {
+            layers = outputs
+                .iter()
+                .map(|(name, _, _)| (name.clone(), LayerError::default()))
+                .collect();
+        }
+        for ((_, error), (_, a, b)) in layers.iter_mut().zip(&outputs) {
+            error.add(a, b);
+        }
+
+        let best = (0..VOCAB_SIZE)
+            .max_by(|&a, &b| float.probs[a].total_cmp(&float.probs[b]))
+            .unwrap();
+        if dist.top().next().map(|c| c.token) == Some(best) {
+            agree += 1;
+        }
+    }
+
+    Drift {
+        layers,
+        agree,
+        tokens: ids.len(),
+    }
+}
+
+fn layer_norm(x: &[f32], gain: &[f32]) -> Vec<f32> {
+    let n = x.len() as f32;
+    let mean = x.iter().sum::<f32>() / n;
+    let var = x.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / n;
+    let inv = 1.0 / (var + EPS).sqrt();
+    x.iter()
+        .zip(gain)
+        .map(|(v, g)| (v - mean) * inv * g)
+        .collect()
+}
+
+fn softmax(x: &[f32]) -> Vec<f32> {
+    let max = x.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exp: Vec<f32> = x.iter().map(|v| (v - max).exp()).collect();
+    let sum: f32 = exp.iter().sum();
+    exp.iter().map(|e| e / sum).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChatTemplate, Model, ModelPaths, Tokenizer};
+
+    #[test]
+    fn to_f32_sign_extends_24_bits() {
+        assert_eq!(to_f32(0), 0.0);
+        assert_eq!(to_f32(1 << 18), 1.0);
+        assert_eq!(to_f32(3 << 17), 1.5);
+        assert_eq!(to_f32(0xffffff), -1.0 / (1 << 18) as f32);
+        assert_eq!(to_f32((1 << 24) - (1 << 18)), -1.0);
+        assert_eq!(to_f32(0x7fffff), 32.0 - 1.0 / (1 << 18) as f32);
+        assert_eq!(to_f32(0x800000), -32.0);
+        // Bits above the 24 are ignored.
+        assert_eq!(to_f32(0xff00_0000 | (1 << 18)), 1.0);
+        assert_eq!(to_f32(0x0100_0000 | 0xffffff), to_f32(0xffffff));
+    }
+
+    #[test]
+    fn agrees_with_the_emulator_on_the_top_token() {
+        let paths = ModelPaths::default();
+        let tokenizer = Tokenizer::load(&paths.tokens).unwrap();
+        let template = ChatTemplate::new(&tokenizer).unwrap();
+        let prompt = template.user_turn(&tokenizer, "hello there").unwrap();
+
+        let mut model = Model::new(&paths).unwrap();
+        let mut float = FloatModel::new(&paths).unwrap();
+        for &token in &prompt {
+            let fixed = model.predict(token).top().next().unwrap().token;
+            let probs = float.process(token).probs;
+            let best = (0..probs.len())
+                .max_by(|&a, &b| probs[a].total_cmp(&probs[b]))
+                .unwrap();
+            assert_eq!(best, fixed, "after token {}", token);
+        }
+    }
+
+    #[test]
+    fn measures_every_layer() {
+        let paths = ModelPaths::default();
+        let mut model = Model::new(&paths).unwrap();
+        let mut float = FloatModel::new(&paths).unwrap();
+        let drift = compare(&mut model, &mut float, &[1, 14]);
+
+        let names: Vec<&str> = drift.layers.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names[0], "embedding");
+        assert_eq!(
+            names[1..7],
+            ["block0", "block1", "block2", "block3", "block4", "block5"]
+        );
+        assert_eq!(names[7..], ["ln_f", "logits", "top-8 probs"]);
+        let (_, logits) = &drift.layers[8];
+        assert_eq!(logits.count, 2 * VOCAB_SIZE);
+        assert!(logits.mean() <= logits.max as f64);
+        assert_eq!((drift.agree, drift.tokens), (2, 2));
+    }
+
+    #[test]
+    fn layer_error_tracks_the_worst_and_the_mean() {
+        let mut error = LayerError::default();
+        assert_eq!(error.mean(), 0.0);
+        error.add(&[1.0, 2.0], &[1.5, 2.0]);
+        error.add(&[0.0], &[-1.0]);
+        assert_eq!((error.max, error.count), (1.0, 3));
+        assert_eq!(error.mean(), 0.5);
+    }
+}
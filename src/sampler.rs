@@ -0,0 +1,394 @@
+//! Picking the next token from a [`Distribution`].
+//!
+//! [`RedstoneSampler`] is what the machine does, and is the only one whose
+//! output a world can reproduce. The others are for seeing what better
+//! decoding would make of the same model; they draw their randomness from
+//! the same [`PRNG`], so a seed still gives the same tokens every time.
+
+use crate::distribution::Distribution;
+use crate::prng::PRNG;
+
+/// Probabilities below this are never sampled by the machine.
+const MIN_PROB: u64 = 1 << 20;
+
+/// A token picked by a [`Sampler`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pick {
+    pub token: usize,
+    /// The value the sampler got from the [`PRNG`], if it drew one.
+    pub drawn: Option<u32>,
+}
+
+pub trait Sampler {
+    fn sample(&mut self, dist: &Distribution, rng: &mut PRNG) -> Pick;
+}
+
+/// Picks the next token from the top-8 output of [`crate::Model::process`]
+/// the same way the machine does: draw a random number, walk the candidates
+/// from least to most likely subtracting their probabilities, and fall back to
+/// the most likely one.
+pub fn sample(act: &[u64], rng: &mut PRNG) -> usize {
+    walk(act, rng.next())
+}
+
+/// The walk of [`sample`], given the number drawn.
+fn walk(act: &[u64], drawn: u32) -> usize {
+    let mut cur = drawn as i32;
+
+    for j in (1..act.len()).rev() {
+        if (act[j] >> 11) < MIN_PROB {
+            continue;
+        }
+        cur -= (act[j] >> 11) as i32;
+        if cur < 0 {
+            return (act[j] & 2047) as usize;
+        }
+    }
+
+    (act[0] & 2047) as usize
+}
+
+/// The machine's sampler; see [`sample`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RedstoneSampler;
+
+impl Sampler for RedstoneSampler {
+    fn sample(&mut self, dist: &Distribution, rng: &mut PRNG) -> Pick {
+        let drawn = rng.next();
+        Pick {
+            token: walk(dist.packed(), drawn),
+            drawn: Some(drawn),
+        }
+    }
+}
+
+/// Always picks the most likely token, without touching the PRNG.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Greedy;
+
+impl Sampler for Greedy {
+    fn sample(&mut self, dist: &Distribution, _rng: &mut PRNG) -> Pick {
+        Pick {
+            token: dist.ranked().next().map_or(0, |(id, _)| id),
+            drawn: None,
+        }
+    }
+}
+
+/// Samples from the whole vocabulary, after reshaping the distribution with
+/// a temperature and then cutting it down with any of the usual filters.
+#[derive(Clone, Copy, Debug)]
+pub struct FullSampler {
+    /// Probabilities are raised to `1 / temperature` before sampling.
+    pub temperature: f64,
+    /// Keep only this many of the most likely tokens.
+    pub top_k: Option<usize>,
+    /// Keep the fewest most likely tokens whose probabilities add up to this.
+    pub top_p: Option<f64>,
+    /// Keep only tokens at least this fraction as likely as the most likely.
+    pub min_p: Option<f64>,
+}
+
+impl Default for FullSampler {
+    fn default() -> Self {
+        FullSampler {
+            temperature: 1.0,
+            top_k: None,
+            top_p: None,
+            min_p: None,
+        }
+    }
+}
+
+impl FullSampler {
+    /// The tokens left after the temperature and filters, most likely first,
+    /// with their reshaped probabilities. Empty if every token has
+    /// probability 0.
+    fn candidates(&self, dist: &Distribution) -> Vec<(usize, f64)> {
+        let mut candidates: Vec<(usize, f64)> = dist
+            .ranked()
+            .map(|(id, p)| (id, p.powf(1.0 / self.temperature)))
+            .take_while(|&(_, p)| p > 0.0)
+            .collect();
+        if candidates.is_empty() {
+            return candidates;
+        }
+        let total: f64 = candidates.iter().map(|&(_, p)| p).sum();
+        for (_, p) in &mut candidates {
+            *p /= total;
+        }
+
+        if let Some(k) = self.top_k {
+            candidates.truncate(k.max(1));
+        }
+        if let Some(min_p) = self.min_p {
+            let cutoff = candidates[0].1 * min_p;
+            candidates.retain(|&(_, p)| p >= cutoff);
+        }
+        if let Some(top_p) = self.top_p {
+            let mut sum = 0.0;
+            let keep = candidates
+                .iter()
+                .position(|&(_, p)| {
+                    sum += p;
+                    sum >= top_p
+                })
+                .map_or(candidates.len(), |i| i + 1);
+            candidates.truncate(keep);
+        }
+        candidates
+    }
+}
+
+impl Sampler for FullSampler {
+    fn sample(&mut self, dist: &Distribution, rng: &mut PRNG) -> Pick {
+        let candidates = self.candidates(dist);
+        if candidates.is_empty() {
+            return Greedy.sample(dist, rng);
+        }
+
+        let total: f64 = candidates.iter().map(|&(_, p)| p).sum();
+        let drawn = rng.next();
+        let mut cur = drawn as f64 / (1u64 << 23) as f64 * total;
+        let token = candidates
+            .iter()
+            .find(|&&(_, p)| {
+                cur -= p;
+                cur < 0.0
+            })
+            .unwrap_or(&candidates[candidates.len() - 1])
+            .0;
+        Pick {
+            token,
+            drawn: Some(drawn),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VOCAB_SIZE;
+
+    /// A distribution whose machine view gives `probs` to tokens 10, 11, ...
+    /// and whose full view has them in the same order, each more likely than
+    /// the one before.
+    fn distribution(probs: &[u32]) -> Distribution {
+        let mut logits = [0; VOCAB_SIZE];
+        let mut machine = vec![0; VOCAB_SIZE];
+        for (i, &p) in probs.iter().enumerate() {
+            logits[10 + i] = (i as u32 + 1) << 18;
+            machine[10 + i] = p;
+        }
+        Distribution::new(logits, machine)
+    }
+
+    #[test]
+    fn redstone_sampler_reports_every_draw() {
+        let dist = distribution(&[1 << 22, 1 << 22]);
+        for seed in [0, 1, 12345] {
+            let mut rng = PRNG::new(seed);
+            let pick = RedstoneSampler.sample(&dist, &mut rng);
+            assert_eq!(pick.drawn, Some(PRNG::new(seed).next()));
+            assert_eq!(pick.token, sample(dist.packed(), &mut PRNG::new(seed)));
+        }
+        // A stuck PRNG still draws, even though its state never changes.
+        let pick = RedstoneSampler.sample(&dist, &mut PRNG::new(0));
+        assert_eq!(pick.drawn, Some(0));
+    }
+
+    #[test]
+    fn greedy_does_not_draw() {
+        let dist = distribution(&[1 << 21, 1 << 22]);
+        let mut rng = PRNG::new(7);
+        let pick = Greedy.sample(&dist, &mut rng);
+        assert_eq!(
+            pick,
+            Pick {
+                token: 11,
+                drawn: None
+            }
+        );
+        assert_eq!(rng, PRNG::new(7));
+    }
+
+    #[test]
+    fn full_sampler_reports_its_draw() {
+        let dist = distribution(&[1 << 22]);
+        let pick = FullSampler::default().sample(&dist, &mut PRNG::new(0));
+        assert_eq!(pick.drawn, Some(0));
+    }
+
+    /// A distribution whose full view gives token `id` probability `p` for
+    /// each `(id, p)`, and every other token none.
+    fn probabilities(probs: &[(usize, f64)]) -> Distribution {
+        let mut full = vec![0.0; VOCAB_SIZE];
+        for &(id, p) in probs {
+            full[id] = p;
+        }
+        Distribution::with_probabilities(full)
+    }
+
+    fn survivors(sampler: FullSampler, dist: &Distribution) -> Vec<usize> {
+        sampler.candidates(dist).iter().map(|&(id, _)| id).collect()
+    }
+
+    #[test]
+    fn full_sampler_filters() {
+        let dist = probabilities(&[(4, 0.1), (1, 0.4), (3, 0.2), (2, 0.3)]);
+        let with = |f: fn(&mut FullSampler)| {
+            let mut sampler = FullSampler::default();
+            f(&mut sampler);
+            survivors(sampler, &dist)
+        };
+
+        assert_eq!(with(|_| {}), [1, 2, 3, 4]);
+        assert_eq!(with(|s| s.top_k = Some(2)), [1, 2]);
+        assert_eq!(with(|s| s.top_k = Some(0)), [1]);
+        assert_eq!(with(|s| s.top_k = Some(10)), [1, 2, 3, 4]);
+        // Keeps tokens at least 0.6 * 0.4 = 0.24 likely.
+        assert_eq!(with(|s| s.min_p = Some(0.6)), [1, 2]);
+        assert_eq!(with(|s| s.min_p = Some(0.4)), [1, 2, 3]);
+        // Keeps tokens until their total reaches top_p, including the one
+        // that gets it there.
+        assert_eq!(with(|s| s.top_p = Some(0.65)), [1, 2]);
+        assert_eq!(with(|s| s.top_p = Some(0.75)), [1, 2, 3]);
+        assert_eq!(with(|s| s.top_p = Some(0.1)), [1]);
+        // top_k applies first, and top_p counts what it left.
+        assert_eq!(
+            with(|s| {
+                s.top_k = Some(3);
+                s.min_p = Some(0.4);
+                s.top_p = Some(0.65)
+            }),
+            [1, 2]
+        );
+    }
+
+    #[test]
+    fn full_sampler_temperature_reshapes() {
+        let dist = probabilities(&[(1, 0.4), (2, 0.3), (3, 0.2), (4, 0.1)]);
+        let shaped = |temperature| {
+            FullSampler {
+                temperature,
+                ..FullSampler::default()
+            }
+            .candidates(&dist)
+        };
+
+        // Halving the temperature squares the probabilities: 0.16, 0.09,
+        // 0.04 and 0.01 out of 0.3.
+        let cold = shaped(0.5);
+        let expected = [0.16 / 0.3, 0.09 / 0.3, 0.04 / 0.3, 0.01 / 0.3];
+        for (&(_, p), e) in cold.iter().zip(expected) {
+            assert!((p - e).abs() < 1e-12, "{} != {}", p, e);
+        }
+        // A high temperature flattens them without reordering.
+        let hot = shaped(100.0);
+        assert_eq!(
+            hot.iter().map(|&(id, _)| id).collect::<Vec<_>>(),
+            [1, 2, 3, 4]
+        );
+        assert!(hot[0].1 - hot[3].1 < 0.01);
+        // min_p is judged after the temperature.
+        let sampler = FullSampler {
+            temperature: 0.5,
+            min_p: Some(0.5),
+            ..FullSampler::default()
+        };
+        assert_eq!(survivors(sampler, &dist), [1, 2]);
+    }
+
+    #[test]
+    fn full_sampler_picks_among_survivors() {
+        let dist = probabilities(&[(1, 0.4), (2, 0.3), (3, 0.2), (4, 0.1)]);
+        let mut sampler = FullSampler {
+            top_k: Some(1),
+            ..FullSampler::default()
+        };
+        for seed in [1, 2, 3, 12345] {
+            assert_eq!(sampler.sample(&dist, &mut PRNG::new(seed)).token, 1);
+        }
+        // A draw of 0 takes the most likely, and the walk goes from there.
+        let pick = FullSampler::default().sample(&dist, &mut PRNG::new(0));
+        assert_eq!(pick.token, 1);
+    }
+
+    #[test]
+    fn greedy_breaks_ties_towards_the_higher_id() {
+        let dist = probabilities(&[(3, 0.5), (7, 0.5)]);
+        assert_eq!(Greedy.sample(&dist, &mut PRNG::new(1)).token, 7);
+        let dist = probabilities(&[(3, 0.25), (9, 0.25), (5, 0.5)]);
+        assert_eq!(Greedy.sample(&dist, &mut PRNG::new(1)).token, 5);
+    }
+
+    /// The sampling loop of the original `main.rs`.
+    fn baseline(act: &[u64], drawn: u32) -> usize {
+        let mut cur = drawn as i32;
+        let mut here = -1i32;
+        for j in (1..=7).rev() {
+            if (act[j] >> 11) < (1 << 20) {
+                continue;
+            }
+            cur -= (act[j] >> 11) as i32;
+            if cur < 0 {
+                here = (act[j] & 2047) as i32;
+                break;
+            }
+        }
+        if here == -1 {
+            here = (act[0] & 2047) as i32;
+        }
+        here as usize
+    }
+
+    #[test]
+    fn redstone_sampler_walks_like_the_original() {
+        let cases: [&[u32]; 4] = [
+            &[1 << 22, 1 << 22],
+            &[
+                (1 << 20) - 1,
+                1 << 20,
+                3 << 20,
+                1 << 21,
+                1 << 20,
+                5,
+                0,
+                1 << 21,
+            ],
+            // Nothing but the most likely reaches the cutoff.
+            &[(1 << 20) - 1, 1 << 19, 1 << 23],
+            &[1 << 20; 9],
+        ];
+        for probs in cases {
+            let dist = distribution(probs);
+            let draws = (0..1 << 23).step_by(4099).chain([
+                0,
+                (1 << 20) - 1,
+                1 << 20,
+                (1 << 21) - 1,
+                1 << 21,
+                (1 << 23) - 1,
+            ]);
+            for drawn in draws {
+                assert_eq!(
+                    walk(dist.packed(), drawn),
+                    baseline(dist.packed(), drawn),
+                    "{:?} drawing {}",
+                    probs,
+                    drawn
+                );
+            }
+        }
+
+        // The least likely token at the cutoff comes first; anything under it
+        // is skipped, and a draw past them all falls back to the top token.
+        let dist = distribution(&[(1 << 20) - 1, 1 << 20, 1 << 22]);
+        assert_eq!(walk(dist.packed(), 0), 11);
+        assert_eq!(walk(dist.packed(), (1 << 20) - 1), 11);
+        assert_eq!(walk(dist.packed(), 1 << 20), 12);
+        assert_eq!(walk(dist.packed(), (1 << 20) + (1 << 22)), 12);
+        let dist = distribution(&[(1 << 20) - 1]);
+        assert_eq!(walk(dist.packed(), 0), 10);
+    }
+}
@@ -0,0 +1,240 @@
+//! Sponge schematics (version 2) of the ROM blocks, for pasting updated
+//! weights into a world with WorldEdit.
+//!
+//! Only ROM bits are written; every other block is air, so the schematic
+//! should be pasted with `//paste -a -o` to leave the rest of the machine
+//! alone and put the ROMs back at their original coordinates.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::error::LoadError;
+use crate::layout::{Layout, ONE_BLOCK, Pos, RomPlacement, ZERO_BLOCK};
+use crate::nbt::{self, Tag};
+use crate::weights::WeightSource;
+
+/// Data version of Minecraft 1.20.4, which the world is saved in.
+const DATA_VERSION: i32 = 3700;
+
+/// A box of blocks, stored as indices into a palette of block states.
+pub struct Schematic {
+    /// World coordinates of the minimum corner.
+    pub origin: Pos,
+    pub width: usize,
+    pub height: usize,
+    pub length: usize,
+    pub palette: Vec<String>,
+    /// Palette indices in x, then z, then y order.
+    blocks: Vec<u8>,
+}
+
+impl Schematic {
+    /// Builds a schematic of every ROM in `layout` accepted by `filter`, with
+    /// its contents read from `source`.
+    pub fn from_layout<F: Fn(&RomPlacement) -> bool>(
+        layout: &Layout,
+        source: &dyn WeightSource,
+        filter: F,
+    ) -> Result<Self, LoadError> {
+        let roms: Vec<&RomPlacement> = layout.roms().iter().filter(|r| filter(r)).collect();
+
+        let mut min = (i32::MAX, i32::MAX, i32::MAX);
+        let mut max = (i32::MIN, i32::MIN, i32::MIN);
+        for rom in &roms {
+            let (lo, hi) = rom.bounds(0..rom.format.words());
+            min = (min.0.min(lo.0), min.1.min(lo.1), min.2.min(lo.2));
+            max = (max.0.max(hi.0), max.1.max(hi.1), max.2.max(hi.2));
+        }
+        if roms.is_empty() {
+            min = (0, 0, 0);
+            max = (-1, -1, -1);
+        }
+
+        let mut schematic = Schematic {
+            origin: min,
+            width: (max.0 - min.0 + 1) as usize,
+            height: (max.1 - min.1 + 1) as usize,
+            length: (max.2 - min.2 + 1) as usize,
+            palette: vec![
+                "minecraft:air".to_string(),
+                ZERO_BLOCK.to_string(),
+                ONE_BLOCK.to_string(),
+            ],
+            blocks: Vec::new(),
+        };
+        schematic.blocks = vec![0; schematic.width * schematic.height * schematic.length];
+
+        for rom in roms {
+            let bytes = source.read(&rom.name, rom.format.size())?;
+            rom.for_each_bit(&bytes, |pos, bit| {
+                let i = schematic.index(pos).unwrap();
+                schematic.blocks[i] = if bit { 2 } else { 1 };
+            });
+        }
+
+        Ok(schematic)
+    }
+
+    fn index(&self, (x, y, z): Pos) -> Option<usize> {
+        let (dx, dy, dz) = (x - self.origin.0, y - self.origin.1, z - self.origin.2);
+        if dx < 0 || dy < 0 || dz < 0 {
+            return None;
+        }
+        let (dx, dy, dz) = (dx as usize, dy as usize, dz as usize);
+        if dx >= self.width || dy >= self.height || dz >= self.length {
+            return None;
+        }
+        Some(dx + self.width * (dz + self.length * dy))
+    }
+
+    /// The block state at world position `pos`, if it is inside the schematic.
+    pub fn block(&self, pos: Pos) -> Option<&str> {
+        let i = self.index(pos)?;
+        Some(&self.palette[self.blocks[i] as usize])
+    }
+
+    /// Writes the schematic as gzipped NBT.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut w = GzEncoder::new(file, Compression::fast());
+
+        let short = |n: usize| {
+            i16::try_from(n)
+                .map(Tag::Short)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "schematic too large"))
+        };
+        let palette = self
+            .palette
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.clone(), Tag::Int(i as i32)))
+            .collect();
+        let metadata = vec![
+            ("WEOffsetX".to_string(), Tag::Int(0)),
+            ("WEOffsetY".to_string(), Tag::Int(0)),
+            ("WEOffsetZ".to_string(), Tag::Int(0)),
+        ];
+
+        // The block data can be hundreds of megabytes, so the root compound is
+        // written by hand to stream it instead of building a tag for it.
+        w.write_all(&[10])?;
+        w.write_all(&(b"Schematic".len() as u16).to_be_bytes())?;
+        w.write_all(b"Schematic")?;
+        nbt::write(&mut w, "Version", &Tag::Int(2))?;
+        nbt::write(&mut w, "DataVersion", &Tag::Int(DATA_VERSION))?;
+        nbt::write(&mut w, "Width", &short(self.width)?)?;
+        nbt::write(&mut w, "Height", &short(self.height)?)?;
+        nbt::write(&mut w, "Length", &short(self.length)?)?;
+        nbt::write(
+            &mut w,
+            "Offset",
+            &Tag::IntArray(vec![self.origin.0, self.origin.1, self.origin.2]),
+        )?;
+        nbt::write(&mut w, "Metadata", &Tag::Compound(metadata))?;
+        nbt::write(&mut w, "PaletteMax", &Tag::Int(self.palette.len() as i32))?;
+        nbt::write(&mut w, "Palette", &Tag::Compound(palette))?;
+        nbt::write(&mut w, "BlockEntities", &Tag::List(Vec::new()))?;
+
+        // Palette indices below 128 are their own varint encoding.
+        assert!(self.palette.len() <= 128);
+        w.write_all(&[7])?;
+        w.write_all(&(b"BlockData".len() as u16).to_be_bytes())?;
+        w.write_all(b"BlockData")?;
+        w.write_all(&(self.blocks.len() as i32).to_be_bytes())?;
+        w.write_all(&self.blocks)?;
+        w.write_all(&[0])?;
+
+        w.finish()?.flush()
+    }
+
+    /// Reads a gzipped Sponge schematic. Schematics with more than 256 kinds
+    /// of block aren't supported.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        let mut data = Vec::new();
+        GzDecoder::new(file).read_to_end(&mut data)?;
+        let (_, root) = nbt::read(&mut data.as_slice())?;
+        // Version 3 nests everything under a `Schematic` compound.
+        let root = match root.get("Schematic") {
+            Some(inner) => inner,
+            None => &root,
+        };
+
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let dim = |key: &str| {
+            root.get(key)
+                .and_then(Tag::as_i64)
+                .map(|v| v as u16 as usize)
+                .ok_or_else(|| invalid(&format!("schematic has no {}", key)))
+        };
+        let (width, height, length) = (dim("Width")?, dim("Height")?, dim("Length")?);
+
+        let origin = match root.get("Offset") {
+            Some(Tag::IntArray(v)) if v.len() == 3 => (v[0], v[1], v[2]),
+            _ => (0, 0, 0),
+        };
+
+        let blocks_tag = root.get("Blocks");
+        let palette_tag = root
+            .get("Palette")
+            .or_else(|| blocks_tag.and_then(|b| b.get("Palette")))
+            .and_then(Tag::as_compound)
+            .ok_or_else(|| invalid("schematic has no palette"))?;
+        let mut palette = vec![String::new(); palette_tag.len()];
+        for (name, id) in palette_tag {
+            let id = id.as_i64().ok_or_else(|| invalid("bad palette entry"))? as usize;
+            if id >= palette.len() || id > 255 {
+                return Err(invalid("palette is too large"));
+            }
+            palette[id] = name.clone();
+        }
+
+        let data = match root
+            .get("BlockData")
+            .or_else(|| blocks_tag.and_then(|b| b.get("Data")))
+        {
+            Some(Tag::ByteArray(data)) => data,
+            _ => return Err(invalid("schematic has no block data")),
+        };
+        // Every block takes at least a byte.
+        if width * height * length > data.len() {
+            return Err(invalid("block data doesn't match the schematic's size"));
+        }
+        let mut blocks = Vec::with_capacity(width * height * length);
+        let mut value = 0usize;
+        let mut shift = 0;
+        for &b in data {
+            value |= ((b & 0x7f) as usize) << shift;
+            if b & 0x80 != 0 {
+                shift += 7;
+                if shift > 28 {
+                    return Err(invalid("block data has an overlong varint"));
+                }
+                continue;
+            }
+            if value >= palette.len() {
+                return Err(invalid("block data refers past the palette"));
+            }
+            blocks.push(value as u8);
+            value = 0;
+            shift = 0;
+        }
+        if blocks.len() != width * height * length {
+            return Err(invalid("block data doesn't match the schematic's size"));
+        }
+
+        Ok(Schematic {
+            origin,
+            width,
+            height,
+            length,
+            palette,
+            blocks,
+        })
+    }
+}
@@ -0,0 +1,647 @@
+//! A small HTTP server speaking enough of the OpenAI API for chat frontends
+//! and notebooks to talk to the emulator, as run by `craftgpt serve`.
+//!
+//! It answers `POST /v1/completions`, `POST /v1/chat/completions` and
+//! `GET /v1/models`, streaming tokens as server-sent events when a request
+//! sets `stream`. There is only one model, so requests are handled one at a
+//! time, each from a fresh context; a client that takes longer than
+//! [`ServerOptions::timeout`] to send its request is dropped so it can't
+//! hold up the others. Browsers on
+//! other origins are only let in when [`ServerOptions::cors_origin`] says so.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::chat::{self, Role};
+use crate::generate::{Generator, Stop};
+use crate::sampler::{FullSampler, Greedy, RedstoneSampler, Sampler};
+use crate::{ChatTemplate, Model, ModelState, PRNG, Tokenizer};
+
+const MODEL_NAME: &str = "craftgpt";
+/// Largest request body accepted.
+const MAX_BODY: usize = 1 << 20;
+/// Largest request line and headers accepted, together.
+const MAX_HEAD: usize = 16 << 10;
+
+#[derive(Deserialize)]
+struct CompletionRequest {
+    prompt: String,
+    #[serde(flatten)]
+    params: Params,
+}
+
+#[derive(Deserialize)]
+struct ChatRequest {
+    messages: Vec<Message>,
+    #[serde(flatten)]
+    params: Params,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    role: String,
+    content: String,
+}
+
+/// The sampling fields both endpoints take. Without any of them the server
+/// samples the way its command line says, the machine's way by default; a
+/// temperature of 0 is greedy.
+#[derive(Deserialize)]
+struct Params {
+    max_tokens: Option<usize>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    top_k: Option<usize>,
+    min_p: Option<f64>,
+    seed: Option<u32>,
+    #[serde(default)]
+    stream: bool,
+}
+
+impl Params {
+    /// Turns away sampling settings that would make no sense, rather than
+    /// letting them through to the sampler.
+    fn check(&self) -> Result<(), Rejection> {
+        let out_of_range = |name: &str, value: f64, range: &str| {
+            Err(Rejection::bad_request(format!(
+                "{} must be {}, not {}",
+                name, range, value
+            )))
+        };
+        if let Some(t) = self.temperature
+            && !(t >= 0.0 && t.is_finite())
+        {
+            return out_of_range("temperature", t, "a number of at least 0");
+        }
+        if let Some(p) = self.top_p
+            && !(p > 0.0 && p <= 1.0)
+        {
+            return out_of_range("top_p", p, "above 0 and at most 1");
+        }
+        if let Some(p) = self.min_p
+            && !(0.0..=1.0).contains(&p)
+        {
+            return out_of_range("min_p", p, "between 0 and 1");
+        }
+        if self.top_k == Some(0) {
+            return Err(Rejection::bad_request("top_k must be at least 1"));
+        }
+        // The PRNG is 23 bits, and stays at 0 forever once it gets there.
+        if let Some(seed) = self.seed
+            && !(1..1 << 23).contains(&seed)
+        {
+            return Err(Rejection::bad_request(format!(
+                "seed must be between 1 and {}, not {}",
+                (1 << 23) - 1,
+                seed
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Which endpoint a completion is for, which decides the shape of the
+/// responses.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Endpoint {
+    Completions,
+    Chat,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+/// A request that can't be served, answered with an OpenAI-style error.
+struct Rejection {
+    status: &'static str,
+    message: String,
+}
+
+impl Rejection {
+    fn bad_request<E: ToString>(e: E) -> Self {
+        Rejection {
+            status: "400 Bad Request",
+            message: e.to_string(),
+        }
+    }
+}
+
+/// How a [`Server`] answers requests that leave things unsaid.
+pub struct ServerOptions {
+    /// The sampler for requests that don't ask for one of their own.
+    pub sampler: Box<dyn Sampler>,
+    /// Answers stop after this many tokens. Requests may ask for fewer, but
+    /// not for more.
+    pub max_tokens: usize,
+    /// Never pick the padding IDs past the end of the vocabulary.
+    pub mask_unused: bool,
+    /// How long a client has to send its whole request, and to take each
+    /// write of the response.
+    pub timeout: Duration,
+    /// The origin browsers may call the server from, if any. `*` lets in
+    /// every page the browser visits.
+    pub cors_origin: Option<String>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            sampler: Box::new(RedstoneSampler),
+            max_tokens: 128,
+            mask_unused: false,
+            timeout: Duration::from_secs(10),
+            cors_origin: None,
+        }
+    }
+}
+
+pub struct Server {
+    model: Model,
+    tokenizer: Tokenizer,
+    template: ChatTemplate,
+    options: ServerOptions,
+    /// The model before it has seen anything, to start each request from.
+    start: ModelState,
+    requests: u64,
+}
+
+impl Server {
+    /// A server answering with `model` from where it is now.
+    pub fn new(
+        model: Model,
+        tokenizer: Tokenizer,
+        template: ChatTemplate,
+        options: ServerOptions,
+    ) -> Self {
+        Server {
+            start: model.snapshot(),
+            model,
+            tokenizer,
+            template,
+            options,
+            requests: 0,
+        }
+    }
+
+    /// Answers connections on `listener` one at a time, for as long as it
+    /// accepts them. A connection that fails is reported and dropped.
+    pub fn run(&mut self, listener: &TcpListener) {
+        for stream in listener.incoming() {
+            if let Err(e) = stream.and_then(|stream| self.handle(stream)) {
+                eprintln!("connection failed: {}", e);
+            }
+        }
+    }
+
+    /// Reads one request from `stream` and answers it.
+    pub fn handle(&mut self, stream: TcpStream) -> io::Result<()> {
+        stream.set_write_timeout(Some(self.options.timeout))?;
+        let cors = match &self.options.cors_origin {
+            Some(origin) => format!("Access-Control-Allow-Origin: {}\r\n", origin),
+            None => String::new(),
+        };
+        let mut reader = BufReader::new(Deadline {
+            stream,
+            at: Instant::now() + self.options.timeout,
+        });
+        let request = match read_request(&mut reader) {
+            Ok(request) => request,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                let mut stream = reader.into_inner().stream;
+                return reject(&mut stream, &cors, Rejection::bad_request(e));
+            }
+            Err(e) => return Err(e),
+        };
+        let mut stream = reader.into_inner().stream;
+        eprintln!("{} {}", request.method, request.path);
+
+        let result = match (request.method.as_str(), request.path.as_str()) {
+            ("OPTIONS", _) if !cors.is_empty() => {
+                return write!(
+                    stream,
+                    "HTTP/1.1 204 No Content\r\n{}Access-Control-Allow-Methods: GET, POST\r\n\
+                     Access-Control-Allow-Headers: *\r\nConnection: close\r\n\r\n",
+                    cors
+                );
+            }
+            ("GET", "/v1/models") => {
+                let models = json!({
+                    "object": "list",
+                    "data": [{"id": MODEL_NAME, "object": "model", "owned_by": "craftgpt"}],
+                });
+                return respond(&mut stream, &cors, "200 OK", &models);
+            }
+            ("POST", "/v1/completions") => serde_json::from_slice(&request.body)
+                .map_err(Rejection::bad_request)
+                .and_then(|request: CompletionRequest| {
+                    let ids = self
+                        .tokenizer
+                        .encode(&request.prompt)
+                        .map_err(Rejection::bad_request)?;
+                    Ok((ids, request.params))
+                })
+                .map(|(ids, params)| (Endpoint::Completions, ids, params)),
+            ("POST", "/v1/chat/completions") => serde_json::from_slice(&request.body)
+                .map_err(Rejection::bad_request)
+                .and_then(|request: ChatRequest| {
+                    Ok((self.chat_ids(&request.messages)?, request.params))
+                })
+                .map(|(ids, params)| (Endpoint::Chat, ids, params)),
+            (_, "/v1/models" | "/v1/completions" | "/v1/chat/completions") => Err(Rejection {
+                status: "405 Method Not Allowed",
+                message: format!("{} isn't allowed on {}", request.method, request.path),
+            }),
+            _ => Err(Rejection {
+                status: "404 Not Found",
+                message: format!("no such endpoint {}", request.path),
+            }),
+        };
+
+        let result = result.and_then(|(endpoint, ids, params)| {
+            if ids.is_empty() {
+                return Err(Rejection::bad_request("the prompt is empty"));
+            }
+            params.check()?;
+            Ok((endpoint, ids, params))
+        });
+        match result {
+            Ok((endpoint, ids, params)) => {
+                self.complete(&mut stream, &cors, endpoint, &ids, &params)
+            }
+            Err(rejection) => reject(&mut stream, &cors, rejection),
+        }
+    }
+
+    /// Lays the messages out with the chat template. The model has no notion
+    /// of a system prompt, so system messages are sent as user turns.
+    fn chat_ids(&self, messages: &[Message]) -> Result<Vec<usize>, Rejection> {
+        let messages = messages
+            .iter()
+            .map(|message| {
+                let role = match message.role.as_str() {
+                    "user" | "system" => Role::User,
+                    "assistant" => Role::Assistant,
+                    role => {
+                        return Err(Rejection::bad_request(format!("unknown role '{}'", role)));
+                    }
+                };
+                Ok(chat::Message {
+                    role,
+                    text: message.content.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.template
+            .prompt(&self.tokenizer, &messages)
+            .map_err(Rejection::bad_request)
+    }
+
+    fn complete(
+        &mut self,
+        stream: &mut TcpStream,
+        cors: &str,
+        endpoint: Endpoint,
+        ids: &[usize],
+        params: &Params,
+    ) -> io::Result<()> {
+        self.requests += 1;
+        let id = match endpoint {
+            Endpoint::Completions => format!("cmpl-{}", self.requests),
+            Endpoint::Chat => format!("chatcmpl-{}", self.requests),
+        };
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let chunk = |choice: Value| {
+            let object = match endpoint {
+                Endpoint::Completions => "text_completion",
+                Endpoint::Chat => "chat.completion.chunk",
+            };
+            json!({
+                "id": id,
+                "object": object,
+                "created": created,
+                "model": MODEL_NAME,
+                "choices": [choice],
+            })
+        };
+
+        self.model.restore(&self.start);
+        let mut dist = None;
+        for &token in ids {
+            dist = Some(self.model.predict(token));
+        }
+        let dist = dist.expect("prompt is not empty");
+
+        let generator = Generator {
+            tokenizer: &self.tokenizer,
+            template: self.template,
+            max_tokens: params
+                .max_tokens
+                .map_or(self.options.max_tokens, |n| n.min(self.options.max_tokens)),
+            mask_unused: self.options.mask_unused,
+        };
+        let mut custom = custom_sampler(params);
+        let sampler = match custom.as_deref_mut() {
+            Some(sampler) => sampler,
+            None => self.options.sampler.as_mut(),
+        };
+        let mut rng = PRNG::new(params.seed.unwrap_or_else(clock_seed));
+
+        let tokenizer = &self.tokenizer;
+        let mut first = true;
+        let mut piece = |token: usize| {
+            let text = tokenizer.token(token).replace('_', " ");
+            if std::mem::take(&mut first) {
+                text.trim_start().to_string()
+            } else {
+                text
+            }
+        };
+
+        if !params.stream {
+            let generation =
+                generator.generate(&mut self.model, dist, sampler, &mut rng, &mut |_| {});
+            let tokens = generation.tokens();
+            let text = tokenizer.decode(&tokens).trim().to_string();
+            let finish = finish_reason(generation.stop);
+            let choice = match endpoint {
+                Endpoint::Completions => json!({
+                    "index": 0,
+                    "text": text,
+                    "logprobs": null,
+                    "finish_reason": finish,
+                }),
+                Endpoint::Chat => json!({
+                    "index": 0,
+                    "message": {"role": "assistant", "content": text},
+                    "finish_reason": finish,
+                }),
+            };
+            let mut response = chunk(choice);
+            if endpoint == Endpoint::Chat {
+                response["object"] = json!("chat.completion");
+            }
+            response["usage"] = json!({
+                "prompt_tokens": ids.len(),
+                "completion_tokens": tokens.len(),
+                "total_tokens": ids.len() + tokens.len(),
+            });
+            return respond(stream, cors, "200 OK", &response);
+        }
+
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+             {}Connection: close\r\n\r\n",
+            cors
+        )?;
+        if endpoint == Endpoint::Chat {
+            let role = json!({"index": 0, "delta": {"role": "assistant"}, "finish_reason": null});
+            send_event(stream, &chunk(role))?;
+        }
+
+        // A client that hangs up can't stop the model mid-answer, so the
+        // first failed write is kept and reported at the end.
+        let mut failed = None;
+        let generation =
+            generator.generate(&mut self.model, dist, sampler, &mut rng, &mut |token| {
+                if failed.is_some() {
+                    return;
+                }
+                let text = piece(token);
+                let choice = match endpoint {
+                    Endpoint::Completions => {
+                        json!({"index": 0, "text": text, "logprobs": null, "finish_reason": null})
+                    }
+                    Endpoint::Chat => {
+                        json!({"index": 0, "delta": {"content": text}, "finish_reason": null})
+                    }
+                };
+                if let Err(e) = send_event(stream, &chunk(choice)) {
+                    failed = Some(e);
+                }
+            });
+        if let Some(e) = failed {
+            return Err(e);
+        }
+
+        let finish = finish_reason(generation.stop);
+        let choice = match endpoint {
+            Endpoint::Completions => {
+                json!({"index": 0, "text": "", "logprobs": null, "finish_reason": finish})
+            }
+            Endpoint::Chat => json!({"index": 0, "delta": {}, "finish_reason": finish}),
+        };
+        send_event(stream, &chunk(choice))?;
+        write!(stream, "data: [DONE]\n\n")?;
+        stream.flush()
+    }
+}
+
+/// The sampler a request asks for, if it asks for one.
+fn custom_sampler(params: &Params) -> Option<Box<dyn Sampler>> {
+    if params.temperature == Some(0.0) {
+        return Some(Box::new(Greedy));
+    }
+    let custom = params.temperature.is_some()
+        || params.top_p.is_some()
+        || params.top_k.is_some()
+        || params.min_p.is_some();
+    custom.then(|| {
+        Box::new(FullSampler {
+            temperature: params.temperature.unwrap_or(1.0),
+            top_k: params.top_k,
+            top_p: params.top_p,
+            min_p: params.min_p,
+        }) as Box<dyn Sampler>
+    })
+}
+
+fn finish_reason(stop: Stop) -> &'static str {
+    match stop {
+        Stop::EndToken => "stop",
+        Stop::MaxTokens => "length",
+    }
+}
+
+/// A seed for requests that don't give one. Seeds are 23 bits, and 0 would
+/// leave the PRNG stuck.
+fn clock_seed() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    (nanos & ((1 << 23) - 1)) | 1
+}
+
+/// A stream that fails every read once `at` has passed, so that a client
+/// sending a byte at a time can't keep a request going forever.
+struct Deadline {
+    stream: TcpStream,
+    at: Instant,
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.at.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the request took too long",
+            ));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Request> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let mut head = reader.take(MAX_HEAD as u64);
+    let mut line = String::new();
+    head.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(invalid("malformed request line"));
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut length = 0;
+    loop {
+        line.clear();
+        if head.read_line(&mut line)? == 0 {
+            if head.limit() == 0 {
+                return Err(invalid("request headers too large"));
+            }
+            return Err(invalid("headers end early"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            length = value
+                .trim()
+                .parse()
+                .map_err(|_| invalid("bad Content-Length"))?;
+        }
+    }
+    if length > MAX_BODY {
+        return Err(invalid("request body too large"));
+    }
+
+    let mut body = vec![0; length];
+    head.into_inner().read_exact(&mut body)?;
+    Ok(Request { method, path, body })
+}
+
+/// Writes a JSON response. `cors` is the CORS header line, or nothing.
+fn respond(stream: &mut TcpStream, cors: &str, status: &str, body: &Value) -> io::Result<()> {
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        cors,
+        body
+    )?;
+    stream.flush()
+}
+
+fn reject(stream: &mut TcpStream, cors: &str, rejection: Rejection) -> io::Result<()> {
+    let body = json!({
+        "error": {"message": rejection.message, "type": "invalid_request_error"},
+    });
+    respond(stream, cors, rejection.status, &body)
+}
+
+fn send_event(stream: &mut TcpStream, data: &Value) -> io::Result<()> {
+    write!(stream, "data: {}\n\n", data)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(request: &[u8]) -> io::Result<Request> {
+        read_request(&mut &request[..])
+    }
+
+    fn reason(request: &[u8]) -> String {
+        match read(request) {
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+                e.to_string()
+            }
+            Ok(_) => panic!("request was accepted"),
+        }
+    }
+
+    #[test]
+    fn reads_a_request() {
+        let request =
+            read(b"POST /v1/completions HTTP/1.1\r\ncontent-length: 2\r\n\r\n{}").unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/v1/completions");
+        assert_eq!(request.body, b"{}");
+    }
+
+    #[test]
+    fn limits_the_request_head() {
+        let mut request = b"GET /v1/models HTTP/1.1\r\n".to_vec();
+        for i in 0..MAX_HEAD / 16 {
+            request.extend_from_slice(format!("X-Pad-{:05}: 1\r\n", i).as_bytes());
+        }
+        request.extend_from_slice(b"\r\n");
+        assert_eq!(reason(&request), "request headers too large");
+
+        let mut endless = b"GET /".to_vec();
+        endless.resize(2 * MAX_HEAD, b'a');
+        assert_eq!(reason(&endless), "request headers too large");
+
+        assert_eq!(
+            reason(b"GET / HTTP/1.1\r\nHost: x\r\n"),
+            "headers end early"
+        );
+        let too_long = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1
+        );
+        assert_eq!(reason(too_long.as_bytes()), "request body too large");
+    }
+
+    #[test]
+    fn seeds_must_be_23_bits_and_not_0() {
+        let params = |seed| Params {
+            max_tokens: None,
+            temperature: None,
+            top_p: None,
+            top_k: None,
+            min_p: None,
+            seed: Some(seed),
+            stream: false,
+        };
+        assert!(params(1).check().is_ok());
+        assert!(params((1 << 23) - 1).check().is_ok());
+        for seed in [0, 1 << 23, u32::MAX] {
+            let rejection = params(seed).check().unwrap_err();
+            assert_eq!(
+                rejection.message,
+                format!("seed must be between 1 and 8388607, not {}", seed)
+            );
+        }
+    }
+}
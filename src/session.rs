@@ -0,0 +1,217 @@
+//! Saving a conversation with the model to disk and picking it up again, so
+//! a long chat doesn't have to be reprocessed token by token.
+//!
+//! A session holds the tokens processed so far, the model's attention caches
+//! and position, the context policy they were built under and the sampler's
+//! PRNG, which is everything needed to carry on bit-exactly. The file is laid
+//! out as follows, all integers little-endian:
+//!
+//! ```text
+//! magic          8 bytes  "CGPTSESS"
+//! version        u32
+//! window         u32, 0 for the faithful context policy
+//! PRNG state     u32
+//! position       u32
+//! token count    u32
+//! tokens         u32 each
+//! block count    u32
+//! cache length   u32, tokens cached in every head
+//! caches         for each block, for each head, the keys and then the
+//!                values, 48 u16 pseudo-floats per token
+//! ```
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::path::Path;
+
+use crate::attention::{Cache, HEAD_SIZE, HEADS};
+use crate::model::LAYERS;
+use crate::{CONTEXT_SIZE, ContextPolicy, Model, ModelState, PRNG, VOCAB_SIZE};
+
+const MAGIC: &[u8; 8] = b"CGPTSESS";
+pub const FORMAT_VERSION: u32 = 1;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A conversation in progress.
+#[derive(Clone, Debug)]
+pub struct Session {
+    /// Every token the model has processed, in order.
+    pub tokens: Vec<usize>,
+    pub policy: ContextPolicy,
+    pub state: ModelState,
+    pub rng: PRNG,
+}
+
+impl Session {
+    /// Captures where `model` is, after processing `tokens`.
+    pub fn new(model: &Model, tokens: Vec<usize>, rng: PRNG) -> Self {
+        Session {
+            tokens,
+            policy: model.context_policy(),
+            state: model.snapshot(),
+            rng,
+        }
+    }
+
+    /// Puts `model` back where the session left off.
+    pub fn restore(&self, model: &mut Model) {
+        model.set_context_policy(self.policy);
+        model.restore(&self.state);
+    }
+
+    /// Writes the session to `path`. It is written beside it first and then
+    /// moved into place, so a save that fails part way leaves whatever was
+    /// at `path` as it was.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let Some(name) = path.file_name() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a file name", path.display()),
+            ));
+        };
+        let mut temp = name.to_os_string();
+        temp.push(".tmp");
+        let temp = path.with_file_name(temp);
+
+        let result = File::create(&temp)
+            .and_then(|file| {
+                let mut w = BufWriter::new(file);
+                self.write(&mut w)?;
+                w.into_inner()?.sync_all()
+            })
+            .and_then(|()| fs::rename(&temp, path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        write_u32(w, FORMAT_VERSION)?;
+        write_u32(
+            w,
+            match self.policy {
+                ContextPolicy::Faithful => 0,
+                ContextPolicy::SlidingWindow(size) => size as u32,
+            },
+        )?;
+        write_u32(w, self.rng.state())?;
+        write_u32(w, self.state.index as u32)?;
+        write_u32(w, self.tokens.len() as u32)?;
+        for &token in &self.tokens {
+            write_u32(w, token as u32)?;
+        }
+
+        write_u32(w, self.state.caches.len() as u32)?;
+        write_u32(w, self.state.caches.first().map_or(0, Cache::len) as u32)?;
+        for cache in &self.state.caches {
+            for head in 0..HEADS {
+                for entries in [&cache.keys[head], &cache.values[head]] {
+                    for v in entries.iter().flatten() {
+                        w.write_all(&v.to_le_bytes())?;
+                    }
+                }
+            }
+        }
+
+        w.flush()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut r = BufReader::new(file);
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a session file".to_string()));
+        }
+        let version = read_u32(&mut r)?;
+        if version != FORMAT_VERSION {
+            return Err(invalid(format!("unsupported session version {}", version)));
+        }
+
+        let policy = match read_u32(&mut r)? {
+            0 => ContextPolicy::Faithful,
+            size => ContextPolicy::SlidingWindow(size as usize),
+        };
+        let rng = PRNG::new(read_u32(&mut r)?);
+        let index = read_u32(&mut r)? as usize;
+        let count = read_u32(&mut r)? as usize;
+        let tokens = (0..count)
+            .map(|_| match read_u32(&mut r)? as usize {
+                token if token < VOCAB_SIZE => Ok(token),
+                token => Err(invalid(format!("token {} is out of range", token))),
+            })
+            .collect::<io::Result<_>>()?;
+
+        let blocks = read_u32(&mut r)? as usize;
+        if blocks != LAYERS {
+            return Err(invalid(format!("{} blocks, expected {}", blocks, LAYERS)));
+        }
+        let len = read_u32(&mut r)? as usize;
+        let capacity = match policy {
+            ContextPolicy::Faithful => CONTEXT_SIZE,
+            ContextPolicy::SlidingWindow(size) => size,
+        };
+        if len != index.min(capacity) {
+            return Err(invalid(format!(
+                "{} cached tokens at position {}, expected {}",
+                len,
+                index,
+                index.min(capacity)
+            )));
+        }
+        // The window size and position come from the file, so `len` can be
+        // anything; don't allocate for more than the file holds.
+        let cache_bytes = (LAYERS * HEADS * 2 * HEAD_SIZE * 2) as u64;
+        let remaining = file_len.saturating_sub(r.stream_position()?);
+        if (len as u64).saturating_mul(cache_bytes) > remaining {
+            return Err(invalid(format!(
+                "{} cached tokens don't fit in the rest of the file",
+                len
+            )));
+        }
+
+        let mut caches = Vec::with_capacity(blocks);
+        for _ in 0..blocks {
+            let mut entries = || -> io::Result<Vec<[u16; HEAD_SIZE]>> {
+                let mut entries = vec![[0u16; HEAD_SIZE]; len];
+                for v in entries.iter_mut().flatten() {
+                    let mut buf = [0u8; 2];
+                    r.read_exact(&mut buf)?;
+                    *v = u16::from_le_bytes(buf);
+                }
+                Ok(entries)
+            };
+            let mut cache = Cache::new();
+            for head in 0..HEADS {
+                cache.keys[head] = entries()?;
+                cache.values[head] = entries()?;
+            }
+            caches.push(cache);
+        }
+
+        Ok(Session {
+            tokens,
+            policy,
+            state: ModelState { index, caches },
+            rng,
+        })
+    }
+}
+
+fn write_u32<W: Write>(w: &mut W, v: u32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
@@ -0,0 +1,801 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::error::LoadError;
+use crate::weights::{
+    TOKEN_CHARS_SIZE, TOKEN_INDICES_SIZE, TOKEN_TRIE_SIZE, WeightSource, u24_words,
+};
+use crate::{ChatTemplate, VOCAB_SIZE};
+
+/// A character in the input that no token starts with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenizeError {
+    /// Byte offset of the character in the text passed to
+    /// [`Tokenizer::encode`].
+    pub offset: usize,
+    pub character: char,
+}
+
+impl fmt::Display for TokenizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "no token matches {:?} at byte {}",
+            self.character, self.offset
+        )
+    }
+}
+
+impl Error for TokenizeError {}
+
+impl TokenizeError {
+    fn at(text: &str, offset: usize) -> Self {
+        let character = text[offset..].chars().next().unwrap_or(' ');
+        TokenizeError { offset, character }
+    }
+}
+
+/// Trims, lowercases and prefixes `text` with a space, as the machine does.
+/// Returns the normalized bytes and the offset in `text` each one came from,
+/// plus the offset of the end.
+fn normalize(text: &str) -> (Vec<u8>, Vec<usize>) {
+    let start = text.len() - text.trim_start().len();
+    let trimmed = text.trim();
+
+    let mut bytes = vec![b' '];
+    let mut origin = vec![start];
+    for (i, c) in trimmed.char_indices() {
+        for lower in c.to_lowercase() {
+            let mut buf = [0u8; 4];
+            for &b in lower.encode_utf8(&mut buf).as_bytes() {
+                bytes.push(b);
+                origin.push(start + i);
+            }
+        }
+    }
+    origin.push(start + trimmed.len());
+
+    (bytes, origin)
+}
+
+#[derive(Default)]
+struct Node {
+    children: Vec<(u8, usize)>,
+    /// The token spelled by the path to this node, if there is one.
+    token: Option<usize>,
+}
+
+impl Node {
+    fn child(&self, byte: u8) -> Option<usize> {
+        self.children
+            .iter()
+            .find(|&&(b, _)| b == byte)
+            .map(|&(_, node)| node)
+    }
+}
+
+/// Converts between text and token IDs using the vocabulary in `tokens.txt`.
+///
+/// The vocabulary is shorter than the model's [`VOCAB_SIZE`] outputs; the
+/// IDs past its end are padding, named like `<unused_1906>`. They can't be
+/// typed, but the model can still give them probability.
+pub struct Tokenizer {
+    /// Token text as written in the vocabulary, with spaces as `_`, followed
+    /// by the padding names.
+    tokens: Vec<String>,
+    /// Number of real tokens.
+    used: usize,
+    /// A trie of the token texts with real spaces; node 0 is the root.
+    nodes: Vec<Node>,
+}
+
+impl Tokenizer {
+    /// Builds a tokenizer from the vocabulary, one token per line, with
+    /// spaces written as `_`.
+    ///
+    /// # Panics
+    ///
+    /// If there are more than [`VOCAB_SIZE`] tokens.
+    pub fn new(mut tokens: Vec<String>) -> Self {
+        assert!(tokens.len() <= VOCAB_SIZE, "too many tokens");
+        let used = tokens.len();
+        let mut nodes = vec![Node::default()];
+
+        for (id, token) in tokens.iter().enumerate() {
+            let mut node = 0;
+            for byte in token.replace('_', " ").bytes() {
+                node = match nodes[node].child(byte) {
+                    Some(child) => child,
+                    None => {
+                        nodes.push(Node::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.push((byte, child));
+                        child
+                    }
+                };
+            }
+            // If a token appears twice, the machine picks the first.
+            nodes[node].token.get_or_insert(id);
+        }
+        tokens.extend((used..VOCAB_SIZE).map(|id| format!("<unused_{}>", id)));
+
+        Tokenizer {
+            tokens,
+            used,
+            nodes,
+        }
+    }
+
+    /// Reads the vocabulary from a file, checking it fits the model.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
+        let path = path.as_ref();
+        let missing = |source| LoadError::Missing {
+            path: path.to_path_buf(),
+            source,
+        };
+        let reader = io::BufReader::new(File::open(path).map_err(missing)?);
+        let tokens: Vec<String> = reader.lines().collect::<io::Result<_>>().map_err(missing)?;
+
+        if tokens.len() > VOCAB_SIZE {
+            return Err(LoadError::Vocabulary {
+                path: path.to_path_buf(),
+                tokens: tokens.len(),
+            });
+        }
+        Ok(Tokenizer::new(tokens))
+    }
+
+    /// Number of real tokens; IDs from here up to [`VOCAB_SIZE`] are padding.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn is_unused(&self, id: usize) -> bool {
+        id >= self.used
+    }
+
+    /// The ID of the token written as `token` in the vocabulary.
+    pub fn id(&self, token: &str) -> Option<usize> {
+        self.tokens[..self.used].iter().position(|t| t == token)
+    }
+
+    /// The token's text as written in the vocabulary, with spaces as `_`, or
+    /// its padding name.
+    ///
+    /// # Panics
+    ///
+    /// If `id` is not below [`VOCAB_SIZE`].
+    pub fn token(&self, id: usize) -> &str {
+        &self.tokens[id]
+    }
+
+    /// Splits `text` into token IDs by repeatedly taking the longest matching
+    /// token. The text is trimmed, lowercased and prefixed with a space, as
+    /// the machine does.
+    pub fn encode(&self, text: &str) -> Result<Vec<usize>, TokenizeError> {
+        Ok(self
+            .encode_with_offsets(text)?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect())
+    }
+
+    /// Like [`Tokenizer::encode`], but also returns the byte range of `text`
+    /// each token came from. The space added in front belongs to the first
+    /// token, which starts where the trimmed text does.
+    pub fn encode_with_offsets(
+        &self,
+        text: &str,
+    ) -> Result<Vec<(usize, Range<usize>)>, TokenizeError> {
+        let (bytes, origin) = normalize(text);
+
+        let mut ans = Vec::new();
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let mut node = 0;
+            let mut best = None;
+            for (len, &byte) in bytes[pos..].iter().enumerate() {
+                match self.nodes[node].child(byte) {
+                    Some(child) => node = child,
+                    None => break,
+                }
+                if let Some(id) = self.nodes[node].token {
+                    best = Some((id, len + 1));
+                }
+            }
+
+            let Some((id, len)) = best else {
+                return Err(TokenizeError::at(text, origin[pos]));
+            };
+            ans.push((id, origin[pos]..origin[pos + len]));
+            pos += len;
+        }
+
+        Ok(ans)
+    }
+
+    /// Joins token IDs back into text. Padding IDs come out as their names,
+    /// and IDs the model can't produce as `<invalid_N>`.
+    pub fn decode(&self, ids: &[usize]) -> String {
+        let mut text = String::new();
+        for &id in ids {
+            match self.tokens.get(id) {
+                Some(token) if id < self.used => text.push_str(&token.replace('_', " ")),
+                Some(name) => text.push_str(name),
+                None => text.push_str(&format!("<invalid_{}>", id)),
+            }
+        }
+        text
+    }
+}
+
+/// Prompts to try both tokenizers on when no file of them is given.
+pub const SAMPLE_PROMPTS: &[&str] = &[
+    "hello there",
+    "what is your favorite color",
+    "how do i make a cake?",
+    "I feel sad today, can you help me?",
+    "tell me about the weather outside",
+    "what's your favorite food; do you like cooking?",
+    "it's raining and i'm bored",
+    "do you think robots can feel happy!",
+    "my friend's birthday is tomorrow - any ideas",
+    "Why is the sky blue",
+];
+
+// Entry kinds in the in-game trie, stored in bits 14-19 of each word. Other
+// values are the code of the character leading to the child node.
+const ENTER: u32 = 0;
+const TOKEN: u32 = 1;
+const END: u32 = 2;
+const LEAF: u32 = 3;
+
+/// Ends a token's spelling in `tokens/chars`.
+const SPELLING_END: u32 = 1;
+
+/// The characters the keyboard can type, by their 6-bit code.
+fn code_char(code: u32) -> Option<char> {
+    match code {
+        16..=22 => Some(b"!?.,;-'"[code as usize - 16] as char),
+        32..=57 => Some((b'a' + (code - 32) as u8) as char),
+        63 => Some(' '),
+        _ => None,
+    }
+}
+
+fn char_code(c: u8) -> Option<u32> {
+    match c {
+        b'a'..=b'z' => Some(32 + (c - b'a') as u32),
+        b' ' => Some(63),
+        _ => b"!?.,;-'"
+            .iter()
+            .position(|&p| p == c)
+            .map(|i| 16 + i as u32),
+    }
+}
+
+/// The tokenizer built into the world, read from the `tokens/trie`,
+/// `tokens/chars` and `tokens/indices` ROMs.
+///
+/// The trie is a list of 24-bit words per node: optionally the node's token
+/// first (`TOKEN`, or `LEAF` for a node with no children), then one word per
+/// child holding its character code and address, then `END`. The root has an
+/// extra `ENTER` child leading to `_[/inst]`. The machine buffers the typed
+/// text and repeatedly emits the longest token it can walk to, then carries
+/// on from the character after it, so it should agree with [`Tokenizer`].
+///
+/// `chars` spells each token out as character codes, starting at the offset
+/// `indices` gives for it, for the screen.
+pub struct RomTokenizer {
+    trie: Vec<u32>,
+    chars: Vec<u32>,
+    indices: Vec<u32>,
+}
+
+impl RomTokenizer {
+    pub fn new(source: &dyn WeightSource) -> Result<Self, LoadError> {
+        let words = |name, size| -> Result<Vec<u32>, LoadError> {
+            Ok(u24_words(&source.read(name, size)?).collect())
+        };
+        let tokenizer = RomTokenizer {
+            trie: words("tokens/trie", TOKEN_TRIE_SIZE)?,
+            chars: words("tokens/chars", TOKEN_CHARS_SIZE)?,
+            indices: words("tokens/indices", TOKEN_INDICES_SIZE)?,
+        };
+
+        if let Err(reason) = tokenizer.check_node(0, 0) {
+            return Err(LoadError::BadTable {
+                path: source.locate("tokens/trie"),
+                reason,
+            });
+        }
+        if let Some(id) = (0..tokenizer.indices.len())
+            .find(|&id| tokenizer.indices[id] as usize >= tokenizer.chars.len())
+        {
+            return Err(LoadError::BadTable {
+                path: source.locate("tokens/indices"),
+                reason: format!("token {} starts past the end of the characters", id),
+            });
+        }
+
+        Ok(tokenizer)
+    }
+
+    /// Makes sure every node ends inside the table and the trie has no
+    /// loops, so walking it can't go wrong later.
+    fn check_node(&self, addr: usize, depth: usize) -> Result<(), String> {
+        if depth > 64 {
+            return Err("the trie loops or is more than 64 characters deep".to_string());
+        }
+        for (addr, &word) in self.trie.iter().enumerate().skip(addr) {
+            let (code, value) = (word >> 14, (word & 0x3fff) as usize);
+            match code {
+                TOKEN => {}
+                END | LEAF => return Ok(()),
+                _ if code == ENTER || code_char(code).is_some() => {
+                    if value >= self.trie.len() {
+                        return Err(format!("entry {} points past the end", addr));
+                    }
+                    self.check_node(value, depth + 1)?;
+                }
+                _ => return Err(format!("entry {} has unknown kind {}", addr, code)),
+            }
+        }
+        Err(format!("the node at {} never ends", addr))
+    }
+
+    /// The longest token at the start of `codes`, and how many codes it
+    /// takes.
+    fn longest(&self, codes: &[u32]) -> Option<(usize, usize)> {
+        let mut best = None;
+        let mut addr = 0;
+        for depth in 0.. {
+            let mut next = None;
+            for &word in &self.trie[addr..] {
+                let (code, value) = (word >> 14, (word & 0x3fff) as usize);
+                match code {
+                    TOKEN => best = Some((value, depth)),
+                    LEAF => {
+                        best = Some((value, depth));
+                        break;
+                    }
+                    END => break,
+                    _ if codes.get(depth) == Some(&code) => {
+                        next = Some(value);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            match next {
+                Some(child) => addr = child,
+                None => break,
+            }
+        }
+        best
+    }
+
+    /// Splits `text` into token IDs the way the machine does, after the same
+    /// trimming, lowercasing and leading space as [`Tokenizer::encode`]. The
+    /// `_[/inst]` the enter key adds isn't included.
+    pub fn encode(&self, text: &str) -> Result<Vec<usize>, TokenizeError> {
+        let (bytes, origin) = normalize(text);
+        let codes = bytes
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| char_code(b).ok_or_else(|| TokenizeError::at(text, origin[i])))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut ans = Vec::new();
+        let mut pos = 0;
+        while pos < codes.len() {
+            let Some((id, len)) = self.longest(&codes[pos..]).filter(|&(_, len)| len > 0) else {
+                return Err(TokenizeError::at(text, origin[pos]));
+            };
+            ans.push(id);
+            pos += len;
+        }
+        Ok(ans)
+    }
+
+    /// The token spelled exactly `text`, with real spaces, if there is one.
+    pub fn lookup(&self, text: &str) -> Option<usize> {
+        let codes = text.bytes().map(char_code).collect::<Option<Vec<_>>>()?;
+        match self.longest(&codes) {
+            Some((id, len)) if len == codes.len() => Some(id),
+            _ => None,
+        }
+    }
+
+    /// How the screen spells token `id`, or `None` if it isn't made of
+    /// characters, as for the instruction markers and unused IDs.
+    pub fn spelling(&self, id: usize) -> Option<String> {
+        let start = *self.indices.get(id)? as usize;
+        let mut text = String::new();
+        for &code in &self.chars[start..] {
+            match code {
+                SPELLING_END => return Some(text),
+                _ => text.push(code_char(code)?),
+            }
+        }
+        None
+    }
+}
+
+/// A way the ROM tokenizer disagrees with [`Tokenizer`], found by
+/// [`RomTokenizer::divergences`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Divergence {
+    /// The ROMs spell token `id` differently, or spell a marker or padding
+    /// ID, which they shouldn't.
+    Spelling {
+        id: usize,
+        text: String,
+        spelling: Option<String>,
+    },
+    /// Walking the trie with the token's text ends somewhere else.
+    Lookup {
+        id: usize,
+        text: String,
+        found: Option<usize>,
+    },
+    /// A prompt tokenizes differently.
+    Prompt {
+        prompt: String,
+        expected: Result<Vec<usize>, TokenizeError>,
+        actual: Result<Vec<usize>, TokenizeError>,
+    },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |ids: &Result<Vec<usize>, TokenizeError>| match ids {
+            Ok(ids) => format!("{:?}", ids),
+            Err(e) => e.to_string(),
+        };
+        match self {
+            Divergence::Spelling { id, text, spelling } => write!(
+                f,
+                "token {} {:?} is spelled {:?} in the ROM",
+                id, text, spelling
+            ),
+            Divergence::Lookup { id, text, found } => {
+                write!(
+                    f,
+                    "token {} {:?} leads to {:?} in the trie",
+                    id, text, found
+                )
+            }
+            Divergence::Prompt {
+                prompt,
+                expected,
+                actual,
+            } => {
+                writeln!(f, "{:?}", prompt)?;
+                writeln!(f, "  tokens.txt: {}", show(expected))?;
+                write!(f, "  ROMs:       {}", show(actual))
+            }
+        }
+    }
+}
+
+impl RomTokenizer {
+    /// Checks the ROMs against `tokenizer`: every token must be spelled the
+    /// same and found by walking the trie, and every prompt must tokenize
+    /// the same. The instruction markers can't be typed or printed, and the
+    /// padding IDs aren't tokens at all, so the ROMs mustn't spell those.
+    pub fn divergences<S: AsRef<str>>(
+        &self,
+        tokenizer: &Tokenizer,
+        template: &ChatTemplate,
+        prompts: &[S],
+    ) -> Vec<Divergence> {
+        let mut divergences = Vec::new();
+
+        for id in 0..VOCAB_SIZE {
+            let text = tokenizer.token(id).replace('_', " ");
+            let spelling = self.spelling(id);
+            if template.is_marker(id) || tokenizer.is_unused(id) {
+                if spelling.is_some() {
+                    divergences.push(Divergence::Spelling { id, text, spelling });
+                }
+                continue;
+            }
+
+            let found = self.lookup(&text);
+            if spelling.as_deref() != Some(text.as_str()) {
+                divergences.push(Divergence::Spelling {
+                    id,
+                    text: text.clone(),
+                    spelling,
+                });
+            }
+            if found != Some(id) {
+                divergences.push(Divergence::Lookup { id, text, found });
+            }
+        }
+
+        for prompt in prompts {
+            let prompt = prompt.as_ref();
+            let expected = tokenizer.encode(prompt);
+            let actual = self.encode(prompt);
+            if expected != actual {
+                divergences.push(Divergence::Prompt {
+                    prompt: prompt.to_string(),
+                    expected,
+                    actual,
+                });
+            }
+        }
+
+        divergences
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ModelPaths;
+
+    /// A tokenizer with as many tokens as the real vocabulary, all of them
+    /// words.
+    fn tokenizer() -> Tokenizer {
+        Tokenizer::new((0..1906).map(|id| format!("_w{}", id)).collect())
+    }
+
+    #[test]
+    fn decodes_words() {
+        assert_eq!(tokenizer().decode(&[0, 1905]), " w0 w1905");
+    }
+
+    #[test]
+    fn padding_decodes_to_its_name() {
+        let tokenizer = tokenizer();
+        assert!(tokenizer.is_unused(1906));
+        assert_eq!(tokenizer.decode(&[1906]), "<unused_1906>");
+        assert_eq!(tokenizer.decode(&[VOCAB_SIZE - 1]), "<unused_1919>");
+    }
+
+    #[test]
+    fn ids_past_the_vocabulary_are_invalid() {
+        let decoded = tokenizer().decode(&[0, VOCAB_SIZE, 1 << 40]);
+        assert_eq!(decoded, " w0<invalid_1920><invalid_1099511627776>");
+    }
+
+    fn vocabulary() -> Tokenizer {
+        Tokenizer::load(ModelPaths::default().tokens).unwrap()
+    }
+
+    /// The scan `main.rs` used before the trie: normalize the prompt, then
+    /// repeatedly take the longest token it starts with, the earliest on a
+    /// tie.
+    fn scan(tokenizer: &Tokenizer, text: &str) -> Option<Vec<usize>> {
+        let spelled: Vec<String> = (0..tokenizer.used())
+            .map(|id| tokenizer.token(id).replace('_', " "))
+            .collect();
+        let prompt = format!(" {}", text.trim().to_lowercase());
+        let mut rest = prompt.as_bytes();
+        let mut ans = Vec::new();
+        while !rest.is_empty() {
+            let mut best: Option<(usize, usize)> = None;
+            for (id, token) in spelled.iter().enumerate() {
+                if rest.starts_with(token.as_bytes())
+                    && token.len() > best.map_or(0, |(_, len)| len)
+                {
+                    best = Some((id, token.len()));
+                }
+            }
+            let (id, len) = best?;
+            ans.push(id);
+            rest = &rest[len..];
+        }
+        Some(ans)
+    }
+
+    #[test]
+    fn encodes_with_a_leading_space_after_trimming() {
+        let tokenizer = vocabulary();
+        let (i, can, space, feel) = (6, 13, 10, 14);
+        assert_eq!(tokenizer.token(can), "_can");
+        assert_eq!(tokenizer.encode("I can feel"), Ok(vec![i, can, feel]));
+        assert_eq!(
+            tokenizer.encode("\t I CAN  feel \n"),
+            Ok(vec![i, can, space, feel])
+        );
+        assert_eq!(tokenizer.encode("   "), Ok(vec![space]));
+    }
+
+    #[test]
+    fn offsets_cover_the_original_text() {
+        let tokenizer = vocabulary();
+        let text = "  I CAN  feel ";
+        let tokens = tokenizer.encode_with_offsets(text).unwrap();
+        // The added space belongs to the first token, which starts at the
+        // first character after the trimmed whitespace.
+        assert_eq!(tokens, vec![(6, 2..3), (13, 3..7), (10, 7..8), (14, 8..13)]);
+        assert_eq!(&text[tokens[1].1.clone()], " CAN");
+    }
+
+    #[test]
+    fn offsets_point_into_the_text_before_lowercasing() {
+        let tokenizer = vocabulary();
+        // 'İ' lowercases to 'i' and a combining dot, three bytes in place of
+        // two; the dot matches nothing, and is reported as the 'İ' typed.
+        let error = tokenizer.encode_with_offsets("CAN İt").unwrap_err();
+        assert_eq!(
+            error,
+            TokenizeError {
+                offset: 4,
+                character: 'İ'
+            }
+        );
+    }
+
+    #[test]
+    fn errors_give_the_byte_offset_of_the_character() {
+        let tokenizer = vocabulary();
+        let error = tokenizer.encode("  caf\u{e9} au lait").unwrap_err();
+        assert_eq!(
+            error,
+            TokenizeError {
+                offset: 5,
+                character: '\u{e9}'
+            }
+        );
+        assert_eq!(error.to_string(), "no token matches '\u{e9}' at byte 5");
+
+        // Uppercase characters are reported as typed.
+        let error = tokenizer.encode("\u{c9}T\u{c9}").unwrap_err();
+        assert_eq!((error.offset, error.character), (0, '\u{c9}'));
+        let error = tokenizer.encode("hi #1").unwrap_err();
+        assert_eq!((error.offset, error.character), (3, '#'));
+    }
+
+    #[test]
+    fn matches_the_old_scan() {
+        let tokenizer = vocabulary();
+        let prompts = [
+            "hello there",
+            "I feel sad today, can you help me?",
+            "what's your favorite food; do you like cooking?",
+            "my friend's birthday is tomorrow - any ideas",
+            "  Why   is the sky blue!  ",
+            "feelings feeling feels",
+        ];
+        for prompt in prompts {
+            assert_eq!(
+                tokenizer.encode(prompt).ok(),
+                scan(&tokenizer, prompt),
+                "{:?}",
+                prompt
+            );
+        }
+        for id in 0..tokenizer.used() {
+            let text = tokenizer.token(id).replace('_', " ");
+            assert_eq!(
+                tokenizer.encode(&text).ok(),
+                scan(&tokenizer, &text),
+                "{:?}",
+                text
+            );
+        }
+    }
+
+    fn rom_tokenizer() -> RomTokenizer {
+        RomTokenizer::new(&ModelPaths::default()).unwrap()
+    }
+
+    #[test]
+    fn the_roms_spell_and_find_every_token() {
+        let tokenizer = vocabulary();
+        let template = ChatTemplate::new(&tokenizer).unwrap();
+        let rom = rom_tokenizer();
+        for id in 0..VOCAB_SIZE {
+            let text = tokenizer.token(id).replace('_', " ");
+            if template.is_marker(id) || tokenizer.is_unused(id) {
+                assert_eq!(rom.spelling(id), None, "{}", id);
+                continue;
+            }
+            assert_eq!(rom.spelling(id).as_deref(), Some(text.as_str()), "{}", id);
+            assert_eq!(rom.lookup(&text), Some(id), "{:?}", text);
+            assert_eq!(rom.encode(&text), tokenizer.encode(&text), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn the_roms_encode_the_sample_prompts_alike() {
+        let tokenizer = vocabulary();
+        let rom = rom_tokenizer();
+        for prompt in SAMPLE_PROMPTS {
+            let expected = tokenizer.encode(prompt);
+            assert!(expected.is_ok(), "{:?}", prompt);
+            assert_eq!(rom.encode(prompt), expected, "{:?}", prompt);
+        }
+        assert_eq!(rom.encode("hi #1"), tokenizer.encode("hi #1"));
+    }
+
+    fn trie(words: &[u32]) -> RomTokenizer {
+        RomTokenizer {
+            trie: words.to_vec(),
+            chars: Vec::new(),
+            indices: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn checks_the_trie_is_well_formed() {
+        let a = char_code(b'a').unwrap() << 14;
+        let b = char_code(b'b').unwrap() << 14;
+        let end = END << 14;
+        // The root leads to "a", a leaf for token 5, and "b", with token 6
+        // and no children.
+        let good = [a | 3, b | 4, end, LEAF << 14 | 5, TOKEN << 14 | 6, end];
+        assert_eq!(trie(&good).check_node(0, 0), Ok(()));
+        assert_eq!(trie(&good).lookup("a"), Some(5));
+        assert_eq!(trie(&good).lookup("b"), Some(6));
+
+        let looped = [a | 2, end, b, end];
+        let error = trie(&looped).check_node(0, 0).unwrap_err();
+        assert!(error.contains("loops"), "{}", error);
+        let outside = [a | 2, end];
+        assert_eq!(
+            trie(&outside).check_node(0, 0),
+            Err("entry 0 points past the end".to_string())
+        );
+        let unended = [a | 1, TOKEN << 14 | 5];
+        assert_eq!(
+            trie(&unended).check_node(0, 0),
+            Err("the node at 1 never ends".to_string())
+        );
+        let unknown = [4 << 14, end];
+        assert_eq!(
+            trie(&unknown).check_node(0, 0),
+            Err("entry 0 has unknown kind 4".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_divergences() {
+        let tokenizer = vocabulary();
+        let template = ChatTemplate::new(&tokenizer).unwrap();
+        let rom = rom_tokenizer();
+        assert_eq!(rom.divergences(&tokenizer, &template, SAMPLE_PROMPTS), []);
+
+        // A tokenizer whose first real token is misspelled.
+        let mut tokens: Vec<String> = (0..tokenizer.used())
+            .map(|id| tokenizer.token(id).to_string())
+            .collect();
+        let id = tokenizer.id("_can").unwrap();
+        tokens[id] = "_cab".to_string();
+        let wrong = Tokenizer::new(tokens);
+        let divergences = rom.divergences(&wrong, &template, &["I can"]);
+        assert_eq!(
+            divergences,
+            [
+                Divergence::Spelling {
+                    id,
+                    text: " cab".to_string(),
+                    spelling: Some(" can".to_string()),
+                },
+                Divergence::Lookup {
+                    id,
+                    text: " cab".to_string(),
+                    found: None,
+                },
+                Divergence::Prompt {
+                    prompt: "I can".to_string(),
+                    expected: wrong.encode("I can"),
+                    actual: Ok(vec![6, id]),
+                },
+            ]
+        );
+        assert_eq!(
+            divergences[0].to_string(),
+            "token 13 \" cab\" is spelled Some(\" can\") in the ROM"
+        );
+    }
+}
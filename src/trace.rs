@@ -0,0 +1,170 @@
+//! Recording the intermediate activations of [`crate::Model::predict_traced`],
+//! for comparing the emulator against register readouts from a world.
+//!
+//! Tensors are named by where they come from: `embedding`, then for each
+//! block `blockN.ln_1`, `blockN.headH.scores` (the scaled attention scores
+//! for every cached position), `blockN.headH.weights` (the softmax of the
+//! scores), `blockN.attn` (the attention output added to the residual),
+//! `blockN.ln_2`, `blockN.mlp_hidden` (after the ReLU), `blockN.mlp` and
+//! `blockN` (the residual stream after the block), and finally `ln_f` and
+//! `logits`. Values are the raw 24-bit words the machine holds, two's
+//! complement with 18 fractional bits, except the attention weights, which
+//! have 22.
+//!
+//! The binary dump is laid out as follows, all integers little-endian:
+//!
+//! ```text
+//! magic          8 bytes  "CGPTTRCE"
+//! version        u32
+//! token count    u32
+//! tokens         token ID (u32), position (u32), tensor count (u32),
+//!                tensors
+//! tensor         name length (u16), name (UTF-8), value count (u32),
+//!                values (3 bytes each)
+//! ```
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::Fixed24;
+
+const MAGIC: &[u8; 8] = b"CGPTTRCE";
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Receives the intermediate tensors of each token as they are computed.
+pub trait Tracer {
+    /// Called before the first tensor of each token.
+    fn begin(&mut self, _token: usize, _position: usize) {}
+
+    fn record(&mut self, name: &str, values: &[Fixed24]);
+
+    /// Whether anything is recorded at all. Callers skip building names for
+    /// a tracer that isn't.
+    fn enabled(&self) -> bool {
+        true
+    }
+}
+
+/// Traces nothing.
+impl Tracer for () {
+    fn record(&mut self, _name: &str, _values: &[Fixed24]) {}
+
+    fn enabled(&self) -> bool {
+        false
+    }
+}
+
+/// Prefixes the names of everything recorded through it with a numbered
+/// scope, e.g. `block3`.
+pub(crate) struct Scoped<'a> {
+    inner: &'a mut dyn Tracer,
+    scope: &'static str,
+    index: usize,
+}
+
+impl<'a> Scoped<'a> {
+    pub(crate) fn new(inner: &'a mut dyn Tracer, scope: &'static str, index: usize) -> Self {
+        Scoped {
+            inner,
+            scope,
+            index,
+        }
+    }
+}
+
+impl Tracer for Scoped<'_> {
+    fn record(&mut self, name: &str, values: &[Fixed24]) {
+        if self.inner.enabled() {
+            let name = format!("{}{}.{}", self.scope, self.index, name);
+            self.inner.record(&name, values);
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.inner.enabled()
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Tensor {
+    pub name: String,
+    pub values: Vec<Fixed24>,
+}
+
+/// Everything recorded while processing one token.
+#[derive(Clone, Debug, Serialize)]
+pub struct TokenTrace {
+    pub token: usize,
+    pub position: usize,
+    pub tensors: Vec<Tensor>,
+}
+
+impl TokenTrace {
+    pub fn get(&self, name: &str) -> Option<&[Fixed24]> {
+        self.tensors
+            .iter()
+            .find(|t| t.name == name)
+            .map(|t| t.values.as_slice())
+    }
+}
+
+/// A [`Tracer`] that keeps every tensor of every token.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ProcessTrace {
+    pub tokens: Vec<TokenTrace>,
+}
+
+impl Tracer for ProcessTrace {
+    fn begin(&mut self, token: usize, position: usize) {
+        self.tokens.push(TokenTrace {
+            token,
+            position,
+            tensors: Vec::new(),
+        });
+    }
+
+    fn record(&mut self, name: &str, values: &[Fixed24]) {
+        let token = self
+            .tokens
+            .last_mut()
+            .expect("tensor recorded before the first token");
+        token.tensors.push(Tensor {
+            name: name.to_string(),
+            values: values.to_vec(),
+        });
+    }
+}
+
+impl ProcessTrace {
+    pub fn save_json<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut w, self)?;
+        w.flush()
+    }
+
+    pub fn save_binary<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(MAGIC)?;
+        w.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        w.write_all(&(self.tokens.len() as u32).to_le_bytes())?;
+
+        for token in &self.tokens {
+            w.write_all(&(token.token as u32).to_le_bytes())?;
+            w.write_all(&(token.position as u32).to_le_bytes())?;
+            w.write_all(&(token.tensors.len() as u32).to_le_bytes())?;
+            for tensor in &token.tensors {
+                w.write_all(&(tensor.name.len() as u16).to_le_bytes())?;
+                w.write_all(tensor.name.as_bytes())?;
+                w.write_all(&(tensor.values.len() as u32).to_le_bytes())?;
+                for v in &tensor.values {
+                    w.write_all(&v.to_le_bytes()[..3])?;
+                }
+            }
+        }
+
+        w.flush()
+    }
+}
@@ -1,10 +1,10 @@
-use std::fs::File;
-use std::io::Read;
-
-use crate::matmul::MatMul;
+use crate::distribution::Distribution;
+use crate::error::LoadError;
+use crate::matmul::{MatMul, set_row};
+use crate::weights::{MATRIX_ROM_SIZE, WeightSource, read_softmax_table};
 use crate::{EMBED_SIZE, FIXED_POINT_MASK, FIXED_POINT_SIZE, Fixed24};
 
-const OUTPUT_SIZE: usize = 8;
+pub const OUTPUT_SIZE: usize = 8;
 pub const VOCAB_SIZE: usize = 1920;
 
 pub struct Unembedding {
@@ -13,68 +13,63 @@ pub struct Unembedding {
 }
 
 impl Unembedding {
-    pub fn new() -> Self {
-        let mut weights = vec![vec![]; VOCAB_SIZE];
+    pub fn new(source: &dyn WeightSource) -> Result<Self, LoadError> {
+        let mut weights = vec![0u8; VOCAB_SIZE * EMBED_SIZE];
 
         for i in 0..48 {
-            let path = format!("weights/weight_files/unembedding/lm_head_{}.bin", i + 1);
-            let mut file = File::open(path).expect("couldn't read file");
-            let mut cur_weights = vec![0u8; 9600];
-            file.read_exact(&mut cur_weights)
-                .expect("couldn't read into buffer");
+            let name = format!("unembedding/lm_head_{}", i + 1);
+            let cur_weights = source.read(&name, MATRIX_ROM_SIZE)?;
+            let row = |n: usize| &cur_weights[EMBED_SIZE * n..EMBED_SIZE * (n + 1)];
 
-            for j in 0..20 {
-                if i % 2 == 0 {
-                    weights[48 * j + 2 * (i % 24) + 960 * (i / 24)] =
-                        cur_weights[EMBED_SIZE * j..EMBED_SIZE * (j + 1)].to_vec();
-                    weights[48 * j + 2 * (i % 24) + 960 * (i / 24) + 1] =
-                        cur_weights[EMBED_SIZE * (j + 20)..EMBED_SIZE * (j + 21)].to_vec();
-                } else {
-                    weights[48 * j + 2 * (i % 24) + 960 * (i / 24) + 1] =
-                        cur_weights[EMBED_SIZE * j..EMBED_SIZE * (j + 1)].to_vec();
-                    weights[48 * j + 2 * (i % 24) + 960 * (i / 24)] =
-                        cur_weights[EMBED_SIZE * (j + 20)..EMBED_SIZE * (j + 21)].to_vec();
-                }
-            }
-        }
+            let base = 2 * (i % 24) + 960 * (i / 24);
+            let (first, second) = if i % 2 == 0 {
+                (base, base + 1)
+            } else {
+                (base + 1, base)
+            };
 
-        let mut weights_array: [[u8; EMBED_SIZE]; VOCAB_SIZE] = [[0; EMBED_SIZE]; VOCAB_SIZE];
-        for i in 0..VOCAB_SIZE {
-            for j in 0..EMBED_SIZE {
-                weights_array[i][j] = weights[i][j];
+            for j in 0..20 {
+                set_row(&mut weights, 48 * j + first, row(j));
+                set_row(&mut weights, 48 * j + second, row(j + 20));
             }
         }
 
-        let lm_head = MatMul::new(&weights_array, false);
+        let lm_head = MatMul::new(weights, false);
 
-        let mut softmax_exp = [0u32; 1024];
-        let mut file =
-            File::open("weights/weight_files/softmax_2.bin").expect("couldn't read file");
-        for i in 0..1024 {
-            let mut buf = [0u8; 3];
-            file.read_exact(&mut buf)
-                .expect("couldn't read into buffer");
-            softmax_exp[i] = u32::from_le_bytes([buf[0], buf[1], buf[2], 0]);
-        }
+        let softmax_exp = read_softmax_table(source, "softmax_2")?;
 
-        Unembedding {
+        Ok(Unembedding {
             lm_head,
             softmax_exp,
-        }
+        })
     }
 
     pub fn forward(&self, input: &[Fixed24; EMBED_SIZE]) -> Vec<u64> {
-        let mut logits = self.lm_head.forward(input);
+        self.distribution(self.logits(input)).packed().to_vec()
+    }
 
+    pub fn logits(&self, input: &[Fixed24; EMBED_SIZE]) -> [Fixed24; VOCAB_SIZE] {
+        self.lm_head.forward(input)
+    }
+
+    pub fn distribution(&self, logits: [Fixed24; VOCAB_SIZE]) -> Distribution {
+        let probs = self.probabilities(&logits);
+        Distribution::new(logits, probs)
+    }
+
+    /// Runs the softmax circuit over `logits`, giving every token's
+    /// probability scaled by 2^23.
+    pub fn probabilities(&self, logits: &[Fixed24; VOCAB_SIZE]) -> Vec<u32> {
+        let mut logits = *logits;
         let mut biggest = 0u32;
-        for i in 0..VOCAB_SIZE {
-            logits[i] ^= 1 << (FIXED_POINT_SIZE - 1);
-            biggest = biggest.max(logits[i]);
+        for logit in &mut logits {
+            *logit ^= 1 << (FIXED_POINT_SIZE - 1);
+            biggest = biggest.max(*logit);
         }
 
         let mut softmax_sum: u32 = 0;
-        for i in 0..VOCAB_SIZE {
-            let power = (biggest - logits[i]) >> 12;
+        for &logit in &logits {
+            let power = (biggest - logit) >> 12;
             let res = if power >= 1024 {
                 0
             } else {
@@ -84,26 +79,93 @@ impl Unembedding {
         }
         let softmax_sum = (1u64 << 46) / softmax_sum as u64;
 
-        let mut output = vec![0u64; OUTPUT_SIZE];
-        for i in 0..VOCAB_SIZE {
-            let power = (biggest - logits[i]) >> 12;
+        let mut probs = Vec::with_capacity(VOCAB_SIZE);
+        for &logit in &logits {
+            let power = (biggest - logit) >> 12;
             let res = if power >= 1024 {
                 0
             } else {
                 self.softmax_exp[power as usize]
             };
+            probs.push(((softmax_sum * res as u64) >> 23) as u32 & FIXED_POINT_MASK);
+        }
+
+        probs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ModelPaths;
+    use crate::layout::{Matrix, matrix_rows};
+
+    #[test]
+    fn rom_rows_match_the_loader() {
+        let paths = ModelPaths::default();
+        let unembedding = Unembedding::new(&paths).unwrap();
+        let rows = matrix_rows(Matrix::LmHead, &paths);
+        assert_eq!(rows.concat(), unembedding.lm_head.weights());
+    }
+
+    /// `Unembedding::forward` from the original `main.rs`.
+    fn original_forward(unembedding: &Unembedding, input: &[Fixed24; EMBED_SIZE]) -> Vec<u64> {
+        let mut logits = unembedding.lm_head.forward(input);
+
+        let mut biggest = 0u32;
+        for logit in &mut logits {
+            *logit ^= 1 << (FIXED_POINT_SIZE - 1);
+            biggest = biggest.max(*logit);
+        }
+
+        let mut softmax_sum: u32 = 0;
+        for &logit in &logits {
+            let power = (biggest - logit) >> 12;
+            let res = if power >= 1024 {
+                0
+            } else {
+                unembedding.softmax_exp[power as usize]
+            };
+            softmax_sum = softmax_sum.wrapping_add(res);
+        }
+        let softmax_sum = (1u64 << 46) / softmax_sum as u64;
+
+        let mut output = vec![0u64; OUTPUT_SIZE];
+        for (i, &logit) in logits.iter().enumerate() {
+            let power = (biggest - logit) >> 12;
+            let res = if power >= 1024 {
+                0
+            } else {
+                unembedding.softmax_exp[power as usize]
+            };
             let mut res = ((softmax_sum * res as u64) >> 23) & FIXED_POINT_MASK as u64;
             res = (1 << 11) * res + i as u64;
 
-            for j in 0..OUTPUT_SIZE {
-                if res > output[j] {
-                    let tmp = output[j];
-                    output[j] = res;
-                    res = tmp;
+            for slot in &mut output {
+                if res > *slot {
+                    std::mem::swap(slot, &mut res);
                 }
             }
         }
-
         output
     }
+
+    #[test]
+    fn forward_matches_the_original() {
+        let unembedding = Unembedding::new(&ModelPaths::default()).unwrap();
+        let mut rng = crate::PRNG::new(12345);
+        for scale in [0, 14, 16, 18] {
+            // Small signed inputs, up to 2^scale in the fixed point.
+            let input: [Fixed24; EMBED_SIZE] = std::array::from_fn(|_| {
+                let x = (rng.next() >> (23 - scale)) as i32 - (1 << scale) / 2;
+                x as u32 & FIXED_POINT_MASK
+            });
+            assert_eq!(
+                unembedding.forward(&input),
+                original_forward(&unembedding, &input),
+                "scale {}",
+                scale
+            );
+        }
+    }
 }
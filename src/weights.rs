@@ -0,0 +1,185 @@
+use std::borrow::Cow;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::EMBED_SIZE;
+use crate::error::LoadError;
+
+/// Environment variable naming the directory holding the weight files.
+pub const WEIGHTS_DIR_VAR: &str = "CRAFTGPT_WEIGHTS_DIR";
+/// Environment variable naming the vocabulary file.
+pub const TOKENS_VAR: &str = "CRAFTGPT_TOKENS";
+/// Environment variable naming the world layout file.
+pub const LAYOUT_VAR: &str = "CRAFTGPT_LAYOUT";
+
+const DEFAULT_WEIGHTS_DIR: &str = "weights/weight_files";
+const DEFAULT_TOKENS: &str = "tokens.txt";
+const DEFAULT_LAYOUT: &str = "weights/layout.json";
+
+/// Size of the attention, MLP and unembedding matrix ROMs.
+pub(crate) const MATRIX_ROM_SIZE: usize = 9600;
+/// Size of a layernorm ROM: one 24-bit weight per embedding lane.
+pub(crate) const LAYERNORM_ROM_SIZE: usize = EMBED_SIZE * 3;
+/// Size of an embedding ROM: 32 rows of 24-bit values.
+pub(crate) const EMBEDDING_ROM_SIZE: usize = 32 * EMBED_SIZE * 3;
+/// Size of a softmax table: 1024 24-bit entries.
+pub(crate) const SOFTMAX_TABLE_SIZE: usize = 1024 * 3;
+/// Sizes of the in-game tokenizer's ROMs, in 24-bit words: the trie, the
+/// spelling of each token, and where each token's spelling starts.
+pub(crate) const TOKEN_TRIE_SIZE: usize = 16384 * 3;
+pub(crate) const TOKEN_CHARS_SIZE: usize = 16384 * 3;
+pub(crate) const TOKEN_INDICES_SIZE: usize = 2048 * 3;
+
+/// Names and sizes of every ROM [`crate::Model::new`] reads, in load order.
+pub fn model_roms() -> Vec<(String, usize)> {
+    let mut roms = Vec::new();
+    for i in 1..=60 {
+        roms.push((format!("embedding/wte_{}", i), EMBEDDING_ROM_SIZE));
+    }
+    for i in 1..=2 {
+        roms.push((format!("embedding/wpe_{}", i), EMBEDDING_ROM_SIZE));
+    }
+    for i in 1..=13 {
+        roms.push((format!("layernorm/ln_{}", i), LAYERNORM_ROM_SIZE));
+    }
+    for i in 1..=144 {
+        roms.push((format!("attention/att_{}", i), MATRIX_ROM_SIZE));
+    }
+    for i in 1..=288 {
+        roms.push((format!("mlp/mlp_{}", i), MATRIX_ROM_SIZE));
+    }
+    for i in 1..=48 {
+        roms.push((format!("unembedding/lm_head_{}", i), MATRIX_ROM_SIZE));
+    }
+    roms.push(("softmax".to_string(), SOFTMAX_TABLE_SIZE));
+    roms.push(("softmax_2".to_string(), SOFTMAX_TABLE_SIZE));
+    roms
+}
+
+/// Somewhere the model's ROMs can be read from.
+///
+/// ROMs are named the way `layout.json` names them, e.g. `mlp/mlp_25` or
+/// `embedding/wte_1`, plus the two softmax tables `softmax` and `softmax_2`
+/// and the tokenizer's `tokens/trie`, `tokens/chars` and `tokens/indices`.
+pub trait WeightSource {
+    /// Reads the ROM `name`, which must be exactly `size` bytes long.
+    fn read(&self, name: &str, size: usize) -> Result<Cow<'_, [u8]>, LoadError>;
+
+    /// Where `name` lives, for error messages.
+    fn locate(&self, name: &str) -> PathBuf;
+}
+
+/// Where to find a weight set on disk.
+#[derive(Clone, Debug)]
+pub struct ModelPaths {
+    /// Directory containing `attention/`, `mlp/`, `softmax.bin` and so on.
+    pub weights_dir: PathBuf,
+    /// The vocabulary, one token per line.
+    pub tokens: PathBuf,
+    /// Where each ROM sits in the world; see [`crate::layout`].
+    pub layout: PathBuf,
+}
+
+impl ModelPaths {
+    pub fn new<W: Into<PathBuf>, T: Into<PathBuf>>(weights_dir: W, tokens: T) -> Self {
+        ModelPaths {
+            weights_dir: weights_dir.into(),
+            tokens: tokens.into(),
+            layout: PathBuf::from(DEFAULT_LAYOUT),
+        }
+    }
+
+    /// Reads the paths from `CRAFTGPT_WEIGHTS_DIR`, `CRAFTGPT_TOKENS` and
+    /// `CRAFTGPT_LAYOUT`, falling back to the repository layout relative to
+    /// the current directory.
+    pub fn from_env() -> Self {
+        let var = |name, default| {
+            env::var_os(name)
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from(default))
+        };
+        ModelPaths {
+            weights_dir: var(WEIGHTS_DIR_VAR, DEFAULT_WEIGHTS_DIR),
+            tokens: var(TOKENS_VAR, DEFAULT_TOKENS),
+            layout: var(LAYOUT_VAR, DEFAULT_LAYOUT),
+        }
+    }
+}
+
+impl Default for ModelPaths {
+    fn default() -> Self {
+        ModelPaths::new(DEFAULT_WEIGHTS_DIR, DEFAULT_TOKENS)
+    }
+}
+
+impl WeightSource for ModelPaths {
+    fn read(&self, name: &str, size: usize) -> Result<Cow<'_, [u8]>, LoadError> {
+        read_rom(&self.locate(name), size).map(Cow::Owned)
+    }
+
+    fn locate(&self, name: &str) -> PathBuf {
+        self.weights_dir.join(format!("{}.bin", name))
+    }
+}
+
+/// Reads a whole ROM file, which must be exactly `size` bytes long.
+pub(crate) fn read_rom(path: &Path, size: usize) -> Result<Vec<u8>, LoadError> {
+    let bytes = fs::read(path).map_err(|source| LoadError::Missing {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    if bytes.len() < size {
+        return Err(LoadError::ShortRead {
+            path: path.to_path_buf(),
+            expected: size,
+            actual: bytes.len(),
+        });
+    }
+    if bytes.len() > size {
+        return Err(LoadError::WrongSize {
+            path: path.to_path_buf(),
+            expected: size,
+            actual: bytes.len(),
+        });
+    }
+
+    Ok(bytes)
+}
+
+/// Splits a ROM into the little-endian 24-bit words it stores.
+pub(crate) fn u24_words(bytes: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    bytes
+        .chunks_exact(3)
+        .map(|buf| u32::from_le_bytes([buf[0], buf[1], buf[2], 0]))
+}
+
+/// Reads one of the 1024-entry exponential tables used by the softmax
+/// circuits. The first entry is the weight of the largest input, so it must be
+/// non-zero, and the entries must never increase.
+pub(crate) fn read_softmax_table(
+    source: &dyn WeightSource,
+    name: &str,
+) -> Result<[u32; 1024], LoadError> {
+    let bytes = source.read(name, SOFTMAX_TABLE_SIZE)?;
+    let mut table = [0u32; 1024];
+    for (i, w) in u24_words(&bytes).enumerate() {
+        table[i] = w;
+    }
+
+    if table[0] == 0 {
+        return Err(LoadError::BadTable {
+            path: source.locate(name),
+            reason: "first entry is zero".to_string(),
+        });
+    }
+    if let Some(i) = (1..1024).find(|&i| table[i] > table[i - 1]) {
+        return Err(LoadError::BadTable {
+            path: source.locate(name),
+            reason: format!("entry {} is larger than entry {}", i, i - 1),
+        });
+    }
+
+    Ok(table)
+}
@@ -0,0 +1,115 @@
+//! Packing the weights into a bundle and reading them back.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use craftgpt::{Bundle, LoadError, ModelPaths, WeightSource, bundle};
+
+/// A scratch directory, removed when dropped.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("craftgpt-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        Scratch(dir)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Every `.bin` file under `dir`, by its path relative to `root`.
+fn bins(root: &Path, dir: &Path, found: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            bins(root, &path, found);
+        } else if path.extension().is_some_and(|e| e == "bin") {
+            found.push(path.strip_prefix(root).unwrap().to_path_buf());
+        }
+    }
+}
+
+#[test]
+fn unpacking_gives_back_the_weights() {
+    let scratch = Scratch::new("bundle-round-trip");
+    let weights = ModelPaths::default().weights_dir;
+    let path = scratch.0.join("weights.cgpt");
+
+    let count = bundle::pack(&weights, &path).unwrap();
+    let bundle = Bundle::open(&path).unwrap();
+    assert_eq!(bundle.tensors().len(), count);
+    bundle.verify().unwrap();
+
+    let mut files = Vec::new();
+    bins(&weights, &weights, &mut files);
+    assert_eq!(files.len(), count);
+    let expected = fs::read(weights.join("mlp/mlp_25.bin")).unwrap();
+    assert_eq!(bundle.get("mlp/mlp_25").unwrap(), expected);
+
+    let out = scratch.0.join("unpacked");
+    bundle.unpack(&out).unwrap();
+    for file in &files {
+        let expected = fs::read(weights.join(file)).unwrap();
+        let actual = fs::read(out.join(file)).unwrap();
+        assert!(expected == actual, "{} differs", file.display());
+    }
+}
+
+#[test]
+fn a_flipped_data_byte_fails_its_checksum() {
+    let scratch = Scratch::new("bundle-flipped");
+    let path = scratch.0.join("weights.cgpt");
+    bundle::pack(ModelPaths::default().weights_dir, &path).unwrap();
+
+    let tensor = Bundle::open(&path).unwrap().tensors()[10].clone();
+    let mut bytes = fs::read(&path).unwrap();
+    bytes[tensor.offset + tensor.size / 2] ^= 0x10;
+    fs::write(&path, bytes).unwrap();
+
+    // The header is intact, so the bundle opens; only the tensor is bad.
+    let bundle = Bundle::open(&path).unwrap();
+    match bundle.get(&tensor.name) {
+        Err(LoadError::Checksum {
+            path: at, expected, ..
+        }) => {
+            assert_eq!(at, path.join(&tensor.name));
+            assert_eq!(expected, tensor.crc);
+        }
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("{} passed its checksum", tensor.name),
+    }
+    assert!(matches!(bundle.verify(), Err(LoadError::Checksum { .. })));
+}
+
+#[test]
+fn reads_must_ask_for_the_stored_size() {
+    let scratch = Scratch::new("bundle-sizes");
+    let path = scratch.0.join("weights.cgpt");
+    bundle::pack(ModelPaths::default().weights_dir, &path).unwrap();
+    let bundle = Bundle::open(&path).unwrap();
+
+    let size = bundle.get("mlp/mlp_1").unwrap().len();
+    assert_eq!(bundle.read("mlp/mlp_1", size).unwrap().len(), size);
+    match bundle.read("mlp/mlp_1", size + 1) {
+        Err(LoadError::ShortRead {
+            expected, actual, ..
+        }) => assert_eq!((expected, actual), (size + 1, size)),
+        other => panic!("unexpected result {:?}", other.map(|b| b.len())),
+    }
+    match bundle.read("mlp/mlp_1", size - 1) {
+        Err(LoadError::WrongSize {
+            expected, actual, ..
+        }) => assert_eq!((expected, actual), (size - 1, size)),
+        other => panic!("unexpected result {:?}", other.map(|b| b.len())),
+    }
+    assert!(matches!(
+        bundle.read("mlp/nonexistent", 1),
+        Err(LoadError::Missing { .. })
+    ));
+}
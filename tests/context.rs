@@ -0,0 +1,23 @@
+//! Runs the real model well past its context size. Slow in a debug build, so
+//! run it with `cargo test --release -- --ignored`.
+
+use craftgpt::{CONTEXT_SIZE, ContextPolicy, Model, ModelPaths, Tokenizer};
+
+#[test]
+#[ignore]
+fn both_policies_run_past_1024_tokens_and_agree() {
+    let paths = ModelPaths::default();
+    let tokenizer = Tokenizer::load(&paths.tokens).unwrap();
+    let text = "[INST] tell me about the weather today [/INST] it is sunny and warm .";
+    let ids = tokenizer.encode(text).unwrap();
+    let mut faithful = Model::new(&paths).unwrap();
+    let mut window = Model::new(&paths).unwrap();
+    window.set_context_policy(ContextPolicy::SlidingWindow(CONTEXT_SIZE));
+
+    for (i, &token) in ids.iter().cycle().take(1030).enumerate() {
+        let a = faithful.predict(token);
+        let b = window.predict(token);
+        assert_eq!(a.packed(), b.packed(), "token {}", i);
+    }
+    assert_eq!(faithful.index(), 1030);
+}
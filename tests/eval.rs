@@ -0,0 +1,46 @@
+//! Scores a tiny corpus with the real weights.
+
+use craftgpt::eval::{Evaluation, evaluate};
+use craftgpt::{Model, ModelPaths, Tokenizer};
+
+const CORPUS: [&str; 2] = [
+    "[INST] hello there [/INST] hi!",
+    "[INST] thanks [/INST] you're welcome",
+];
+
+#[test]
+fn scores_a_tiny_corpus() {
+    let paths = ModelPaths::default();
+    let tokenizer = Tokenizer::load(&paths.tokens).unwrap();
+    let mut model = Model::new(&paths).unwrap();
+    let conversations: Vec<Vec<usize>> = CORPUS
+        .iter()
+        .map(|text| tokenizer.encode(text).unwrap())
+        .collect();
+
+    let scores = evaluate(&mut model, conversations.iter().map(Vec::as_slice));
+    let targets: usize = conversations.iter().map(|ids| ids.len() - 1).sum();
+    assert_eq!(scores.conversations, 2);
+    assert_eq!(scores.targets, targets);
+    assert!(scores.top1 <= scores.top8 && scores.top8 <= scores.targets);
+    assert!(scores.outside <= scores.targets);
+    assert_eq!(scores.impossible, 0);
+    let nll = scores.mean_nll().unwrap();
+    assert!(nll > 0.0 && nll.is_finite());
+    assert!(scores.perplexity().unwrap() >= 1.0);
+
+    // Each conversation starts afresh, so scoring them one at a time adds up
+    // to the same thing.
+    let mut fresh = Model::new(&paths).unwrap();
+    let start = fresh.snapshot();
+    let mut separate = Evaluation::default();
+    for ids in &conversations {
+        fresh.restore(&start);
+        separate.add(&mut fresh, ids);
+    }
+    assert_eq!(separate.targets, scores.targets);
+    assert_eq!(separate.top1, scores.top1);
+    assert_eq!(separate.top8, scores.top8);
+    assert_eq!(separate.outside, scores.outside);
+    assert!((separate.nll - scores.nll).abs() < 1e-9);
+}
@@ -0,0 +1,75 @@
+//! Loading a model from a weights directory with a ROM missing or cut short.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use craftgpt::{LoadError, Model, ModelPaths};
+
+/// A copy of the weights directory, removed when dropped.
+struct WeightsCopy(PathBuf);
+
+impl WeightsCopy {
+    fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("craftgpt-{}-{}", name, std::process::id()));
+        copy_dir(&ModelPaths::default().weights_dir, &dir);
+        WeightsCopy(dir)
+    }
+
+    fn paths(&self) -> ModelPaths {
+        ModelPaths::new(&self.0, ModelPaths::default().tokens)
+    }
+}
+
+impl Drop for WeightsCopy {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let path = entry.unwrap().path();
+        let target = to.join(path.file_name().unwrap());
+        if path.is_dir() {
+            copy_dir(&path, &target);
+        } else {
+            fs::copy(&path, &target).unwrap();
+        }
+    }
+}
+
+#[test]
+fn a_missing_rom_is_reported_with_its_path() {
+    let copy = WeightsCopy::new("missing");
+    let rom = copy.0.join("mlp/mlp_7.bin");
+    fs::remove_file(&rom).unwrap();
+
+    match Model::new(&copy.paths()) {
+        Err(LoadError::Missing { path, .. }) => assert_eq!(path, rom),
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("loaded without {}", rom.display()),
+    }
+}
+
+#[test]
+fn a_truncated_rom_is_reported_with_its_sizes() {
+    let copy = WeightsCopy::new("truncated");
+    let rom = copy.0.join("attention/att_3.bin");
+    let bytes = fs::read(&rom).unwrap();
+    fs::write(&rom, &bytes[..1000]).unwrap();
+
+    match Model::new(&copy.paths()) {
+        Err(LoadError::ShortRead {
+            path,
+            expected,
+            actual,
+        }) => {
+            assert_eq!(path, rom);
+            assert_eq!((expected, actual), (bytes.len(), 1000));
+        }
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("loaded a truncated {}", rom.display()),
+    }
+}
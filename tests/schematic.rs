@@ -0,0 +1,146 @@
+//! Exports schematics of a few ROMs with the command line and reads the
+//! weights back out of them.
+
+use std::collections::HashSet;
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::process::Command;
+
+use craftgpt::layout::{Layout, ONE_BLOCK, ZERO_BLOCK};
+use craftgpt::nbt::{self, Tag};
+use craftgpt::schematic::Schematic;
+use craftgpt::{ModelPaths, WeightSource};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+/// Exports the ROMs whose names start with `prefix`, then checks that no two
+/// bits share a block and that every ROM decodes back to its weight file.
+fn round_trip(prefix: &str) {
+    let paths = ModelPaths::default();
+    let layout = Layout::load(&paths.layout).unwrap();
+    let out = env::temp_dir().join(format!(
+        "craftgpt-{}-{}.schem",
+        std::process::id(),
+        prefix.replace('/', "-")
+    ));
+
+    let export = Command::new(env!("CARGO_BIN_EXE_craftgpt"))
+        .args(["--only", prefix, "export-schem"])
+        .arg(&out)
+        .output()
+        .unwrap();
+    assert!(
+        export.status.success(),
+        "{}",
+        String::from_utf8_lossy(&export.stderr)
+    );
+    let schematic = Schematic::load(&out).unwrap();
+    fs::remove_file(&out).unwrap();
+
+    let roms: Vec<_> = layout
+        .roms()
+        .iter()
+        .filter(|rom| rom.name.starts_with(prefix))
+        .collect();
+    assert!(!roms.is_empty());
+
+    let mut positions = HashSet::new();
+    let mut bits = 0;
+    for rom in &roms {
+        let expected = paths.read(&rom.name, rom.format.size()).unwrap();
+        rom.for_each_bit(&expected, |pos, _| {
+            positions.insert(pos);
+            bits += 1;
+        });
+        let actual = rom
+            .decode(|pos| match schematic.block(pos)? {
+                ONE_BLOCK => Some(true),
+                ZERO_BLOCK => Some(false),
+                _ => None,
+            })
+            .unwrap_or_else(|pos| panic!("{}: no ROM bit at {:?}", rom.name, pos));
+        assert_eq!(rom.compare(&expected, &actual), [], "{}", rom.name);
+    }
+    assert_eq!(positions.len(), bits, "ROM bits overlap under {}", prefix);
+
+    // Everything else in the schematic is left as air.
+    let (x0, y0, z0) = schematic.origin;
+    let mut blocks = 0;
+    for y in y0..y0 + schematic.height as i32 {
+        for z in z0..z0 + schematic.length as i32 {
+            for x in x0..x0 + schematic.width as i32 {
+                blocks += (schematic.block((x, y, z)) != Some("minecraft:air")) as usize;
+            }
+        }
+    }
+    assert_eq!(blocks, bits);
+}
+
+#[test]
+fn matrix_roms_and_their_flipped_twins_round_trip() {
+    // lm_head_1 and lm_head_10 to lm_head_19, five pairs sharing an origin.
+    round_trip("unembedding/lm_head_1");
+}
+
+#[test]
+fn layernorm_roms_round_trip() {
+    round_trip("layernorm/ln_9");
+}
+
+#[test]
+fn embedding_roms_round_trip() {
+    round_trip("embedding/wte_6");
+}
+
+/// Loads a schematic of the given size with a two-block palette and `data`
+/// as its block data, giving the reason it was turned down.
+fn load_error(size: i16, data: &[u8]) -> String {
+    let out = env::temp_dir().join(format!(
+        "craftgpt-{}-bad-{}.schem",
+        std::process::id(),
+        size
+    ));
+    let palette = vec![
+        ("minecraft:air".to_string(), Tag::Int(0)),
+        ("minecraft:stone".to_string(), Tag::Int(1)),
+    ];
+    let root = Tag::Compound(vec![
+        ("Width".to_string(), Tag::Short(size)),
+        ("Height".to_string(), Tag::Short(size)),
+        ("Length".to_string(), Tag::Short(size)),
+        ("Palette".to_string(), Tag::Compound(palette)),
+        ("BlockData".to_string(), Tag::ByteArray(data.to_vec())),
+    ]);
+    let mut w = GzEncoder::new(File::create(&out).unwrap(), Compression::fast());
+    nbt::write(&mut w, "Schematic", &root).unwrap();
+    w.finish().unwrap();
+
+    let result = Schematic::load(&out);
+    fs::remove_file(&out).unwrap();
+    let e = result.err().expect("the schematic loaded");
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    e.to_string()
+}
+
+#[test]
+fn turns_away_malformed_block_data() {
+    // A schematic claiming far more blocks than it has data for is turned
+    // down before anything is allocated for them.
+    assert_eq!(
+        load_error(i16::MAX, &[1; 8]),
+        "block data doesn't match the schematic's size"
+    );
+    assert_eq!(
+        load_error(2, &[0x80; 8]),
+        "block data has an overlong varint"
+    );
+    assert_eq!(
+        load_error(1, &[0x82, 0x00]),
+        "block data refers past the palette"
+    );
+    assert_eq!(
+        load_error(1, &[0, 1]),
+        "block data doesn't match the schematic's size"
+    );
+}
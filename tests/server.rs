@@ -0,0 +1,134 @@
+//! Talks to a server on a localhost port, answering with the real weights.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use craftgpt::server::{Server, ServerOptions};
+use craftgpt::{ChatTemplate, Model, ModelPaths, Tokenizer};
+use serde_json::Value;
+
+const MAX_TOKENS: usize = 2;
+
+/// Starts a server on a free port, left running until the tests exit.
+fn start() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let paths = ModelPaths::default();
+        let tokenizer = Tokenizer::load(&paths.tokens).unwrap();
+        let template = ChatTemplate::new(&tokenizer).unwrap();
+        let model = Model::new(&paths).unwrap();
+        let options = ServerOptions {
+            max_tokens: MAX_TOKENS,
+            timeout: Duration::from_millis(200),
+            ..ServerOptions::default()
+        };
+        Server::new(model, tokenizer, template, options).run(&listener);
+    });
+    addr
+}
+
+/// Sends a request and returns the status code, headers and body.
+fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, head.to_string(), body.to_string())
+}
+
+fn error_message(body: &str) -> String {
+    let body: Value = serde_json::from_str(body).unwrap();
+    body["error"]["message"].as_str().unwrap().to_string()
+}
+
+#[test]
+fn serves_completions_and_turns_away_bad_requests() {
+    let addr = start();
+
+    // A client that connects and says nothing must not hold up the others.
+    let _silent = TcpStream::connect(addr).unwrap();
+    // Nor can one that keeps sending a byte at a time: it is cut off once
+    // the whole request has taken longer than the timeout.
+    let trickle = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"POST /v1/completions HTTP/1.1\r\n")
+            .unwrap();
+        (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(50));
+            stream.write_all(b"X").is_err()
+        })
+    });
+
+    let completion = r#"{"prompt": "[INST] hello there [/INST]", "seed": 7, "max_tokens": 1000}"#;
+    let (status, head, body) = request(addr, "POST", "/v1/completions", completion);
+    assert_eq!(status, 200);
+    assert!(!head.contains("Access-Control-Allow-Origin"));
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["object"], "text_completion");
+    assert!(body["choices"][0]["text"].is_string());
+    let tokens = body["usage"]["completion_tokens"].as_u64().unwrap();
+    assert!(tokens as usize <= MAX_TOKENS);
+
+    let chat = r#"{"messages": [{"role": "user", "content": "hello there"}], "seed": 7,
+        "stream": true}"#;
+    let (status, head, body) = request(addr, "POST", "/v1/chat/completions", chat);
+    assert_eq!(status, 200);
+    assert!(head.contains("text/event-stream"));
+    let events: Vec<&str> = body
+        .split("\n\n")
+        .filter(|event| !event.is_empty())
+        .map(|event| event.strip_prefix("data: ").unwrap())
+        .collect();
+    assert_eq!(events.last(), Some(&"[DONE]"));
+    let chunks: Vec<Value> = events[..events.len() - 1]
+        .iter()
+        .map(|event| serde_json::from_str(event).unwrap())
+        .collect();
+    assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+    assert!(chunks.last().unwrap()["choices"][0]["finish_reason"].is_string());
+
+    let bad = [
+        "{",
+        r#"{"prompt": "hi", "temperature": -1}"#,
+        r#"{"prompt": "hi", "top_p": 1.5}"#,
+        r#"{"prompt": "hi", "min_p": -0.1}"#,
+        r#"{"prompt": "hi", "top_k": 0}"#,
+        r#"{"prompt": "hi", "seed": 0}"#,
+        r#"{"prompt": "hi", "seed": 8388608}"#,
+    ];
+    for body in bad {
+        let (status, _, response) = request(addr, "POST", "/v1/completions", body);
+        assert_eq!(status, 400, "{}", body);
+        assert!(!error_message(&response).is_empty());
+    }
+    let unknown_role = r#"{"messages": [{"role": "narrator", "content": "hi"}]}"#;
+    let (status, _, _) = request(addr, "POST", "/v1/chat/completions", unknown_role);
+    assert_eq!(status, 400);
+
+    let (status, _, _) = request(addr, "GET", "/v1/nowhere", "");
+    assert_eq!(status, 404);
+    let (status, _, _) = request(addr, "GET", "/v1/completions", "");
+    assert_eq!(status, 405);
+    // Without a CORS origin there is nothing to preflight.
+    let (status, _, _) = request(addr, "OPTIONS", "/v1/completions", "");
+    assert_eq!(status, 405);
+
+    assert!(
+        trickle.join().unwrap(),
+        "the trickling client was never dropped"
+    );
+}
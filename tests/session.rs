@@ -0,0 +1,116 @@
+//! Saves a conversation part way through and checks that carrying on from
+//! the file gives what carrying on without it does.
+
+use std::env;
+use std::fs;
+
+use craftgpt::sampler::{RedstoneSampler, Sampler};
+use craftgpt::session::{self, Session};
+use craftgpt::{ContextPolicy, Distribution, Model, ModelPaths, PRNG, Tokenizer};
+
+/// Samples `count` tokens, feeding each back in. Returns them and the
+/// prediction after the last.
+fn continue_for(
+    model: &mut Model,
+    mut dist: Distribution,
+    rng: &mut PRNG,
+    count: usize,
+) -> (Vec<usize>, Distribution) {
+    let mut tokens = Vec::new();
+    for _ in 0..count {
+        let token = RedstoneSampler.sample(&dist, rng).token;
+        tokens.push(token);
+        dist = model.predict(token);
+    }
+    (tokens, dist)
+}
+
+#[test]
+fn a_saved_session_carries_on_like_an_uninterrupted_one() {
+    let paths = ModelPaths::default();
+    let tokenizer = Tokenizer::load(&paths.tokens).unwrap();
+    let mut model = Model::new(&paths).unwrap();
+    let fresh = model.snapshot();
+    // A window shorter than the conversation, so the saved caches have
+    // already dropped tokens.
+    model.set_context_policy(ContextPolicy::SlidingWindow(4));
+
+    let mut tokens = tokenizer.encode("[INST] hello there [/INST]").unwrap();
+    let mut dist = None;
+    for &token in &tokens {
+        dist = Some(model.predict(token));
+    }
+    let mut rng = PRNG::new(7);
+    let (answer, dist) = continue_for(&mut model, dist.unwrap(), &mut rng, 2);
+    tokens.extend(answer);
+
+    let path = env::temp_dir().join(format!("craftgpt-session-{}.bin", std::process::id()));
+    Session::new(&model, tokens.clone(), rng.clone())
+        .save(&path)
+        .unwrap();
+    let (expected, expected_dist) = continue_for(&mut model, dist.clone(), &mut rng, 2);
+
+    model.set_context_policy(ContextPolicy::Faithful);
+    model.restore(&fresh);
+    let session = Session::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(session.tokens, tokens);
+    assert_eq!(session.policy, ContextPolicy::SlidingWindow(4));
+    session.restore(&mut model);
+    let mut rng = session.rng.clone();
+    let (resumed, resumed_dist) = continue_for(&mut model, dist, &mut rng, 2);
+
+    assert_eq!(resumed, expected);
+    assert_eq!(resumed_dist.packed(), expected_dist.packed());
+}
+
+#[test]
+fn a_cache_longer_than_the_file_is_an_error() {
+    let header = [
+        session::FORMAT_VERSION,
+        u32::MAX, // window
+        1,        // PRNG state
+        u32::MAX, // position
+        0,        // token count
+        6,        // block count
+        u32::MAX, // cache length, with no caches after it
+    ];
+    let mut file = b"CGPTSESS".to_vec();
+    for value in header {
+        file.extend_from_slice(&value.to_le_bytes());
+    }
+    let path = env::temp_dir().join(format!("craftgpt-huge-{}.bin", std::process::id()));
+    fs::write(&path, file).unwrap();
+    let error = Session::load(&path).unwrap_err();
+    fs::remove_file(&path).unwrap();
+    assert!(error.to_string().contains("don't fit"), "{}", error);
+}
+
+#[test]
+fn a_failed_save_leaves_what_was_there() {
+    let paths = ModelPaths::default();
+    let model = Model::new(&paths).unwrap();
+    let session = Session::new(&model, vec![1, 2, 3], PRNG::new(7));
+
+    let dir = env::temp_dir().join(format!("craftgpt-saves-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("session.bin");
+    fs::write(&path, b"earlier").unwrap();
+    session.save(&path).unwrap();
+    assert_eq!(Session::load(&path).unwrap().tokens, [1, 2, 3]);
+
+    // The session can't take the place of a directory that has a file in it.
+    let occupied = dir.join("occupied");
+    fs::create_dir_all(&occupied).unwrap();
+    fs::write(occupied.join("file"), b"kept").unwrap();
+    assert!(session.save(&occupied).is_err());
+    assert_eq!(fs::read(occupied.join("file")).unwrap(), b"kept");
+
+    let mut left: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    left.sort();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(left, ["occupied", "session.bin"]);
+}
@@ -0,0 +1,127 @@
+//! What `Model::predict_traced` records, and how a trace is saved.
+
+use std::env;
+use std::fs;
+
+use craftgpt::trace::{ProcessTrace, Tracer};
+use craftgpt::{Fixed24, Model, ModelPaths};
+
+/// The names and sizes every token should record, in order, with `cached`
+/// tokens in the attention cache.
+fn expected(cached: usize) -> Vec<(String, usize)> {
+    let mut names = vec![("embedding".to_string(), 240)];
+    for block in 0..6 {
+        let tensor = |name: &str, len| (format!("block{}.{}", block, name), len);
+        names.push(tensor("ln_1", 240));
+        for head in 0..5 {
+            names.push(tensor(&format!("head{}.scores", head), cached));
+            names.push(tensor(&format!("head{}.weights", head), cached));
+        }
+        names.push(tensor("attn", 240));
+        names.push(tensor("ln_2", 240));
+        names.push(tensor("mlp_hidden", 960));
+        names.push(tensor("mlp", 240));
+        names.push((format!("block{}", block), 240));
+    }
+    names.push(("ln_f".to_string(), 240));
+    names.push(("logits".to_string(), 1920));
+    names
+}
+
+fn traced(tokens: &[usize]) -> ProcessTrace {
+    let mut model = Model::new(&ModelPaths::default()).unwrap();
+    let mut trace = ProcessTrace::default();
+    for &token in tokens {
+        model.predict_traced(token, &mut trace);
+    }
+    trace
+}
+
+#[test]
+fn records_every_tensor_in_order() {
+    let trace = traced(&[1, 14, 6]);
+    assert_eq!(trace.tokens.len(), 3);
+    for (i, token) in trace.tokens.iter().enumerate() {
+        assert_eq!((token.token, token.position), ([1, 14, 6][i], i));
+        let recorded: Vec<(String, usize)> = token
+            .tensors
+            .iter()
+            .map(|t| (t.name.clone(), t.values.len()))
+            .collect();
+        assert_eq!(recorded, expected(i + 1), "token {}", i);
+    }
+    assert_eq!(trace.tokens[2].get("block0.head0.scores").unwrap().len(), 3);
+    assert!(trace.tokens[0].get("block6").is_none());
+}
+
+#[test]
+fn tracing_does_not_change_the_prediction() {
+    let mut model = Model::new(&ModelPaths::default()).unwrap();
+    let plain = model.predict(1);
+    let mut model = Model::new(&ModelPaths::default()).unwrap();
+    let mut trace = ProcessTrace::default();
+    let traced = model.predict_traced(1, &mut trace);
+    assert_eq!(plain.packed(), traced.packed());
+    assert_eq!(trace.tokens[0].get("logits").unwrap(), plain.logits());
+}
+
+/// Counts what reaches it while claiming to record nothing, like `()`.
+#[derive(Default)]
+struct Disabled {
+    names: Vec<String>,
+}
+
+impl Tracer for Disabled {
+    fn record(&mut self, name: &str, _values: &[Fixed24]) {
+        self.names.push(name.to_string());
+    }
+
+    fn enabled(&self) -> bool {
+        false
+    }
+}
+
+#[test]
+fn a_disabled_tracer_gets_only_the_fixed_names() {
+    let mut model = Model::new(&ModelPaths::default()).unwrap();
+    let mut tracer = Disabled::default();
+    model.predict_traced(1, &mut tracer);
+    // Names that would have to be formatted, like `block0.ln_1`, are never
+    // built.
+    assert_eq!(tracer.names, ["embedding", "ln_f", "logits"]);
+}
+
+#[test]
+fn saves_the_binary_layout() {
+    let trace = traced(&[1, 14]);
+    let path = env::temp_dir().join(format!("craftgpt-trace-{}.bin", std::process::id()));
+    trace.save_binary(&path).unwrap();
+    let bytes = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let mut pos = 0;
+    let mut take = |n: usize| {
+        pos += n;
+        &bytes[pos - n..pos]
+    };
+    let u32_at = |b: &[u8]| u32::from_le_bytes(b.try_into().unwrap());
+
+    assert_eq!(take(8), b"CGPTTRCE");
+    assert_eq!(u32_at(take(4)), 1);
+    assert_eq!(u32_at(take(4)), 2);
+    for token in &trace.tokens {
+        assert_eq!(u32_at(take(4)), token.token as u32);
+        assert_eq!(u32_at(take(4)), token.position as u32);
+        assert_eq!(u32_at(take(4)), token.tensors.len() as u32);
+        for tensor in &token.tensors {
+            let name_len = u16::from_le_bytes(take(2).try_into().unwrap()) as usize;
+            assert_eq!(take(name_len), tensor.name.as_bytes());
+            assert_eq!(u32_at(take(4)), tensor.values.len() as u32);
+            for &v in &tensor.values {
+                let b = take(3);
+                assert_eq!(u32::from_le_bytes([b[0], b[1], b[2], 0]), v);
+            }
+        }
+    }
+    assert_eq!(pos, bytes.len());
+}
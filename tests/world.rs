@@ -0,0 +1,120 @@
+//! Builds a small world holding a few ROMs with `WorldWriter`, then reads
+//! them back with `World` and the `extract-world` command.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Output};
+
+use craftgpt::anvil::{World, WorldWriter};
+use craftgpt::layout::{Layout, ONE_BLOCK, RomPlacement, ZERO_BLOCK};
+use craftgpt::{ModelPaths, WeightSource};
+
+/// A flipped matrix ROM, written as it is in the weights.
+const FLIPPED: &str = "attention/att_24";
+/// A layernorm ROM, written with one bit of word 5 wrong.
+const DAMAGED: &str = "layernorm/ln_9";
+
+fn extract(world: &Path, only: &str, out: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_craftgpt"))
+        .args(["--only", only, "extract-world"])
+        .args([world, out])
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn reads_back_what_was_written() {
+    let paths = ModelPaths::default();
+    let layout = Layout::load(&paths.layout).unwrap();
+    let rom = |name| layout.get(name).unwrap();
+    let (flipped, damaged): (&RomPlacement, &RomPlacement) = (rom(FLIPPED), rom(DAMAGED));
+    assert!(flipped.flipped);
+    let flipped_bytes = paths.read(FLIPPED, flipped.format.size()).unwrap();
+    let damaged_bytes = paths.read(DAMAGED, damaged.format.size()).unwrap();
+
+    let mut writer = WorldWriter::new();
+    for (rom, bytes) in [(flipped, &flipped_bytes), (damaged, &damaged_bytes)] {
+        rom.for_each_bit(bytes, |pos, bit| {
+            writer.set_block(pos, if bit { ONE_BLOCK } else { ZERO_BLOCK });
+        });
+    }
+    let wrong = damaged.position(5, 3);
+    let was_set = damaged_bytes[3 * 5] & 1 << 3 != 0;
+    writer.set_block(wrong, if was_set { ZERO_BLOCK } else { ONE_BLOCK });
+
+    let dir = env::temp_dir().join(format!("craftgpt-world-{}", std::process::id()));
+    writer.save(&dir).unwrap();
+
+    let mut world = World::open(&dir).unwrap();
+    let first = flipped.position(0, 0);
+    let expected = if flipped_bytes[0] & 1 != 0 {
+        ONE_BLOCK
+    } else {
+        ZERO_BLOCK
+    };
+    assert_eq!(world.block(first).unwrap(), expected);
+    assert_eq!(
+        world.block((first.0, first.1 - 1, first.2)).unwrap(),
+        "minecraft:air"
+    );
+    assert_eq!(world.block((-1000, 64, -1000)).unwrap(), "minecraft:air");
+
+    let decoded = flipped
+        .decode(|pos| match world.block(pos).ok()? {
+            ONE_BLOCK => Some(true),
+            ZERO_BLOCK => Some(false),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(decoded, flipped_bytes.as_ref());
+
+    let out = dir.join("extracted");
+    let ok = extract(&dir, FLIPPED, &out);
+    assert!(
+        ok.status.success(),
+        "{}",
+        String::from_utf8_lossy(&ok.stdout)
+    );
+    let written = fs::read(out.join(format!("{}.bin", FLIPPED))).unwrap();
+    assert_eq!(written, flipped_bytes.as_ref());
+
+    let bad = extract(&dir, DAMAGED, &out);
+    assert!(!bad.status.success());
+    let report = String::from_utf8_lossy(&bad.stdout);
+    let word = |bytes: &[u8]| u32::from_le_bytes([bytes[15], bytes[16], bytes[17], 0]);
+    let mut actual = damaged_bytes.to_vec();
+    actual[3 * 5] ^= 1 << 3;
+    let line = format!(
+        "{}: row 0 column 5 is {:#x}, expected {:#x}",
+        DAMAGED,
+        word(&actual),
+        word(&damaged_bytes)
+    );
+    assert_eq!(report.lines().collect::<Vec<_>>(), [line.as_str()]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn turns_away_a_chunk_longer_than_its_sectors() {
+    let dir = env::temp_dir().join(format!("craftgpt-long-chunk-{}", std::process::id()));
+    fs::create_dir_all(dir.join("region")).unwrap();
+    // Chunk 0 0 takes one sector from sector 2, but claims almost 4 GiB.
+    let mut region = vec![0u8; 3 * 4096];
+    region[..4].copy_from_slice(&(2 << 8 | 1u32).to_be_bytes());
+    region[8192..8197].copy_from_slice(&[0xff, 0xff, 0xff, 0xf0, 2]);
+    fs::write(dir.join("region/r.0.0.mca"), region).unwrap();
+
+    let mut world = World::open(&dir).unwrap();
+    let e = world.block((0, 64, 0)).unwrap_err();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert!(
+        e.to_string()
+            .contains("claims 4294967284 bytes, but its sectors hold 4096"),
+        "{}",
+        e
+    );
+}